@@ -305,7 +305,9 @@ impl App {
                 .get_new_address(None, None)
                 .map_err(|e| Error::Server(e.into()))?
                 .assume_checked();
-            provisional_payjoin.substitute_output_address(receiver_substitute_address);
+            provisional_payjoin
+                .substitute_output_address(receiver_substitute_address)
+                .map_err(|e| Error::Server(e.into()))?;
         }
 
         let payjoin_proposal = provisional_payjoin.finalize_proposal(
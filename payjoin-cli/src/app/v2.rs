@@ -7,7 +7,7 @@ use bitcoincore_rpc::jsonrpc::serde_json;
 use bitcoincore_rpc::RpcApi;
 use payjoin::bitcoin::psbt::Psbt;
 use payjoin::bitcoin::Amount;
-use payjoin::{base64, bitcoin, Error, PjUriBuilder};
+use payjoin::{base64, bitcoin, DirectoryUrl, Error, PjUriBuilder, RelayUrl};
 use tokio::sync::Mutex as AsyncMutex;
 use tokio::task::spawn_blocking;
 
@@ -73,12 +73,13 @@ impl AppTrait for App {
         use payjoin::receive::v2::Enroller;
 
         let ohttp_keys = unwrap_ohttp_keys_or_else_fetch(&self.config).await?;
-        let mut enrolled = if !is_retry {
+        let enrolled = if !is_retry {
             let mut enroller = Enroller::from_directory_config(
-                self.config.pj_endpoint.clone(),
+                DirectoryUrl::try_from(self.config.pj_endpoint.clone())?,
                 ohttp_keys.clone(),
-                self.config.ohttp_relay.clone(),
-            );
+                RelayUrl::try_from(self.config.ohttp_relay.clone())?,
+                false,
+            )?;
             let (req, ctx) =
                 enroller.extract_req().map_err(|e| anyhow!("Failed to extract request {}", e))?;
             log::debug!("Enrolling receiver");
@@ -112,7 +113,7 @@ impl AppTrait for App {
         println!("{}", pj_uri_string);
 
         log::debug!("Awaiting proposal");
-        let res = self.long_poll_fallback(&mut enrolled).await?;
+        let res = self.long_poll_fallback(enrolled).await?;
         log::debug!("Received request");
         let mut payjoin_proposal = self
             .process_v2_proposal(res)
@@ -160,7 +161,8 @@ impl App {
 
     async fn long_poll_post(&self, req_ctx: &mut payjoin::send::RequestContext) -> Result<Psbt> {
         loop {
-            let (req, ctx) = req_ctx.extract_v2(self.config.ohttp_relay.clone())?;
+            let (req, ctx) =
+                req_ctx.extract_v2(RelayUrl::try_from(self.config.ohttp_relay.clone())?)?;
             println!("Sending fallback request to {}", &req.url);
             let http = http_agent()?;
             let response = spawn_blocking(move || {
@@ -185,8 +187,10 @@ impl App {
 
     async fn long_poll_fallback(
         &self,
-        enrolled: &mut payjoin::receive::v2::Enrolled,
+        mut enrolled: payjoin::receive::v2::Enrolled,
     ) -> Result<payjoin::receive::v2::UncheckedProposal> {
+        use payjoin::receive::v2::Polled;
+
         loop {
             let (req, context) =
                 enrolled.extract_req().map_err(|_| anyhow!("Failed to extract request"))?;
@@ -200,14 +204,25 @@ impl App {
             })
             .await??;
 
-            let proposal = enrolled
-                .process_res(ohttp_response.into_reader(), context)
-                .map_err(|_| anyhow!("GET fallback failed"))?;
             log::debug!("got response");
-            match proposal {
-                Some(proposal) => break Ok(proposal),
-                None => std::thread::sleep(std::time::Duration::from_secs(5)),
+            // Not bound to a variable, so the non-Send `Polled` isn't held across an await
+            let (next, moved) = match enrolled.process_res(ohttp_response.into_reader(), context) {
+                Polled::Proposal(proposal) => break Ok(proposal),
+                Polled::Pending(pending) => {
+                    std::thread::sleep(std::time::Duration::from_secs(5));
+                    (*pending, false)
+                }
+                Polled::Redirected(redirected, directory) => {
+                    log::info!("Session moved to {}", directory);
+                    (*redirected, true)
+                }
+                Polled::Rejected(e, _) => return Err(anyhow!("GET fallback failed: {}", e)),
+            };
+            if moved {
+                // Persist the new location so a resumed session polls it
+                self.receive_store.lock().await.write(next.clone())?;
             }
+            enrolled = next;
         }
     }
 
@@ -288,7 +303,9 @@ impl App {
                 .map_err(|e| Error::Server(e.into()))
                 .expect("testing testing")
                 .assume_checked();
-            provisional_payjoin.substitute_output_address(receiver_substitute_address);
+            provisional_payjoin
+                .substitute_output_address(receiver_substitute_address)
+                .map_err(|e| Error::Server(e.into()))?;
         }
 
         let payjoin_proposal = provisional_payjoin.finalize_proposal(
@@ -17,19 +17,88 @@ pub fn fetch_ohttp_keys(
     payjoin_directory: Url,
     #[cfg(feature = "danger-local-https")] cert_der: Vec<u8>,
 ) -> Result<payjoin::OhttpKeys, Error> {
-    let ohttp_keys_url = payjoin_directory.join("/ohttp-keys")?;
     let proxy = PayjoinProxy::new(
         &ohttp_relay,
         #[cfg(feature = "danger-local-https")]
         cert_der,
     )?;
-    let res = proxy.get(ohttp_keys_url.as_str()).call()?;
-    let mut body = Vec::new();
-    let _ = res.into_reader().read_to_end(&mut body)?;
-    payjoin::OhttpKeys::decode(&body)
+    fetch_ohttp_keys_via(&proxy, payjoin_directory)
+}
+
+/// Fetch the ohttp keys from the specified payjoin directory over a caller supplied transport.
+///
+/// Use this to reach the directory through a transport of your choosing, e.g. a SOCKS5 proxy
+/// to Tor, so that the request never reveals the client's IP address.
+#[cfg(feature = "v2")]
+pub fn fetch_ohttp_keys_via(
+    transport: &impl Transport,
+    payjoin_directory: Url,
+) -> Result<payjoin::OhttpKeys, Error> {
+    let ohttp_keys_url = payjoin_directory.join(payjoin::OHTTP_KEYS_PATH)?;
+    let body = transport.get(&ohttp_keys_url).map_err(InternalError::Transport)?;
+    payjoin::OhttpKeys::from_directory_response(&body)
         .map_err(|e| Error(InternalError::InvalidOhttpKeys(e.to_string())))
 }
 
+/// Send a payjoin [`Request`](payjoin::Request) over `transport` and return the response body.
+pub fn send_request(
+    transport: &impl Transport,
+    request: &payjoin::Request,
+    content_type: &str,
+) -> Result<Vec<u8>, Error> {
+    transport
+        .post(&request.url, content_type, &request.body)
+        .map_err(|e| Error(InternalError::Transport(e)))
+}
+
+/// Poll the payjoin directory once for a proposal to `enrolled`, over `transport`.
+///
+/// The session is consumed and handed back in the [`Polled`](payjoin::receive::v2::Polled)
+/// result, so it can't be polled again once a proposal has been received. If the request can't
+/// be made or sent, the error comes with the session so it can poll again.
+#[cfg(feature = "v2")]
+pub fn poll_once(
+    transport: &impl Transport,
+    mut enrolled: payjoin::receive::v2::Enrolled,
+) -> Result<payjoin::receive::v2::Polled, (Error, Box<payjoin::receive::v2::Enrolled>)> {
+    let (req, ctx) = match enrolled.extract_req() {
+        Ok(req) => req,
+        Err(e) => return Err((InternalError::Payjoin(e.to_string()).into(), Box::new(enrolled))),
+    };
+    match send_request(transport, &req, payjoin::V2_REQ_CONTENT_TYPE) {
+        Ok(res) => Ok(enrolled.process_res(res.as_slice(), ctx)),
+        Err(e) => Err((e, Box::new(enrolled))),
+    }
+}
+
+/// Post a finalized Payjoin Proposal to the payjoin directory over `transport`.
+#[cfg(feature = "v2")]
+pub fn post_proposal(
+    transport: &impl Transport,
+    proposal: &mut payjoin::receive::v2::PayjoinProposal,
+) -> Result<(), Error> {
+    let (req, ctx) =
+        proposal.extract_v2_req().map_err(|e| InternalError::Payjoin(e.to_string()))?;
+    let res = send_request(transport, &req, payjoin::V2_REQ_CONTENT_TYPE)?;
+    proposal.deserialize_res(res, ctx).map_err(|e| InternalError::Payjoin(e.to_string()))?;
+    Ok(())
+}
+
+/// Carries the HTTP requests that payjoin-defaults makes.
+///
+/// Implement this once, e.g. over a SOCKS5 proxy to Tor, and pass it to every helper so that
+/// requests to OHTTP relays and payjoin directories all take the same route.
+pub trait Transport {
+    /// GET `url` and return the response body
+    fn get(&self, url: &Url) -> Result<Vec<u8>, TransportError>;
+
+    /// POST `body` to `url` and return the response body
+    fn post(&self, url: &Url, content_type: &str, body: &[u8]) -> Result<Vec<u8>, TransportError>;
+}
+
+/// Error returned by a [`Transport`] implementation
+pub type TransportError = Box<dyn std::error::Error + Send + Sync>;
+
 #[derive(Debug)]
 pub struct Error(InternalError);
 
@@ -42,6 +111,9 @@ enum InternalError {
     Rustls(rustls::Error),
     #[cfg(feature = "v2")]
     InvalidOhttpKeys(String),
+    #[cfg(feature = "v2")]
+    Payjoin(String),
+    Transport(TransportError),
 }
 
 macro_rules! impl_from_error {
@@ -70,8 +142,11 @@ impl std::fmt::Display for Error {
             InvalidOhttpKeys(e) => {
                 write!(f, "Invalid ohttp keys returned from payjoin directory: {}", e)
             }
+            #[cfg(feature = "v2")]
+            Payjoin(e) => write!(f, "Payjoin error: {}", e),
             #[cfg(feature = "danger-local-https")]
             Rustls(e) => e.fmt(f),
+            Transport(e) => e.fmt(f),
         }
     }
 }
@@ -86,8 +161,11 @@ impl std::error::Error for Error {
             Io(e) => Some(e),
             #[cfg(feature = "v2")]
             InvalidOhttpKeys(_) => None,
+            #[cfg(feature = "v2")]
+            Payjoin(_) => None,
             #[cfg(feature = "danger-local-https")]
             Rustls(e) => Some(e),
+            Transport(e) => Some(e.as_ref()),
         }
     }
 }
@@ -114,8 +192,6 @@ impl PayjoinProxy {
         Ok(Self { client })
     }
 
-    fn get(&self, url: &str) -> ureq::Request { self.client.get(url) }
-
     // Normalize the Url to include the port for ureq. ureq has a bug
     // which makes Proxy::new(...) use port 8080 for all input with scheme
     // http regardless of the port included in the Url. This prevents that.
@@ -147,3 +223,121 @@ impl PayjoinProxy {
         Ok(AgentBuilder::new().tls_config(Arc::new(client_config)))
     }
 }
+
+impl Transport for PayjoinProxy {
+    fn get(&self, url: &Url) -> Result<Vec<u8>, TransportError> {
+        let res = self.client.get(url.as_str()).call()?;
+        let mut body = Vec::new();
+        let _ = res.into_reader().read_to_end(&mut body)?;
+        Ok(body)
+    }
+
+    fn post(&self, url: &Url, content_type: &str, body: &[u8]) -> Result<Vec<u8>, TransportError> {
+        let res =
+            self.client.post(url.as_str()).set("Content-Type", content_type).send_bytes(body)?;
+        let mut body = Vec::new();
+        let _ = res.into_reader().read_to_end(&mut body)?;
+        Ok(body)
+    }
+}
+
+#[cfg(all(test, feature = "v2"))]
+mod test {
+    use std::cell::RefCell;
+
+    use payjoin::receive::v2::{Enrolled, Polled};
+
+    use super::*;
+
+    /// Records every request and answers each with an empty body
+    #[derive(Default)]
+    struct RecordingTransport {
+        gets: RefCell<Vec<Url>>,
+        posts: RefCell<Vec<(Url, String, Vec<u8>)>>,
+    }
+
+    impl Transport for RecordingTransport {
+        fn get(&self, url: &Url) -> Result<Vec<u8>, TransportError> {
+            self.gets.borrow_mut().push(url.clone());
+            Ok(Vec::new())
+        }
+
+        fn post(
+            &self,
+            url: &Url,
+            content_type: &str,
+            body: &[u8],
+        ) -> Result<Vec<u8>, TransportError> {
+            self.posts.borrow_mut().push((url.clone(), content_type.to_owned(), body.to_vec()));
+            Ok(Vec::new())
+        }
+    }
+
+    #[test]
+    fn ohttp_keys_are_fetched_over_transport() {
+        let transport = RecordingTransport::default();
+        let directory = Url::parse("https://directory.com").unwrap();
+        // The empty response holds no keys
+        assert!(fetch_ohttp_keys_via(&transport, directory).is_err());
+        assert_eq!(
+            transport.gets.into_inner(),
+            [Url::parse("https://directory.com/ohttp-keys").unwrap()]
+        );
+        assert!(transport.posts.into_inner().is_empty());
+    }
+
+    /// Fails every request
+    struct FailingTransport;
+
+    impl Transport for FailingTransport {
+        fn get(&self, _: &Url) -> Result<Vec<u8>, TransportError> { Err("unreachable".into()) }
+
+        fn post(&self, _: &Url, _: &str, _: &[u8]) -> Result<Vec<u8>, TransportError> {
+            Err("unreachable".into())
+        }
+    }
+
+    fn test_enrolled() -> Enrolled {
+        // key id 1, X25519 public key, then HKDF-SHA256 with ChaCha20Poly1305
+        let mut key_config = vec![1, 0, 0x20];
+        key_config.extend_from_slice(&[9; 32]);
+        key_config.extend_from_slice(&[0, 4, 0, 1, 0, 3]);
+        Enrolled::recover(
+            bitcoin::secp256k1::KeyPair::from_seckey_slice(
+                &bitcoin::secp256k1::Secp256k1::new(),
+                &[1; 32],
+            )
+            .unwrap(),
+            payjoin::DirectoryUrl::try_from("https://directory.com").unwrap(),
+            payjoin::OhttpKeys::decode(&key_config).unwrap(),
+            payjoin::RelayUrl::try_from("https://relay.com").unwrap(),
+        )
+    }
+
+    #[test]
+    fn poll_once_posts_to_the_relay() {
+        let transport = RecordingTransport::default();
+
+        // The empty response can't be decapsulated
+        assert!(matches!(poll_once(&transport, test_enrolled()), Ok(Polled::Rejected(..))));
+        let posts = transport.posts.into_inner();
+        assert_eq!(posts.len(), 1);
+        let (url, content_type, body) = &posts[0];
+        assert_eq!(url.as_str(), "https://relay.com/");
+        assert_eq!(content_type, payjoin::V2_REQ_CONTENT_TYPE);
+        // An OHTTP request starts with the key config's id, KEM, KDF and AEAD
+        assert_eq!(body[..7], [1, 0, 0x20, 0, 1, 0, 3]);
+        assert!(transport.gets.into_inner().is_empty());
+    }
+
+    #[test]
+    fn failed_poll_hands_back_the_session() {
+        let session = match poll_once(&FailingTransport, test_enrolled()) {
+            Err((_, session)) => session,
+            Ok(_) => panic!("expected the transport error"),
+        };
+        let transport = RecordingTransport::default();
+        assert!(matches!(poll_once(&transport, *session), Ok(Polled::Rejected(..))));
+        assert_eq!(transport.posts.into_inner().len(), 1);
+    }
+}
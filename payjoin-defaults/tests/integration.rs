@@ -14,9 +14,9 @@ mod v2 {
     use bitcoind::bitcoincore_rpc::{self, RpcApi};
     use log::{log_enabled, Level};
     use once_cell::sync::{Lazy, OnceCell};
-    use payjoin::receive::v2::{Enrolled, Enroller, PayjoinProposal, UncheckedProposal};
+    use payjoin::receive::v2::{Enrolled, Enroller, PayjoinProposal, Polled, UncheckedProposal};
     use payjoin::send::RequestBuilder;
-    use payjoin::{OhttpKeys, PjUriBuilder, Request, Uri};
+    use payjoin::{DirectoryUrl, OhttpKeys, PjUriBuilder, RelayUrl, Request, Uri};
     use testcontainers_modules::redis::Redis;
     use testcontainers_modules::testcontainers::clients::Cli;
     use tokio::task::spawn_blocking;
@@ -63,8 +63,13 @@ mod v2 {
             let agent = Arc::new(http_agent(cert_der.clone()).unwrap());
             wait_for_service_ready(directory.clone(), agent.clone()).await.unwrap();
             let mock_ohttp_relay = directory.clone(); // pass through to directory
-            let mut bad_enroller =
-                Enroller::from_directory_config(directory, bad_ohttp_keys, mock_ohttp_relay);
+            let mut bad_enroller = Enroller::from_directory_config(
+                DirectoryUrl::try_from(directory).expect("Directory URL should be valid"),
+                bad_ohttp_keys,
+                RelayUrl::try_from(mock_ohttp_relay).expect("Relay URL should be valid"),
+                false,
+            )
+            .expect("Directory URL should be valid");
             let (req, _ctx) = bad_enroller.extract_req().expect("Failed to extract request");
             spawn_blocking(move || agent.post(req.url.as_str()).send_bytes(&req.body))
                 .await
@@ -116,9 +121,9 @@ mod v2 {
             // debug!("Original psbt: {:#?}", psbt);
             let (send_req, send_ctx) = RequestBuilder::from_psbt_and_uri(psbt, pj_uri)?
                 .build_with_additional_fee(Amount::from_sat(10000), None, FeeRate::ZERO, false)?
-                .extract_v2(directory.to_owned())?; // Mock since we're not
-                                                    // log::info!("send fallback v2");
-                                                    // log::debug!("Request: {:#?}", &send_req.body);
+                .extract_v2(RelayUrl::try_from(directory.to_owned())?)?; // Mock since we're not
+                                                                         // log::info!("send fallback v2");
+                                                                         // log::debug!("Request: {:#?}", &send_req.body);
             let response = {
                 let Request { url, body, .. } = send_req.clone();
                 let agent_clone = agent.clone();
@@ -145,7 +150,11 @@ mod v2 {
                     .await??;
 
             // POST payjoin
-            let proposal = enrolled.process_res(response.into_reader(), ctx)?.unwrap();
+            let proposal = match enrolled.process_res(response.into_reader(), ctx) {
+                Polled::Proposal(proposal) => proposal,
+                Polled::Pending(_) | Polled::Redirected(..) => panic!("Expected a proposal"),
+                Polled::Rejected(e, _) => return Err(e.into()),
+            };
             let mut payjoin_proposal = handle_directory_proposal(receiver, proposal);
             let (req, ctx) = payjoin_proposal.extract_v2_req()?;
             let agent_clone = agent.clone();
@@ -263,7 +272,11 @@ mod v2 {
                     }
                 };
                 // debug!("handle directory response");
-                let proposal = enrolled.process_res(response, ctx).unwrap().unwrap();
+                let proposal = match enrolled.process_res(response, ctx) {
+                    Polled::Proposal(proposal) => proposal,
+                    Polled::Pending(_) | Polled::Redirected(..) => panic!("Expected a proposal"),
+                    Polled::Rejected(e, _) => panic!("Proposal rejected: {}", e),
+                };
                 let mut payjoin_proposal = handle_directory_proposal(receiver, proposal);
                 // Respond with payjoin psbt within the time window the sender is willing to wait
                 // this response would be returned as http response to the sender
@@ -350,10 +363,11 @@ mod v2 {
     ) -> Result<Enrolled, BoxError> {
         let mock_ohttp_relay = directory.clone(); // pass through to directory
         let mut enroller = Enroller::from_directory_config(
-            directory.clone(),
+            DirectoryUrl::try_from(directory.clone())?,
             ohttp_keys,
-            mock_ohttp_relay.clone(),
-        );
+            RelayUrl::try_from(mock_ohttp_relay.clone())?,
+            false,
+        )?;
         let (req, ctx) = enroller.extract_req()?;
         println!("enroll req: {:#?}", &req);
         let res = spawn_blocking(move || {
@@ -448,7 +462,7 @@ mod v2 {
 
         let receiver_substitute_address =
             receiver.get_new_address(None, None).unwrap().assume_checked();
-        payjoin.substitute_output_address(receiver_substitute_address);
+        payjoin.substitute_output_address(receiver_substitute_address).unwrap();
         let payjoin_proposal = payjoin
             .finalize_proposal(
                 |psbt: &Psbt| {
@@ -27,6 +27,13 @@ impl DbPool {
         self.peek_with_timeout(pubkey_id, RES_COLUMN).await
     }
 
+    /// Whether a request is stored for `pubkey_id`, without waiting for one
+    pub async fn has_req(&self, pubkey_id: &str) -> RedisResult<bool> {
+        let mut conn = self.client.get_async_connection().await?;
+        let data: Option<Vec<u8>> = conn.get(channel_name(pubkey_id, REQ_COLUMN)).await?;
+        Ok(data.map_or(false, |data| !data.is_empty()))
+    }
+
     pub async fn push_req(&self, pubkey_id: &str, data: Vec<u8>) -> RedisResult<()> {
         self.push(pubkey_id, REQ_COLUMN, data).await
     }
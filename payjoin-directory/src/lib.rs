@@ -193,6 +193,7 @@ async fn handle_v2(pool: DbPool, req: Request<Body>) -> Result<Response<Body>, H
         (Method::POST, &["", ""]) => post_enroll(body).await,
         (Method::POST, &["", id]) => post_fallback_v2(id, body, pool).await,
         (Method::GET, &["", id]) => get_fallback(id, pool).await,
+        (Method::HEAD, &["", id]) => head_fallback(id, pool).await,
         (Method::POST, &["", id, "payjoin"]) => post_payjoin(id, body, pool).await,
         _ => Ok(not_found()),
     }
@@ -333,6 +334,18 @@ async fn get_fallback(id: &str, pool: DbPool) -> Result<Response<Body>, HandlerE
     }
 }
 
+/// Report whether a request is waiting without returning it or waiting for one to arrive
+async fn head_fallback(id: &str, pool: DbPool) -> Result<Response<Body>, HandlerError> {
+    trace!("HEAD fallback");
+    let id = shorten_string(id);
+    let status = match pool.has_req(&id).await {
+        Ok(true) => StatusCode::OK,
+        Ok(false) => StatusCode::ACCEPTED,
+        Err(e) => return Err(HandlerError::BadRequest(e.into())),
+    };
+    Ok(Response::builder().status(status).body(Body::empty())?)
+}
+
 async fn post_payjoin(id: &str, body: Body, pool: DbPool) -> Result<Response<Body>, HandlerError> {
     trace!("POST payjoin");
     let id = shorten_string(id);
@@ -30,7 +30,10 @@ pub mod send;
 #[cfg(feature = "v2")]
 pub(crate) mod v2;
 #[cfg(feature = "v2")]
-pub use v2::OhttpKeys;
+pub use v2::{
+    AttestationError, DirectoryUrl, OhttpKeys, OhttpKeysError, ParameterAttestation, RelayUrl,
+    UrlError, OHTTP_KEYS_PATH,
+};
 
 #[cfg(any(feature = "send", feature = "receive"))]
 pub(crate) mod input_type;
@@ -42,10 +45,12 @@ mod request;
 pub use request::*;
 
 mod uri;
+mod version;
 #[cfg(any(feature = "send", feature = "receive"))]
 pub(crate) mod weight;
 
 #[cfg(feature = "base64")]
 pub use bitcoin::base64;
-pub use uri::{PjParseError, PjUri, PjUriBuilder, Uri};
+pub use uri::{PjParseError, PjUri, PjUriBuilder, PjUriExt, Uri};
 pub use url::{ParseError, Url};
+pub use version::{supported_versions, Version};
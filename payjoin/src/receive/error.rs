@@ -1,5 +1,4 @@
-use std::error;
-use std::fmt::{self, Display};
+use std::{error, fmt};
 
 #[derive(Debug)]
 pub enum Error {
@@ -45,6 +44,11 @@ impl From<crate::v2::OhttpEncapsulationError> for Error {
     fn from(e: crate::v2::OhttpEncapsulationError) -> Self { Error::Server(Box::new(e)) }
 }
 
+#[cfg(feature = "v2")]
+impl From<DirectoryUrlError> for Error {
+    fn from(e: DirectoryUrlError) -> Self { Error::Server(Box::new(e)) }
+}
+
 /// Error that may occur when the request from sender is malformed.
 ///
 /// This is currently opaque type because we aren't sure which variants will stay.
@@ -62,6 +66,10 @@ pub(crate) enum InternalRequestError {
     InvalidContentLength(std::num::ParseIntError),
     ContentLengthTooLarge(u64),
     SenderParams(super::optional_parameters::Error),
+    /// The proposal's protocol version is supported but not accepted by the receiver.
+    ///
+    /// First argument is the proposal's version, second is the versions the receiver accepts.
+    VersionNotAccepted(crate::Version, Vec<crate::Version>),
     /// The raw PSBT fails bip78-specific validation.
     InconsistentPsbt(crate::psbt::InconsistentPsbt),
     /// The prevtxout is missing
@@ -76,6 +84,14 @@ pub(crate) enum InternalRequestError {
     MixedInputScripts(crate::input_type::InputType, crate::input_type::InputType),
     /// Unrecognized input type
     InputType(crate::input_type::InputTypeError),
+    /// Original PSBT input at this index has no final scriptSig or witness
+    InputNotFinalized(usize),
+    /// Original PSBT input spends an output that no longer exists
+    InputSpent(bitcoin::OutPoint),
+    /// The Original PSBT transaction is already confirmed
+    OriginalTxConfirmed(bitcoin::Txid),
+    /// The Original PSBT carries global xpubs or key origins revealing the sender's wallet
+    LeakyMetadata,
     /// Original PSBT input has been seen before. Only automatic receivers, aka "interactive" in the spec
     /// look out for these to prevent probing attacks.
     InputSeen(bitcoin::OutPoint),
@@ -90,88 +106,289 @@ pub(crate) enum InternalRequestError {
     ///
     /// Second argument is the minimum fee rate optionaly set by the receiver.
     PsbtBelowFeeRate(bitcoin::FeeRate, bitcoin::FeeRate),
+    /// Original PSBT fee rate is above the maximum fee rate set by the receiver.
+    ///
+    /// First argument is the calculated fee rate of the original PSBT.
+    ///
+    /// Second argument is the maximum fee rate set by the receiver.
+    PsbtAboveFeeRate(bitcoin::FeeRate, bitcoin::FeeRate),
+    /// The Original PSBT transaction weighs more than the receiver accepts.
+    ///
+    /// First argument is the weight of the Original PSBT transaction.
+    ///
+    /// Second argument is the maximum weight set by the receiver.
+    OriginalWeightTooHigh(bitcoin::Weight, bitcoin::Weight),
+    /// The fee owed for the receiver's inputs exceeds the sender's `maxadditionalfeecontribution`.
+    ///
+    /// First argument is the additional fee the receiver requires.
+    ///
+    /// Second argument is the maximum additional fee contribution set by the sender.
+    FeeContributionExceedsMaximum(bitcoin::Amount, bitcoin::Amount),
+    /// The sender's `additionalfeeoutputindex` does not point at an Original PSBT output.
+    ///
+    /// First argument is the index given by the sender.
+    ///
+    /// Second argument is the number of outputs in the Original PSBT.
+    FeeOutputIndexOutOfRange(usize, usize),
+    /// The finalized Payjoin Proposal pays less than the minimum relay fee rate.
+    ///
+    /// First argument is the estimated fee rate of the proposal, second is the minimum.
+    ProposalBelowMinRelayFee(bitcoin::FeeRate, bitcoin::FeeRate),
+    /// The finalized Payjoin Proposal pays less than the sender's `minfeerate`.
+    ///
+    /// First argument is the estimated fee rate of the proposal, second is the sender's minimum.
+    ProposalBelowSenderMinFeeRate(bitcoin::FeeRate, bitcoin::FeeRate),
+    /// The finalized Payjoin Proposal pays more than the receiver's maximum effective fee rate.
+    ///
+    /// First argument is the estimated fee rate of the proposal, second is the maximum.
+    ProposalAboveMaxFeeRate(bitcoin::FeeRate, bitcoin::FeeRate),
+    /// The receiver declined the computed Payjoin Proposal
+    ProposalDeclined,
+    /// The Original PSBT has no output that looks like the sender's change
+    MissingSenderChange,
+    /// The session received more proposals than its rate limit allows
+    #[cfg(feature = "v2")]
+    RateLimited,
+    /// The session requires encrypted v2 proposals but the sender posted a plaintext v1 one
+    #[cfg(feature = "v2")]
+    PlaintextFallbackRefused,
+    /// A v2 payload has `\0` padding inside its query rather than after it
+    #[cfg(feature = "v2")]
+    CorruptQueryPadding,
+    /// The sender's ephemeral key was already used in another session
+    #[cfg(feature = "v2")]
+    EphemeralKeyReused,
+}
+
+/// The `v` parameter values of the `accepted` versions this build supports
+fn accepted_version_params(accepted: &[crate::Version]) -> Vec<&'static str> {
+    accepted
+        .iter()
+        .filter(|version| version.is_supported())
+        .map(|version| version.as_str())
+        .collect()
 }
 
 impl From<InternalRequestError> for RequestError {
     fn from(value: InternalRequestError) -> Self { RequestError(value) }
 }
 
-impl fmt::Display for RequestError {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        fn write_error(f: &mut fmt::Formatter, code: &str, message: impl Display) -> fmt::Result {
-            write!(f, r#"{{ "errorCode": "{}", "message": "{}" }}"#, code, message)
+impl RequestError {
+    /// The `errorCode` reported to the sender
+    pub fn error_code(&self) -> &'static str {
+        match &self.0 {
+            InternalRequestError::Psbt(_) => "psbt-error",
+            InternalRequestError::Base64(_) => "base64-decode-error",
+            InternalRequestError::Io(_) => "io-error",
+            InternalRequestError::MissingHeader(_) => "missing-header",
+            InternalRequestError::InvalidContentType(_) => "invalid-content-type",
+            InternalRequestError::InvalidContentLength(_) => "invalid-content-length",
+            InternalRequestError::ContentLengthTooLarge(_) => "content-length-too-large",
+            InternalRequestError::SenderParams(
+                super::optional_parameters::Error::UnknownVersion,
+            )
+            | InternalRequestError::VersionNotAccepted(_, _) => "version-unsupported",
+            InternalRequestError::SenderParams(_) => "sender-params-error",
+            #[cfg(feature = "v2")]
+            InternalRequestError::ParsePsbt(_) | InternalRequestError::Utf8(_) =>
+                "Error parsing PSBT:",
+            #[cfg(feature = "v2")]
+            InternalRequestError::RateLimited => "unavailable",
+            InternalRequestError::ProposalBelowMinRelayFee(_, _)
+            | InternalRequestError::ProposalBelowSenderMinFeeRate(_, _) => "unavailable",
+            InternalRequestError::InconsistentPsbt(_)
+            | InternalRequestError::PrevTxOut(_)
+            | InternalRequestError::MissingPayment
+            | InternalRequestError::OriginalPsbtNotBroadcastable
+            | InternalRequestError::InputOwned(_)
+            | InternalRequestError::MixedInputScripts(_, _)
+            | InternalRequestError::InputType(_)
+            | InternalRequestError::InputNotFinalized(_)
+            | InternalRequestError::InputSpent(_)
+            | InternalRequestError::OriginalTxConfirmed(_)
+            | InternalRequestError::LeakyMetadata
+            | InternalRequestError::InputSeen(_)
+            | InternalRequestError::PsbtBelowFeeRate(_, _)
+            | InternalRequestError::PsbtAboveFeeRate(_, _)
+            | InternalRequestError::OriginalWeightTooHigh(_, _)
+            | InternalRequestError::FeeContributionExceedsMaximum(_, _)
+            | InternalRequestError::FeeOutputIndexOutOfRange(_, _)
+            | InternalRequestError::ProposalAboveMaxFeeRate(_, _)
+            | InternalRequestError::ProposalDeclined
+            | InternalRequestError::MissingSenderChange => "original-psbt-rejected",
+            #[cfg(feature = "v2")]
+            InternalRequestError::PlaintextFallbackRefused
+            | InternalRequestError::CorruptQueryPadding
+            | InternalRequestError::EphemeralKeyReused => "original-psbt-rejected",
         }
+    }
 
+    /// The human readable `message` of the error reply
+    fn message(&self) -> String {
         match &self.0 {
-            InternalRequestError::Psbt(e) => write_error(f, "psbt-error", e),
-            InternalRequestError::Base64(e) => write_error(f, "base64-decode-error", e),
-            InternalRequestError::Io(e) => write_error(f, "io-error", e),
-            InternalRequestError::MissingHeader(header) =>
-                write_error(f, "missing-header", &format!("Missing header: {}", header)),
-            InternalRequestError::InvalidContentType(content_type) => write_error(
-                f,
-                "invalid-content-type",
-                &format!("Invalid content type: {}", content_type),
-            ),
-            InternalRequestError::InvalidContentLength(e) =>
-                write_error(f, "invalid-content-length", e),
-            InternalRequestError::ContentLengthTooLarge(length) => write_error(
-                f,
-                "content-length-too-large",
-                &format!("Content length too large: {}.", length),
-            ),
+            InternalRequestError::Psbt(e) => e.to_string(),
+            InternalRequestError::Base64(e) => e.to_string(),
+            InternalRequestError::Io(e) => e.to_string(),
+            InternalRequestError::MissingHeader(header) => format!("Missing header: {}", header),
+            InternalRequestError::InvalidContentType(content_type) =>
+                format!("Invalid content type: {}", content_type),
+            InternalRequestError::InvalidContentLength(e) => e.to_string(),
+            InternalRequestError::ContentLengthTooLarge(length) =>
+                format!("Content length too large: {}.", length),
             InternalRequestError::SenderParams(e) => match e {
-                super::optional_parameters::Error::UnknownVersion => {
-                    write!(
-                        f,
-                        r#"{{
-                            "errorCode": "version-unsupported",
-                            "supported": "{}",
-                            "message": "This version of payjoin is not supported."
-                        }}"#,
-                        serde_json::to_string(&super::optional_parameters::SUPPORTED_VERSIONS)
-                            .map_err(|_| fmt::Error)?
-                    )
-                }
-                _ => write_error(f, "sender-params-error", e),
+                super::optional_parameters::Error::UnknownVersion =>
+                    "This version of payjoin is not supported.".to_owned(),
+                _ => e.to_string(),
             },
-            InternalRequestError::InconsistentPsbt(e) =>
-                write_error(f, "original-psbt-rejected", e),
-            InternalRequestError::PrevTxOut(e) =>
-                write_error(f, "original-psbt-rejected", &format!("PrevTxOut Error: {}", e)),
-            InternalRequestError::MissingPayment =>
-                write_error(f, "original-psbt-rejected", "Missing payment."),
-            InternalRequestError::OriginalPsbtNotBroadcastable => write_error(
-                f,
-                "original-psbt-rejected",
-                "Can't broadcast. PSBT rejected by mempool.",
-            ),
+            InternalRequestError::VersionNotAccepted(version, _) =>
+                format!("Payjoin version {} is not accepted by this receiver.", version),
+            InternalRequestError::InconsistentPsbt(e) => e.to_string(),
+            InternalRequestError::PrevTxOut(e) => format!("PrevTxOut Error: {}", e),
+            InternalRequestError::MissingPayment => "Missing payment.".to_owned(),
+            InternalRequestError::OriginalPsbtNotBroadcastable =>
+                "Can't broadcast. PSBT rejected by mempool.".to_owned(),
             InternalRequestError::InputOwned(_) =>
-                write_error(f, "original-psbt-rejected", "The receiver rejected the original PSBT."),
-            InternalRequestError::MixedInputScripts(type_a, type_b) => write_error(
-                f,
-                "original-psbt-rejected",
-                &format!("Mixed input scripts: {}; {}.", type_a, type_b),
-            ),
-            InternalRequestError::InputType(e) =>
-                write_error(f, "original-psbt-rejected", &format!("Input Type Error: {}.", e)),
+                "The receiver rejected the original PSBT.".to_owned(),
+            InternalRequestError::MixedInputScripts(type_a, type_b) =>
+                format!("Mixed input scripts: {}; {}.", type_a, type_b),
+            InternalRequestError::InputType(e) => format!("Input Type Error: {}.", e),
+            InternalRequestError::InputNotFinalized(index) =>
+                format!("Input {} is not finalized.", index),
+            InternalRequestError::InputSpent(outpoint) =>
+                format!("Input {} is already spent.", outpoint),
             InternalRequestError::InputSeen(_) =>
-                write_error(f, "original-psbt-rejected", "The receiver rejected the original PSBT."),
+                "The receiver rejected the original PSBT.".to_owned(),
+            InternalRequestError::OriginalTxConfirmed(txid) =>
+                format!("The original transaction {} is confirmed.", txid),
+            InternalRequestError::LeakyMetadata =>
+                "The original PSBT must not carry xpubs or key origins.".to_owned(),
             #[cfg(feature = "v2")]
-            InternalRequestError::ParsePsbt(e) => write_error(f, "Error parsing PSBT:", e),
+            InternalRequestError::ParsePsbt(e) => e.to_string(),
             #[cfg(feature = "v2")]
-            InternalRequestError::Utf8(e) => write_error(f, "Error parsing PSBT:", e),
+            InternalRequestError::Utf8(e) => e.to_string(),
             InternalRequestError::PsbtBelowFeeRate(
                 original_psbt_fee_rate,
                 receiver_min_fee_rate,
-            ) => write_error(
-                f,
-                "original-psbt-rejected",
-                &format!(
-                    "Original PSBT fee rate too low: {} < {}.",
-                    original_psbt_fee_rate, receiver_min_fee_rate
+            ) => format!(
+                "Original PSBT fee rate too low: {} < {}.",
+                original_psbt_fee_rate, receiver_min_fee_rate
+            ),
+            InternalRequestError::PsbtAboveFeeRate(
+                original_psbt_fee_rate,
+                receiver_max_fee_rate,
+            ) => format!(
+                "Original PSBT fee rate too high: {} > {}.",
+                original_psbt_fee_rate, receiver_max_fee_rate
+            ),
+            InternalRequestError::OriginalWeightTooHigh(weight, max_weight) =>
+                format!("Original PSBT weight too high: {} > {}.", weight, max_weight),
+            InternalRequestError::ProposalDeclined =>
+                "The receiver declined the payjoin.".to_owned(),
+            InternalRequestError::MissingSenderChange =>
+                "The receiver requires the original PSBT to have a change output.".to_owned(),
+            InternalRequestError::FeeContributionExceedsMaximum(
+                additional_fee,
+                max_additional_fee_contribution,
+            ) => format!(
+                "Additional fee contribution too high: {} > {}.",
+                additional_fee, max_additional_fee_contribution
+            ),
+            InternalRequestError::ProposalBelowMinRelayFee(fee_rate, min_relay_fee_rate) =>
+                format!(
+                    "Payjoin proposal fee rate below minimum relay fee: {} < {}.",
+                    fee_rate, min_relay_fee_rate
+                ),
+            InternalRequestError::ProposalBelowSenderMinFeeRate(fee_rate, sender_min_fee_rate) =>
+                format!(
+                    "Payjoin proposal fee rate below the sender's minimum: {} < {}.",
+                    fee_rate, sender_min_fee_rate
                 ),
+            InternalRequestError::ProposalAboveMaxFeeRate(fee_rate, max_fee_rate) => format!(
+                "Payjoin proposal fee rate above the maximum: {} > {}.",
+                fee_rate, max_fee_rate
+            ),
+            InternalRequestError::FeeOutputIndexOutOfRange(index, output_count) =>
+                format!("Additional fee output index out of range: {} >= {}.", index, output_count),
+            #[cfg(feature = "v2")]
+            InternalRequestError::RateLimited => "Too many proposals, try again later.".to_owned(),
+            #[cfg(feature = "v2")]
+            InternalRequestError::EphemeralKeyReused =>
+                "The receiver rejected the original PSBT.".to_owned(),
+            #[cfg(feature = "v2")]
+            InternalRequestError::CorruptQueryPadding => "The payload query is corrupt.".to_owned(),
+            #[cfg(feature = "v2")]
+            InternalRequestError::PlaintextFallbackRefused =>
+                "The receiver only accepts encrypted v2 requests.".to_owned(),
+        }
+    }
+
+    /// Render the error reply as compact JSON that is byte-for-byte stable, e.g. for golden
+    /// tests or hashing replies.
+    ///
+    /// The object has no whitespace and its keys are ordered `errorCode`, `message`, then
+    /// `supported` for unsupported versions. Strings are escaped as JSON requires.
+    pub fn to_canonical_bytes(&self) -> Vec<u8> {
+        self.to_json_with_message(&self.message()).into_bytes()
+    }
+
+    /// Render the error reply with a custom human readable `message`, e.g. a localized one,
+    /// while keeping the standard `errorCode`.
+    ///
+    /// The reply is laid out as by [`RequestError::to_canonical_bytes`].
+    pub fn to_json_with_message(&self, message: &str) -> String {
+        let supported = match &self.0 {
+            InternalRequestError::SenderParams(
+                super::optional_parameters::Error::UnknownVersion,
+            ) => Some(super::optional_parameters::supported_version_params()),
+            InternalRequestError::VersionNotAccepted(_, accepted) =>
+                Some(accepted_version_params(accepted)),
+            _ => None,
+        };
+        let mut reply = format!(
+            r#"{{"errorCode":{},"message":{}"#,
+            json_string(self.error_code()),
+            json_string(message)
+        );
+        if let Some(supported) = supported {
+            let supported: Vec<String> = supported.into_iter().map(json_string).collect();
+            reply.push_str(&format!(r#","supported":[{}]"#, supported.join(",")));
+        }
+        reply.push('}');
+        reply
+    }
+}
+
+/// `s` as a JSON string literal
+fn json_string(s: &str) -> String {
+    serde_json::to_string(s).expect("serializing a string can't fail")
+}
+
+impl fmt::Display for RequestError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let message = self.message();
+        // The alternate form `{:#}` writes only the human readable message
+        if f.alternate() {
+            return f.write_str(&message);
+        }
+        match &self.0 {
+            InternalRequestError::SenderParams(
+                super::optional_parameters::Error::UnknownVersion,
+            ) => write!(
+                f,
+                r#"{{
+                    "errorCode": "{}",
+                    "supported": "{}",
+                    "message": "{}"
+                }}"#,
+                self.error_code(),
+                serde_json::to_string(&super::optional_parameters::supported_version_params())
+                    .map_err(|_| fmt::Error)?,
+                message
             ),
+            InternalRequestError::VersionNotAccepted(_, _) =>
+                f.write_str(&self.to_json_with_message(&message)),
+            _ =>
+                write!(f, r#"{{ "errorCode": "{}", "message": "{}" }}"#, self.error_code(), message),
         }
     }
 }
@@ -211,6 +428,17 @@ pub(crate) enum InternalSelectionError {
     TooManyOutputs,
     /// No selection candidates improve privacy
     NotFound,
+    /// The receiver already contributed as many inputs as its consolidation limit allows
+    ConsolidationLimit(usize),
+    /// A custom [`CoinSelector`](super::CoinSelector) failed
+    Custom(Box<dyn error::Error + Send + Sync>),
+}
+
+impl SelectionError {
+    /// Report the failure of a custom [`CoinSelector`](super::CoinSelector)
+    pub fn custom(e: impl Into<Box<dyn error::Error + Send + Sync>>) -> Self {
+        SelectionError(InternalSelectionError::Custom(e.into()))
+    }
 }
 
 impl fmt::Display for SelectionError {
@@ -223,6 +451,9 @@ impl fmt::Display for SelectionError {
             ),
             InternalSelectionError::NotFound =>
                 write!(f, "No selection candidates improve privacy"),
+            InternalSelectionError::ConsolidationLimit(max_inputs) =>
+                write!(f, "Contributing another input would exceed the limit of {}", max_inputs),
+            InternalSelectionError::Custom(e) => write!(f, "Coin selection failed: {}", e),
         }
     }
 }
@@ -230,3 +461,322 @@ impl fmt::Display for SelectionError {
 impl From<InternalSelectionError> for SelectionError {
     fn from(value: InternalSelectionError) -> Self { SelectionError(value) }
 }
+
+/// Error that may occur when substituting the receiver output.
+///
+/// This is currently opaque type because we aren't sure which variants will stay.
+/// You can only display it.
+#[derive(Debug)]
+pub struct OutputSubstitutionError(InternalOutputSubstitutionError);
+
+#[derive(Debug)]
+pub(crate) enum InternalOutputSubstitutionError {
+    /// The receiver output would fall below the dust threshold of its script.
+    ///
+    /// First argument is the value of the output.
+    ///
+    /// Second argument is the dust threshold of the substitute script.
+    OutputBelowDust(bitcoin::Amount, bitcoin::Amount),
+    /// The output at this index does not belong to the receiver
+    NotReceiverOutput(usize),
+    /// The substitute script is already used by the sender output at this index
+    DuplicateScript(usize),
+    /// The receiver outputs cannot spare the requested value.
+    ///
+    /// First argument is the value needed.
+    ///
+    /// Second argument is the most a receiver output can spare without becoming dust.
+    InsufficientValue(bitcoin::Amount, bitcoin::Amount),
+    /// The sender set `disableoutputsubstitution`
+    Disabled,
+}
+
+impl fmt::Display for OutputSubstitutionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.0 {
+            InternalOutputSubstitutionError::OutputBelowDust(value, dust) =>
+                write!(f, "Substituted output is dust: {} < {}", value, dust),
+            InternalOutputSubstitutionError::NotReceiverOutput(vout) =>
+                write!(f, "Output {} does not belong to the receiver", vout),
+            InternalOutputSubstitutionError::DuplicateScript(vout) =>
+                write!(f, "Substitute script duplicates sender output {}", vout),
+            InternalOutputSubstitutionError::InsufficientValue(needed, available) => write!(
+                f,
+                "Receiver outputs cannot spare {}, at most {} is available",
+                needed, available
+            ),
+            InternalOutputSubstitutionError::Disabled =>
+                write!(f, "The sender disabled output substitution"),
+        }
+    }
+}
+
+impl error::Error for OutputSubstitutionError {}
+
+impl From<InternalOutputSubstitutionError> for OutputSubstitutionError {
+    fn from(value: InternalOutputSubstitutionError) -> Self { OutputSubstitutionError(value) }
+}
+
+/// Error that may occur when changing the inputs the receiver contributed.
+///
+/// This is currently opaque type because we aren't sure which variants will stay.
+/// You can only display it.
+#[derive(Debug)]
+pub struct InputContributionError(InternalInputContributionError);
+
+#[derive(Debug)]
+pub(crate) enum InternalInputContributionError {
+    /// The receiver has not contributed any inputs
+    NoContribution,
+    /// The receiver's input policy vetoed contributing this input
+    Vetoed(bitcoin::OutPoint),
+    /// Every candidate input that can't be contributed, and why
+    InvalidInputs(Vec<(bitcoin::OutPoint, InvalidInput)>),
+    /// Receiver output scripts that are also spent by its contributed inputs
+    AddressReuse(Vec<bitcoin::ScriptBuf>),
+    /// The contributed input, or the value it added to a receiver output, is no longer there
+    ContributionChanged(bitcoin::OutPoint),
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum InvalidInput {
+    /// The outpoint appears more than once among the candidates
+    Duplicate,
+    /// The outpoint is already spent by the proposal
+    AlreadyInProposal,
+    /// The input is worth less than the dust threshold of its script
+    Dust(bitcoin::Amount),
+    /// The input is worth less than the fee it costs to spend, and the minimum value needed
+    BelowFeeTarget(bitcoin::Amount, bitcoin::Amount),
+    /// The receiver requires contributed inputs to be confirmed
+    Unconfirmed,
+}
+
+impl fmt::Display for InvalidInput {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            InvalidInput::Duplicate => write!(f, "duplicate candidate"),
+            InvalidInput::AlreadyInProposal => write!(f, "already spent by the proposal"),
+            InvalidInput::Dust(value) => write!(f, "dust value of {}", value),
+            InvalidInput::BelowFeeTarget(value, min) =>
+                write!(f, "value of {} does not cover its fee, needs {}", value, min),
+            InvalidInput::Unconfirmed => write!(f, "unconfirmed"),
+        }
+    }
+}
+
+impl fmt::Display for InputContributionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.0 {
+            InternalInputContributionError::NoContribution =>
+                write!(f, "No receiver input contribution to undo"),
+            InternalInputContributionError::Vetoed(outpoint) =>
+                write!(f, "Input {} was vetoed by the receiver's policy", outpoint),
+            InternalInputContributionError::ContributionChanged(outpoint) => write!(
+                f,
+                "Contributed input {} or the value it added to the receiver output is gone",
+                outpoint
+            ),
+            InternalInputContributionError::InvalidInputs(invalid) => {
+                write!(f, "Invalid inputs:")?;
+                for (outpoint, reason) in invalid {
+                    write!(f, " {} ({});", outpoint, reason)?;
+                }
+                Ok(())
+            }
+            InternalInputContributionError::AddressReuse(scripts) => {
+                write!(f, "Receiver outputs reuse the scripts of its inputs:")?;
+                for script in scripts {
+                    write!(f, " {};", script)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl error::Error for InputContributionError {}
+
+impl From<InternalInputContributionError> for InputContributionError {
+    fn from(value: InternalInputContributionError) -> Self { InputContributionError(value) }
+}
+
+/// Error that may occur when a transaction differs from the committed Payjoin Proposal.
+///
+/// This is currently opaque type because we aren't sure which variants will stay.
+/// You can only display it.
+#[derive(Debug)]
+pub struct CommittedTxError(InternalCommittedTxError);
+
+#[derive(Debug)]
+pub(crate) enum InternalCommittedTxError {
+    /// The transaction version differs
+    Version,
+    /// The transaction lock time differs
+    LockTime,
+    /// The number of inputs differs
+    InputCount(usize, usize),
+    /// The input at this index spends another outpoint or sets another sequence
+    Input(usize),
+    /// The number of outputs differs
+    OutputCount(usize, usize),
+    /// The output at this index pays another script or amount
+    Output(usize),
+}
+
+impl fmt::Display for CommittedTxError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use InternalCommittedTxError::*;
+
+        match &self.0 {
+            Version => write!(f, "Transaction version differs from the proposal"),
+            LockTime => write!(f, "Transaction lock time differs from the proposal"),
+            InputCount(count, expected) =>
+                write!(f, "Transaction has {} inputs, the proposal has {}", count, expected),
+            Input(index) => write!(f, "Input {} differs from the proposal", index),
+            OutputCount(count, expected) =>
+                write!(f, "Transaction has {} outputs, the proposal has {}", count, expected),
+            Output(index) => write!(f, "Output {} differs from the proposal", index),
+        }
+    }
+}
+
+impl error::Error for CommittedTxError {}
+
+impl From<InternalCommittedTxError> for CommittedTxError {
+    fn from(value: InternalCommittedTxError) -> Self { CommittedTxError(value) }
+}
+
+impl From<CommittedTxError> for Error {
+    fn from(e: CommittedTxError) -> Self { Error::Server(Box::new(e)) }
+}
+
+/// Error that may occur when the payjoin directory URL is misconfigured.
+///
+/// This is currently opaque type because we aren't sure which variants will stay.
+/// You can only display it.
+#[cfg(feature = "v2")]
+#[derive(Debug)]
+pub struct DirectoryUrlError(InternalDirectoryUrlError);
+
+#[cfg(feature = "v2")]
+#[derive(Debug)]
+pub(crate) enum InternalDirectoryUrlError {
+    /// The directory is not reached over https and insecure directories were not allowed
+    InsecureScheme(String),
+    /// The directory redirected a session to another origin or away from its subdirectory
+    UnsupportedRedirect(String),
+    /// The OHTTP relay shares the directory's host and port
+    SharedRelayAuthority(String),
+    /// The directory answered a session status request with an unexpected status code
+    UnexpectedStatus(u16),
+}
+
+#[cfg(feature = "v2")]
+impl fmt::Display for DirectoryUrlError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.0 {
+            InternalDirectoryUrlError::InsecureScheme(scheme) =>
+                write!(f, "Directory URL must use https, found scheme: {}", scheme),
+            InternalDirectoryUrlError::UnsupportedRedirect(location) =>
+                write!(f, "Directory redirect to {} leaves its origin or session", location),
+            InternalDirectoryUrlError::SharedRelayAuthority(relay) =>
+                write!(f, "OHTTP relay {} shares the directory's authority", relay),
+            InternalDirectoryUrlError::UnexpectedStatus(status) =>
+                write!(f, "Directory answered the status request with {}", status),
+        }
+    }
+}
+
+#[cfg(feature = "v2")]
+impl error::Error for DirectoryUrlError {}
+
+#[cfg(feature = "v2")]
+impl From<InternalDirectoryUrlError> for DirectoryUrlError {
+    fn from(value: InternalDirectoryUrlError) -> Self { DirectoryUrlError(value) }
+}
+
+/// Error that may occur when encoding an [`Enrolled`](super::v2::Enrolled) session as a
+/// recovery code or decoding it from one.
+///
+/// This is currently opaque type because we aren't sure which variants will stay.
+/// You can only display it.
+#[cfg(feature = "v2")]
+#[derive(Debug)]
+pub struct RecoveryCodeError(InternalRecoveryCodeError);
+
+#[cfg(feature = "v2")]
+#[derive(Debug)]
+pub(crate) enum InternalRecoveryCodeError {
+    Base64(bitcoin::base64::DecodeError),
+    UnsupportedVersion(u8),
+    UnexpectedEnd,
+    Utf8(std::str::Utf8Error),
+    Url(url::ParseError),
+    SecretKey(bitcoin::secp256k1::Error),
+    OhttpKeys(ohttp::Error),
+    /// A URL or content type is longer than its 2 byte length prefix can declare
+    FieldTooLong(usize),
+}
+
+#[cfg(feature = "v2")]
+impl fmt::Display for RecoveryCodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use InternalRecoveryCodeError::*;
+
+        match &self.0 {
+            Base64(e) => write!(f, "Recovery code is not valid base64: {}", e),
+            UnsupportedVersion(v) => write!(f, "Unsupported recovery code version: {}", v),
+            UnexpectedEnd => write!(f, "Recovery code ends unexpectedly"),
+            Utf8(e) => write!(f, "Recovery code URL is not valid UTF-8: {}", e),
+            Url(e) => write!(f, "Recovery code URL is invalid: {}", e),
+            SecretKey(e) => write!(f, "Recovery code secret key is invalid: {}", e),
+            OhttpKeys(e) => write!(f, "Recovery code OHTTP keys are invalid: {}", e),
+            FieldTooLong(len) =>
+                write!(f, "Recovery code field of {} bytes exceeds {} bytes", len, u16::MAX),
+        }
+    }
+}
+
+#[cfg(feature = "v2")]
+impl error::Error for RecoveryCodeError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        use InternalRecoveryCodeError::*;
+
+        match &self.0 {
+            Base64(e) => Some(e),
+            Utf8(e) => Some(e),
+            Url(e) => Some(e),
+            SecretKey(e) => Some(e),
+            OhttpKeys(e) => Some(e),
+            UnsupportedVersion(_) | UnexpectedEnd | FieldTooLong(_) => None,
+        }
+    }
+}
+
+#[cfg(feature = "v2")]
+impl From<InternalRecoveryCodeError> for RecoveryCodeError {
+    fn from(value: InternalRecoveryCodeError) -> Self { RecoveryCodeError(value) }
+}
+
+/// Error that may occur when decoding an [`Enrolled`](super::v2::Enrolled) session from CBOR.
+#[cfg(feature = "cbor")]
+#[derive(Debug)]
+pub struct CborError(ciborium::de::Error<std::io::Error>);
+
+#[cfg(feature = "cbor")]
+impl fmt::Display for CborError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Invalid CBOR session: {}", self.0)
+    }
+}
+
+#[cfg(feature = "cbor")]
+impl error::Error for CborError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> { Some(&self.0) }
+}
+
+#[cfg(feature = "cbor")]
+impl From<ciborium::de::Error<std::io::Error>> for CborError {
+    fn from(value: ciborium::de::Error<std::io::Error>) -> Self { CborError(value) }
+}
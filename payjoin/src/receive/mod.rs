@@ -25,24 +25,35 @@
 //! [reference implementation](https://github.com/payjoin/rust-payjoin/tree/master/payjoin-cli)
 
 use std::cmp::{max, min};
-use std::collections::{BTreeMap, HashMap};
+use std::collections::{BTreeMap, HashMap, HashSet};
 
 use bitcoin::psbt::Psbt;
-use bitcoin::{base64, Amount, FeeRate, OutPoint, Script, TxOut};
+use bitcoin::{base64, Amount, FeeRate, OutPoint, Script, ScriptBuf, TxOut, Weight};
 
 mod error;
 mod optional_parameters;
 #[cfg(feature = "v2")]
 pub mod v2;
 
-pub use error::{Error, RequestError, SelectionError};
-use error::{InternalRequestError, InternalSelectionError};
+#[cfg(feature = "cbor")]
+pub use error::CborError;
+pub use error::{
+    CommittedTxError, Error, InputContributionError, OutputSubstitutionError, RequestError,
+    SelectionError,
+};
+#[cfg(feature = "v2")]
+pub use error::{DirectoryUrlError, RecoveryCodeError};
+use error::{
+    InternalCommittedTxError, InternalInputContributionError, InternalOutputSubstitutionError,
+    InternalRequestError, InternalSelectionError, InvalidInput,
+};
 use optional_parameters::Params;
 use rand::seq::SliceRandom;
 use rand::Rng;
 
 use crate::input_type::InputType;
 use crate::psbt::PsbtExt;
+use crate::weight::ComputeSize;
 
 pub trait Headers {
     fn get_header(&self, key: &str) -> Option<&str>;
@@ -65,9 +76,22 @@ pub struct UncheckedProposal {
 
 impl UncheckedProposal {
     pub fn from_request(
+        body: impl std::io::Read,
+        query: &str,
+        headers: impl Headers,
+    ) -> Result<Self, RequestError> {
+        Self::from_request_with_versions(body, query, headers, crate::supported_versions())
+    }
+
+    /// Like [`UncheckedProposal::from_request`], but reject senders whose declared `v` is not
+    /// one of `accepted_versions` with a `version-unsupported` error listing them.
+    ///
+    /// Senders that omit `v` are taken to speak version 1.
+    pub fn from_request_with_versions(
         mut body: impl std::io::Read,
         query: &str,
         headers: impl Headers,
+        accepted_versions: &[crate::Version],
     ) -> Result<Self, RequestError> {
         let content_type = headers
             .get_header("content-type")
@@ -97,8 +121,8 @@ impl UncheckedProposal {
         let pairs = url::form_urlencoded::parse(query.as_bytes());
         let params = Params::from_query_pairs(pairs).map_err(InternalRequestError::SenderParams)?;
         log::debug!("Received request with params: {:?}", params);
-
-        // TODO check that params are valid for the request's Original PSBT
+        check_version(params.v, accepted_versions)?;
+        check_params(&psbt, &params)?;
 
         Ok(UncheckedProposal { psbt, params })
     }
@@ -108,6 +132,240 @@ impl UncheckedProposal {
         self.psbt.clone().extract_tx()
     }
 
+    /// Check that every input of the Original PSBT carries a final scriptSig or witness.
+    ///
+    /// A PSBT the sender has not finalized cannot be broadcast, which would make the
+    /// broadcast suitability check meaningless. Return original-psbt-rejected otherwise.
+    pub fn require_sender_finalized(self) -> Result<Self, Error> {
+        if let Some(index) = self.psbt.inputs.iter().position(|input| {
+            input.final_script_sig.is_none() && input.final_script_witness.is_none()
+        }) {
+            return Err(InternalRequestError::InputNotFinalized(index).into());
+        }
+        Ok(self)
+    }
+
+    /// Check that every input of the Original PSBT spends an output that still exists.
+    ///
+    /// An Original PSBT spending already-spent outputs can never confirm, so there is no point in
+    /// building a payjoin around it. Return original-psbt-rejected otherwise.
+    pub fn require_unspent_inputs(
+        self,
+        is_unspent: impl Fn(&OutPoint) -> Result<bool, Error>,
+    ) -> Result<Self, Error> {
+        self.psbt.unsigned_tx.input.iter().try_for_each(|txin| {
+            match is_unspent(&txin.previous_output) {
+                Ok(true) => Ok::<(), Error>(()),
+                Ok(false) => Err(Error::BadRequest(
+                    InternalRequestError::InputSpent(txin.previous_output).into(),
+                ))?,
+                Err(e) => Err(Error::Server(e.into()))?,
+            }
+        })?;
+        Ok(self)
+    }
+
+    /// Reject an Original PSBT that carries metadata about the sender's wallet.
+    ///
+    /// The leaky fields are the global xpubs and the BIP32 derivations and taproot key origins
+    /// of every input and output. A sender following BIP 78 leaves them out, so their presence
+    /// points at a careless sender rather than a payjoin problem. Return original-psbt-rejected
+    /// otherwise, or use [`UncheckedProposal::strip_leaky_metadata`] to carry on without them.
+    pub fn require_no_leaky_metadata(self) -> Result<Self, Error> {
+        if has_leaky_metadata(&self.psbt) {
+            return Err(InternalRequestError::LeakyMetadata.into());
+        }
+        Ok(self)
+    }
+
+    /// Remove the fields [`UncheckedProposal::require_no_leaky_metadata`] rejects from the
+    /// Original PSBT.
+    pub fn strip_leaky_metadata(mut self) -> Self {
+        strip_leaky_metadata(&mut self.psbt);
+        self
+    }
+
+    /// Check that the Original PSBT transaction is not confirmed yet.
+    ///
+    /// A stale or replayed Original PSBT whose transaction has already been mined can't be
+    /// turned into a payjoin. Return original-psbt-rejected otherwise.
+    pub fn require_unconfirmed(
+        self,
+        is_confirmed: impl Fn(&bitcoin::Txid) -> Result<bool, Error>,
+    ) -> Result<Self, Error> {
+        let txid = self.psbt.unsigned_tx.txid();
+        match is_confirmed(&txid) {
+            Ok(false) => Ok(self),
+            Ok(true) => Err(InternalRequestError::OriginalTxConfirmed(txid).into()),
+            Err(e) => Err(Error::Server(e.into())),
+        }
+    }
+
+    /// Reject an Original PSBT that does not appear to pay change back to the sender.
+    ///
+    /// Contributing inputs to a transaction that spends the sender's coins in full is
+    /// especially revealing, so some receivers opt out of it. The Original PSBT must have more
+    /// than one output, and one of them must look like change by the heuristics of
+    /// [`ProvisionalProposal::likely_sender_change`]. The receiver's own outputs are not known
+    /// yet, so a non-round payment to the sender's script type passes as change too, and change
+    /// that looks like a payment is missed. Return original-psbt-rejected otherwise.
+    pub fn require_sender_change(self) -> Result<Self, Error> {
+        if self.psbt.unsigned_tx.output.len() < 2 || likely_change_vouts(&self.psbt, &[]).is_empty()
+        {
+            return Err(InternalRequestError::MissingSenderChange.into());
+        }
+        Ok(self)
+    }
+
+    /// Reject an Original PSBT whose transaction weighs more than `max_weight`.
+    ///
+    /// The weight of the Original PSBT bounds how much fee the receiver may be exposed to.
+    pub fn limit_original_weight(self, max_weight: Weight) -> Result<Self, Error> {
+        let weight = self.extract_tx_to_schedule_broadcast().weight();
+        if weight > max_weight {
+            return Err(InternalRequestError::OriginalWeightTooHigh(weight, max_weight).into());
+        }
+        Ok(self)
+    }
+
+    /// Reject an Original PSBT whose fee rate exceeds `max_fee_rate`.
+    ///
+    /// An Original PSBT paying an absurd fee rate may be an attempt to draw the receiver's inputs
+    /// into an overpaying or fee-sniping transaction.
+    pub fn limit_original_fee_rate(self, max_fee_rate: FeeRate) -> Result<Self, Error> {
+        let original_psbt_fee_rate = self.psbt_fee_rate()?;
+        if original_psbt_fee_rate > max_fee_rate {
+            return Err(InternalRequestError::PsbtAboveFeeRate(
+                original_psbt_fee_rate,
+                max_fee_rate,
+            )
+            .into());
+        }
+        Ok(self)
+    }
+
+    /// Advisory heuristic that flags an Original PSBT which already looks like a payjoin.
+    ///
+    /// Returns true when the Original PSBT spends more than one input and either
+    /// - the inputs spend mixed script types, which a single wallet rarely produces, or
+    /// - one input is unnecessary, i.e. the largest output and the fee could be paid without
+    ///   the smallest input (UIH2).
+    ///
+    /// Wallets that consolidate or whose coin selection overshoots trip this heuristic too, so a
+    /// positive result warrants extra scrutiny rather than outright rejection. Inputs missing
+    /// previous output information make the heuristic inconclusive and it returns false.
+    ///
+    /// This complements `check_no_inputs_seen_before`, which detects reentrant payjoin exactly
+    /// when the receiver has seen the inputs before.
+    pub fn looks_like_payjoin(&self) -> bool {
+        let inputs = match self
+            .psbt
+            .input_pairs()
+            .map(|input| {
+                let txout = input.previous_txout().ok()?;
+                Some((txout.value, InputType::from_spent_input(txout, input.psbtin).ok()))
+            })
+            .collect::<Option<Vec<_>>>()
+        {
+            Some(inputs) if inputs.len() > 1 => inputs,
+            _ => return false,
+        };
+
+        let first_type = inputs[0].1;
+        if inputs.iter().any(|(_, input_type)| *input_type != first_type) {
+            log::debug!("Original PSBT spends mixed input scripts");
+            return true;
+        }
+
+        let total_in: u64 = inputs.iter().map(|(value, _)| value).sum();
+        let min_in = inputs.iter().map(|(value, _)| *value).min().unwrap_or_default();
+        let outputs = &self.psbt.unsigned_tx.output;
+        let total_out: u64 = outputs.iter().map(|txo| txo.value).sum();
+        let max_out = outputs.iter().map(|txo| txo.value).max().unwrap_or_default();
+        let fee = total_in.saturating_sub(total_out);
+        if total_in - min_in >= max_out + fee {
+            log::debug!("Original PSBT contains an unnecessary input");
+            return true;
+        }
+        false
+    }
+
+    /// The outputs of the Original PSBT, including the payment and any sender change
+    pub fn original_outputs(&self) -> Vec<TxOut> { self.psbt.unsigned_tx.output.clone() }
+
+    /// The sender's inputs with the previous outputs they spend.
+    ///
+    /// Errors if an input of the Original PSBT lacks its previous output.
+    pub fn sender_inputs(&self) -> Result<Vec<(OutPoint, TxOut)>, Error> {
+        self.psbt
+            .input_pairs()
+            .map(|input| {
+                let txo = input.previous_txout().map_err(InternalRequestError::PrevTxOut)?;
+                Ok((input.txin.previous_output, txo.clone()))
+            })
+            .collect()
+    }
+
+    /// A canonical hash of the Original PSBT transaction, stable across serialization round-trips.
+    ///
+    /// Only the version, inputs, outputs and lock time are committed to. Script sigs, witnesses
+    /// and other PSBT fields are left out, so a resubmitted Original hashes the same.
+    pub fn original_psbt_fingerprint(&self) -> bitcoin::hashes::sha256::Hash {
+        use bitcoin::hashes::Hash;
+
+        let mut tx = self.psbt.unsigned_tx.clone();
+        for txin in &mut tx.input {
+            txin.script_sig = ScriptBuf::new();
+            txin.witness = bitcoin::Witness::new();
+        }
+        bitcoin::hashes::sha256::Hash::hash(&bitcoin::consensus::encode::serialize(&tx))
+    }
+
+    /// The most the sender allows to be deducted from its outputs to pay for the receiver's
+    /// inputs, as declared by `maxadditionalfeecontribution`
+    pub fn additional_fee_contribution_limit(&self) -> Option<Amount> {
+        self.params.additional_fee_contribution.map(|(max, _)| max)
+    }
+
+    /// The output the additional fee is deducted from, as declared by `additionalfeeoutputindex`
+    pub fn additional_fee_output_index(&self) -> Option<usize> {
+        self.params.additional_fee_contribution.map(|(_, index)| index)
+    }
+
+    /// The lowest fee rate the sender accepts for the Payjoin Proposal, as declared by
+    /// `minfeerate`. Zero if the sender did not declare one.
+    pub fn sender_min_fee_rate(&self) -> FeeRate { self.params.min_feerate }
+
+    /// The address type shared by every sender input, or `None` if the inputs mix types.
+    ///
+    /// `None` is also returned if an input lacks its previous output or spends a script
+    /// without an address type.
+    pub fn original_inputs_uniform_type(&self) -> Option<bitcoin::AddressType> {
+        let mut types = self.psbt.input_pairs().map(|input| {
+            let txo = input.previous_txout().ok()?;
+            bitcoin::Address::from_script(&txo.script_pubkey, bitcoin::Network::Bitcoin)
+                .ok()?
+                .address_type()
+        });
+        let first = types.next()??;
+        types.all(|ty| ty == Some(first)).then_some(first)
+    }
+
+    /// Summarize the Original PSBT for a confirmation screen
+    pub fn as_display_payload(&self) -> DisplayInfo {
+        DisplayInfo {
+            outputs: self
+                .psbt
+                .unsigned_tx
+                .output
+                .iter()
+                .map(|txo| (txo.script_pubkey.clone(), Amount::from_sat(txo.value)))
+                .collect(),
+            sender_input_count: self.psbt.unsigned_tx.input.len(),
+            fee_rate: self.psbt_fee_rate().ok(),
+        }
+    }
+
     fn psbt_fee_rate(&self) -> Result<FeeRate, Error> {
         let original_psbt_fee = self.psbt.fee().map_err(InternalRequestError::Psbt)?;
         Ok(original_psbt_fee / self.extract_tx_to_schedule_broadcast().weight())
@@ -160,6 +418,42 @@ impl UncheckedProposal {
     }
 }
 
+/// Details of the Original PSBT that wallets commonly show before accepting a payjoin.
+///
+/// Returned by [`UncheckedProposal::as_display_payload()`]. Which outputs belong to the receiver
+/// is not known yet, so every output is listed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DisplayInfo {
+    /// The script and amount of every output of the Original PSBT
+    pub outputs: Vec<(ScriptBuf, Amount)>,
+    /// The number of inputs the sender spends
+    pub sender_input_count: usize,
+    /// The Original PSBT fee rate, if it can be computed from the previous outputs
+    pub fee_rate: Option<FeeRate>,
+}
+
+/// Check that the sender's payjoin version is one this receiver accepts.
+pub(crate) fn check_version(
+    version: crate::Version,
+    accepted_versions: &[crate::Version],
+) -> Result<(), InternalRequestError> {
+    if !accepted_versions.contains(&version) {
+        return Err(InternalRequestError::VersionNotAccepted(version, accepted_versions.to_vec()));
+    }
+    Ok(())
+}
+
+/// Check that the sender's parameters are valid for the Original PSBT.
+pub(crate) fn check_params(psbt: &Psbt, params: &Params) -> Result<(), InternalRequestError> {
+    if let Some((_, index)) = params.additional_fee_contribution {
+        let output_count = psbt.unsigned_tx.output.len();
+        if index >= output_count {
+            return Err(InternalRequestError::FeeOutputIndexOutOfRange(index, output_count));
+        }
+    }
+    Ok(())
+}
+
 /// Typestate to validate that the Original PSBT has no receiver-owned inputs.
 ///
 /// Call [`check_no_receiver_owned_inputs()`](struct.UncheckedProposal.html#method.check_no_receiver_owned_inputs) to proceed.
@@ -328,10 +622,145 @@ impl OutputsUnknown {
             payjoin_psbt: self.psbt,
             params: self.params,
             owned_vouts,
+            fee_subtraction: FeeSubtractionStrategy::default(),
+            contributions: Vec::new(),
+            min_relay_fee_rate: FeeRate::BROADCAST_MIN,
+            reject_address_reuse: false,
         })
     }
 }
 
+/// How fees owed by the receiver are apportioned across its outputs
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FeeSubtractionStrategy {
+    /// Subtract from the first receiver output
+    #[default]
+    FirstOutput,
+    /// Subtract from the receiver output at this index of the transaction
+    Designated(usize),
+    /// Subtract from the receiver output with the largest amount
+    Largest,
+    /// Subtract from every receiver output in proportion to its amount
+    Proportional,
+}
+
+/// What to do when the receiver's policy vetoes one of the inputs it offered to contribute
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum VetoHandling {
+    /// Contribute the remaining inputs
+    #[default]
+    Skip,
+    /// Contribute none of the inputs
+    Abort,
+}
+
+/// The state of the payjoin a [`CoinSelector`] chooses receiver inputs for
+#[derive(Debug, Clone)]
+pub struct SelectionContext {
+    sender_inputs: Vec<(OutPoint, TxOut)>,
+    contributed_inputs: Vec<(OutPoint, TxOut)>,
+    outputs: Vec<TxOut>,
+    receiver_vouts: Vec<usize>,
+}
+
+impl SelectionContext {
+    /// The sender's inputs with the previous outputs they spend
+    pub fn sender_inputs(&self) -> &[(OutPoint, TxOut)] { &self.sender_inputs }
+
+    /// The inputs the receiver contributed so far with the previous outputs they spend
+    pub fn contributed_inputs(&self) -> &[(OutPoint, TxOut)] { &self.contributed_inputs }
+
+    /// The outputs of the payjoin, including the sender's change and the receiver's outputs
+    pub fn outputs(&self) -> &[TxOut] { &self.outputs }
+
+    /// The indexes of the receiver's outputs in [`SelectionContext::outputs`]
+    pub fn receiver_vouts(&self) -> &[usize] { &self.receiver_vouts }
+}
+
+/// Chooses which of the receiver's candidate inputs to contribute to a payjoin.
+///
+/// Implement it to drive [`ProvisionalProposal::select_inputs`] with a custom coin selection
+/// engine. [`PrivacyPreservingSelector`] is the built-in one.
+pub trait CoinSelector {
+    /// Select the inputs to contribute from `candidates`
+    fn select(
+        &self,
+        candidates: &[(TxOut, OutPoint)],
+        context: &SelectionContext,
+    ) -> Result<Vec<(TxOut, OutPoint)>, SelectionError>;
+}
+
+/// Select the first candidate that avoids the unnecessary input heuristic, as
+/// [`ProvisionalProposal::try_preserving_privacy`] does.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PrivacyPreservingSelector;
+
+impl CoinSelector for PrivacyPreservingSelector {
+    fn select(
+        &self,
+        candidates: &[(TxOut, OutPoint)],
+        context: &SelectionContext,
+    ) -> Result<Vec<(TxOut, OutPoint)>, SelectionError> {
+        let candidates = candidates.iter().map(|input| (Amount::from_sat(input.0.value), input));
+        Ok(vec![select_preserving_privacy(context, candidates)?.clone()])
+    }
+}
+
+/// Select receiver input such that the payjoin avoids UIH2, see
+/// [`ProvisionalProposal::try_preserving_privacy`]
+fn select_preserving_privacy<T>(
+    context: &SelectionContext,
+    candidate_inputs: impl IntoIterator<Item = (Amount, T)>,
+) -> Result<T, InternalSelectionError> {
+    let mut candidate_inputs = candidate_inputs.into_iter().peekable();
+    if candidate_inputs.peek().is_none() {
+        return Err(InternalSelectionError::Empty);
+    }
+
+    if context.outputs.len() != 2 {
+        // Current UIH techniques only support many-input, two-output transactions.
+        return Err(InternalSelectionError::TooManyOutputs);
+    }
+
+    let min_original_out_sats = context
+        .outputs
+        .iter()
+        .map(|output| output.value)
+        .min()
+        .unwrap_or_else(|| Amount::MAX_MONEY.to_sat());
+
+    let min_original_in_sats = context
+        .sender_inputs
+        .iter()
+        .chain(&context.contributed_inputs)
+        .map(|(_, txo)| txo.value)
+        .min()
+        .unwrap_or_else(|| Amount::MAX_MONEY.to_sat());
+
+    // Assume many-input, two output to select the vout for now
+    let prior_payment_sats = context.outputs[context.receiver_vouts[0]].value;
+    for candidate in candidate_inputs {
+        // TODO bound loop by timeout / iterations
+
+        let candidate_sats = candidate.0.to_sat();
+        let candidate_min_out = min(min_original_out_sats, prior_payment_sats + candidate_sats);
+        let candidate_min_in = min(min_original_in_sats, candidate_sats);
+
+        if candidate_min_out < candidate_min_in {
+            // The candidate avoids UIH2 but conforms to UIH1: Optimal change heuristic.
+            // It implies the smallest output is the sender's change address.
+            return Ok(candidate.1);
+        } else {
+            // The candidate conforms to UIH2: Unnecessary input
+            // and could be identified as a potential payjoin
+            continue;
+        }
+    }
+
+    // No suitable privacy preserving selection found
+    Err(InternalSelectionError::NotFound)
+}
+
 /// A mutable checked proposal that the receiver may contribute inputs to to make a payjoin.
 #[derive(Debug, Clone)]
 pub struct ProvisionalProposal {
@@ -339,6 +768,19 @@ pub struct ProvisionalProposal {
     payjoin_psbt: Psbt,
     params: Params,
     owned_vouts: Vec<usize>,
+    fee_subtraction: FeeSubtractionStrategy,
+    /// The input sets contributed so far, one per `contribute_*` call, oldest first
+    contributions: Vec<Vec<Contribution>>,
+    min_relay_fee_rate: FeeRate,
+    reject_address_reuse: bool,
+}
+
+/// A receiver input and the value it added to a receiver output
+#[derive(Debug, Clone)]
+struct Contribution {
+    outpoint: OutPoint,
+    vout: usize,
+    value: u64,
 }
 
 impl ProvisionalProposal {
@@ -357,56 +799,137 @@ impl ProvisionalProposal {
         &self,
         candidate_inputs: HashMap<Amount, OutPoint>,
     ) -> Result<OutPoint, SelectionError> {
-        if candidate_inputs.is_empty() {
-            return Err(SelectionError::from(InternalSelectionError::Empty));
-        }
+        Ok(select_preserving_privacy(&self.selection_context(), candidate_inputs)?)
+    }
 
-        if self.payjoin_psbt.outputs.len() != 2 {
-            // Current UIH techniques only support many-input, two-output transactions.
-            return Err(SelectionError::from(InternalSelectionError::TooManyOutputs));
+    /// Like [`ProvisionalProposal::try_preserving_privacy`], but never let the receiver spend
+    /// more than `max_inputs` of its own UTXOs in this payjoin.
+    ///
+    /// A set of receiver inputs avoids UIH2 only if each of its inputs does on its own, so
+    /// consolidating never buys privacy. The smallest candidate that avoids UIH2 is chosen to
+    /// keep the receiver's footprint small.
+    ///
+    /// When privacy and the limit conflict, the limit wins: once `max_inputs` inputs have been
+    /// contributed, selection fails even if a privacy preserving candidate remains.
+    pub fn try_preserving_privacy_limited(
+        &self,
+        candidate_inputs: HashMap<Amount, OutPoint>,
+        max_inputs: usize,
+    ) -> Result<OutPoint, SelectionError> {
+        if self.contributions.iter().map(Vec::len).sum::<usize>() >= max_inputs {
+            return Err(SelectionError::from(InternalSelectionError::ConsolidationLimit(
+                max_inputs,
+            )));
         }
+        let mut candidates: Vec<(Amount, OutPoint)> = candidate_inputs.into_iter().collect();
+        candidates.sort_unstable_by_key(|(amount, _)| *amount);
+        select_preserving_privacy(&self.selection_context(), candidates).map_err(|e| {
+            if let InternalSelectionError::NotFound = e {
+                log::warn!("No candidate avoids UIH2, and consolidating more inputs won't either");
+            }
+            e.into()
+        })
+    }
 
-        let min_original_out_sats = self
-            .payjoin_psbt
-            .unsigned_tx
-            .output
-            .iter()
-            .map(|output| output.value)
-            .min()
-            .unwrap_or_else(|| Amount::MAX_MONEY.to_sat());
+    /// Let `selector` choose which of `candidates` to contribute.
+    ///
+    /// The selection is not contributed. Pass it to
+    /// [`ProvisionalProposal::contribute_witness_inputs_filtered`], which validates it first.
+    pub fn select_inputs(
+        &self,
+        selector: &impl CoinSelector,
+        candidates: &[(TxOut, OutPoint)],
+    ) -> Result<Vec<(TxOut, OutPoint)>, SelectionError> {
+        selector.select(candidates, &self.selection_context())
+    }
 
-        let min_original_in_sats = self
-            .payjoin_psbt
-            .input_pairs()
-            .filter_map(|input| input.previous_txout().ok().map(|txo| txo.value))
-            .min()
-            .unwrap_or_else(|| Amount::MAX_MONEY.to_sat());
-
-        // Assume many-input, two output to select the vout for now
-        let prior_payment_sats = self.payjoin_psbt.unsigned_tx.output[self.owned_vouts[0]].value;
-        for candidate in candidate_inputs {
-            // TODO bound loop by timeout / iterations
-
-            let candidate_sats = candidate.0.to_sat();
-            let candidate_min_out = min(min_original_out_sats, prior_payment_sats + candidate_sats);
-            let candidate_min_in = min(min_original_in_sats, candidate_sats);
-
-            if candidate_min_out < candidate_min_in {
-                // The candidate avoids UIH2 but conforms to UIH1: Optimal change heuristic.
-                // It implies the smallest output is the sender's change address.
-                return Ok(candidate.1);
+    /// Drop the `candidates` that `is_recent_payjoin_output` reports were created by a recent
+    /// payjoin this receiver took part in, returning the rest in order.
+    ///
+    /// A probing sender could otherwise have the receiver spend its fresh payjoin change here,
+    /// linking the two transactions. This is advisory: nothing is contributed, and the result
+    /// is meant to be passed on to selection.
+    pub fn avoid_recent_payjoin_outputs(
+        &self,
+        candidates: &[(TxOut, OutPoint)],
+        is_recent_payjoin_output: impl Fn(&OutPoint) -> Result<bool, Error>,
+    ) -> Result<Vec<(TxOut, OutPoint)>, Error> {
+        let mut kept = Vec::with_capacity(candidates.len());
+        for (txo, outpoint) in candidates {
+            if is_recent_payjoin_output(outpoint)? {
+                log::debug!("Skipping recent payjoin output {}", outpoint);
             } else {
-                // The candidate conforms to UIH2: Unnecessary input
-                // and could be identified as a potential payjoin
-                continue;
+                kept.push((txo.clone(), *outpoint));
             }
         }
+        Ok(kept)
+    }
+
+    /// The state of the payjoin as a [`CoinSelector`] sees it
+    pub fn selection_context(&self) -> SelectionContext {
+        let sender_inputs: HashSet<OutPoint> =
+            self.original_psbt.unsigned_tx.input.iter().map(|txin| txin.previous_output).collect();
+        let (sender_inputs, contributed_inputs) = self
+            .payjoin_psbt
+            .input_pairs()
+            .filter_map(|input| {
+                let txo = input.previous_txout().ok()?;
+                Some((input.txin.previous_output, txo.clone()))
+            })
+            .partition(|(outpoint, _)| sender_inputs.contains(outpoint));
+        SelectionContext {
+            sender_inputs,
+            contributed_inputs,
+            outputs: self.payjoin_psbt.unsigned_tx.output.clone(),
+            receiver_vouts: self.owned_vouts.clone(),
+        }
+    }
+
+    /// Advisory guess at which outputs the sender keeps as change.
+    ///
+    /// Only outputs not owned by the receiver are considered. An output is flagged if
+    /// - it reuses the script of one of the sender's inputs, or
+    /// - it has the same script type as every sender input and its amount is not a round
+    ///   multiple of 10,000 sats, since payment amounts are usually round and change is not.
+    ///
+    /// Batched payments, fiat-denominated amounts and wallets that randomize change script types
+    /// all defeat these heuristics, so the result should guide privacy decisions rather than be
+    /// relied upon.
+    pub fn likely_sender_change(&self) -> Vec<usize> {
+        likely_change_vouts(&self.original_psbt, &self.owned_vouts)
+    }
 
-        // No suitable privacy preserving selection found
-        Err(SelectionError::from(InternalSelectionError::NotFound))
+    /// Advisory heuristic for whether a receiver output is the single largest output of the
+    /// payjoin as it stands.
+    ///
+    /// An observer who assumes the largest output is the payment learns the amount the
+    /// receiver got, including any value its inputs added. Splitting or rearranging outputs may
+    /// avoid that, but this is one heuristic among several and neither result guarantees
+    /// better privacy. Ties for the largest output count as not largest.
+    pub fn receiver_output_is_largest(&self) -> bool {
+        let outputs = &self.payjoin_psbt.unsigned_tx.output;
+        let max = match outputs.iter().map(|txo| txo.value).max() {
+            Some(max) => max,
+            None => return false,
+        };
+        let mut largest = outputs.iter().enumerate().filter(|(_, txo)| txo.value == max);
+        match (largest.next(), largest.next()) {
+            (Some((vout, _)), None) => self.owned_vouts.contains(&vout),
+            _ => false,
+        }
     }
 
+    /// Contribute a segwit input to the payjoin.
+    ///
+    /// The input takes the sequence of the Original PSBT's first input so that the payjoin
+    /// signals replaceability consistently.
     pub fn contribute_witness_input(&mut self, txo: TxOut, outpoint: OutPoint) {
+        let contribution = self.add_witness_input(txo, outpoint);
+        self.contributions.push(vec![contribution]);
+    }
+
+    /// Add a segwit input without recording it as a contributed input set
+    fn add_witness_input(&mut self, txo: TxOut, outpoint: OutPoint) -> Contribution {
         // The payjoin proposal must not introduce mixed input sequence numbers
         let original_sequence = self
             .payjoin_psbt
@@ -436,8 +959,115 @@ impl ProvisionalProposal {
                 ..Default::default()
             },
         );
+        Contribution { outpoint, vout: *vout_to_augment, value: txo_value }
+    }
+
+    /// Check a candidate input set before contributing any of it, without changing the proposal.
+    ///
+    /// The error lists every input that is a duplicate among the candidates, is already spent
+    /// by the proposal, is worth less than the dust threshold of its script, or is worth less
+    /// than [`ProvisionalProposal::min_input_for_fee_target`] at the sender's `minfeerate`.
+    pub fn validate_inputs(
+        &self,
+        inputs: &[(TxOut, OutPoint)],
+    ) -> Result<(), InputContributionError> {
+        // The input type checks that produced this proposal ensure the weight can be estimated
+        let min_value = self.min_input_for_fee_target(self.params.min_feerate).ok();
+        let mut seen = HashSet::new();
+        let mut invalid = Vec::new();
+        for (txo, outpoint) in inputs {
+            let dust = txo.script_pubkey.dust_value();
+            if !seen.insert(*outpoint) {
+                invalid.push((*outpoint, InvalidInput::Duplicate));
+            } else if self
+                .payjoin_psbt
+                .unsigned_tx
+                .input
+                .iter()
+                .any(|txin| txin.previous_output == *outpoint)
+            {
+                invalid.push((*outpoint, InvalidInput::AlreadyInProposal));
+            } else if txo.value < dust.to_sat() {
+                invalid.push((*outpoint, InvalidInput::Dust(Amount::from_sat(txo.value))));
+            } else if let Some(min_value) = min_value.filter(|min| txo.value < min.to_sat()) {
+                invalid.push((
+                    *outpoint,
+                    InvalidInput::BelowFeeTarget(Amount::from_sat(txo.value), min_value),
+                ));
+            }
+        }
+        if invalid.is_empty() {
+            Ok(())
+        } else {
+            Err(InternalInputContributionError::InvalidInputs(invalid).into())
+        }
+    }
+
+    /// Contribute the segwit inputs that `policy` accepts, returning how many were contributed.
+    ///
+    /// Every input is checked before any is contributed, so with [`VetoHandling::Abort`] a
+    /// vetoed input, and with either handling an input [`ProvisionalProposal::validate_inputs`]
+    /// rejects, leaves the proposal unchanged.
+    pub fn contribute_witness_inputs_filtered(
+        &mut self,
+        inputs: impl IntoIterator<Item = (TxOut, OutPoint)>,
+        policy: impl Fn(&OutPoint, &TxOut) -> Result<bool, Error>,
+        on_veto: VetoHandling,
+    ) -> Result<usize, Error> {
+        let mut accepted = Vec::new();
+        for (txo, outpoint) in inputs {
+            if policy(&outpoint, &txo)? {
+                accepted.push((txo, outpoint));
+            } else if on_veto == VetoHandling::Abort {
+                return Err(Error::Server(Box::new(InputContributionError::from(
+                    InternalInputContributionError::Vetoed(outpoint),
+                ))));
+            } else {
+                log::debug!("Skipping vetoed input {}", outpoint);
+            }
+        }
+        self.validate_inputs(&accepted).map_err(|e| Error::Server(Box::new(e)))?;
+        let contributed = accepted.len();
+        let contributions: Vec<Contribution> = accepted
+            .into_iter()
+            .map(|(txo, outpoint)| self.add_witness_input(txo, outpoint))
+            .collect();
+        if !contributions.is_empty() {
+            self.contributions.push(contributions);
+        }
+        Ok(contributed)
+    }
+
+    /// Contribute segwit inputs only if `is_confirmed` reports every one of them confirmed,
+    /// returning how many were contributed.
+    ///
+    /// Unconfirmed inputs could be replaced or double spent before the payjoin confirms. If any
+    /// are unconfirmed, the error lists all of them and the proposal is left unchanged. The
+    /// inputs are otherwise checked as by [`ProvisionalProposal::validate_inputs`].
+    pub fn contribute_confirmed_witness_inputs(
+        &mut self,
+        inputs: impl IntoIterator<Item = (TxOut, OutPoint)>,
+        is_confirmed: impl Fn(&OutPoint) -> Result<bool, Error>,
+    ) -> Result<usize, Error> {
+        let inputs: Vec<(TxOut, OutPoint)> = inputs.into_iter().collect();
+        let mut unconfirmed = Vec::new();
+        for (_, outpoint) in &inputs {
+            if !is_confirmed(outpoint)? {
+                unconfirmed.push((*outpoint, InvalidInput::Unconfirmed));
+            }
+        }
+        if !unconfirmed.is_empty() {
+            return Err(Error::Server(Box::new(InputContributionError::from(
+                InternalInputContributionError::InvalidInputs(unconfirmed),
+            ))));
+        }
+        self.contribute_witness_inputs_filtered(inputs, |_, _| Ok(true), VetoHandling::Abort)
     }
 
+    /// Contribute a non-segwit input to the payjoin.
+    ///
+    /// The input takes the sequence of the Original PSBT's first input so that the payjoin
+    /// signals replaceability consistently.
     pub fn contribute_non_witness_input(&mut self, tx: bitcoin::Transaction, outpoint: OutPoint) {
         // The payjoin proposal must not introduce mixed input sequence numbers
         let original_sequence = self
@@ -453,6 +1083,11 @@ impl ProvisionalProposal {
         let vout_to_augment =
             self.owned_vouts.choose(&mut rand::thread_rng()).expect("owned_vouts is empty");
         self.payjoin_psbt.unsigned_tx.output[*vout_to_augment].value += txo_value;
+        self.contributions.push(vec![Contribution {
+            outpoint,
+            vout: *vout_to_augment,
+            value: txo_value,
+        }]);
 
         // Insert contribution at random index for privacy
         let mut rng = rand::thread_rng();
@@ -473,44 +1108,301 @@ impl ProvisionalProposal {
         );
     }
 
+    /// The inputs the receiver has contributed so far, with their PSBT data.
+    ///
+    /// The previous output, and thus the amount and script, is in the PSBT input's
+    /// `witness_utxo` or `non_witness_utxo`.
+    pub fn contributed_inputs(
+        &self,
+    ) -> impl '_ + Iterator<Item = (&bitcoin::TxIn, &bitcoin::psbt::Input)> {
+        contributed_input_pairs(&self.original_psbt, &self.payjoin_psbt)
+            .map(|input| (input.txin, input.psbtin))
+    }
+
+    /// Remove the most recently contributed input set and the value it added to the receiver
+    /// outputs, returning the proposal as it was before that contribution.
+    ///
+    /// Each `contribute_*` call contributes one set, so undoing
+    /// [`ProvisionalProposal::contribute_witness_inputs_filtered`] removes every input it
+    /// contributed. Call this before applying fees, so the receiver outputs still hold the
+    /// contributed value. Errors if there is no contribution to undo, or if an input of the set
+    /// or the value it added is gone.
+    pub fn undo_last_contribution(mut self) -> Result<Self, InputContributionError> {
+        let contributions =
+            self.contributions.pop().ok_or(InternalInputContributionError::NoContribution)?;
+        for contribution in contributions.iter().rev() {
+            let changed =
+                || InternalInputContributionError::ContributionChanged(contribution.outpoint);
+            let index = self
+                .payjoin_psbt
+                .unsigned_tx
+                .input
+                .iter()
+                .position(|txin| txin.previous_output == contribution.outpoint)
+                .ok_or_else(changed)?;
+            let output = self
+                .payjoin_psbt
+                .unsigned_tx
+                .output
+                .get_mut(contribution.vout)
+                .ok_or_else(changed)?;
+            output.value = output.value.checked_sub(contribution.value).ok_or_else(changed)?;
+            self.payjoin_psbt.unsigned_tx.input.remove(index);
+            self.payjoin_psbt.inputs.remove(index);
+        }
+        Ok(self)
+    }
+
     pub fn is_output_substitution_disabled(&self) -> bool {
         self.params.disable_output_substitution
     }
 
-    /// Just replace an output address with
-    pub fn substitute_output_address(&mut self, substitute_address: bitcoin::Address) {
-        self.payjoin_psbt.unsigned_tx.output[self.owned_vouts[0]].script_pubkey =
-            substitute_address.script_pubkey();
+    /// The number of outputs the payjoin has with the substitutions and added outputs made so far
+    pub fn projected_output_count(&self) -> usize { self.payjoin_psbt.unsigned_tx.output.len() }
+
+    /// The most the sender allows to be deducted from its outputs to pay for the receiver's
+    /// inputs, as declared by `maxadditionalfeecontribution`
+    pub fn additional_fee_contribution_limit(&self) -> Option<Amount> {
+        self.params.additional_fee_contribution.map(|(max, _)| max)
     }
 
-    /// Apply additional fee contribution now that the receiver has contributed input
-    /// this is kind of a "build_proposal" step before we sign and finalize and extract
+    /// The smallest input value that still nets a positive amount after paying for its own
+    /// weight at `target`
     ///
-    /// WARNING: DO NOT ALTER INPUTS OR OUTPUTS AFTER THIS STEP
-    fn apply_fee(&mut self, min_feerate: Option<FeeRate>) -> Result<&Psbt, RequestError> {
+    /// The estimate assumes the contributed input spends the same script type as the
+    /// sender's first input, which is also what the fee contribution is computed against.
+    /// Inputs of a heavier type spent at the same fee rate need more than this amount.
+    pub fn min_input_for_fee_target(&self, target: FeeRate) -> Result<Amount, Error> {
+        let input_weight = self.receiver_input_weight()?;
+        Ok(input_weight * target + Amount::from_sat(1))
+    }
+
+    /// The fee attributable to each input the receiver has contributed so far, in transaction
+    /// order, if the proposal were finalized with `min_feerate_sat_per_vb`.
+    ///
+    /// Each input is charged its expected weight, estimated as for
+    /// [`ProvisionalProposal::min_input_for_fee_target`], at the fee rate finalizing applies.
+    /// When the sender declared `maxadditionalfeecontribution`, finalizing deducts the sum of
+    /// these fees from the sender's output. Otherwise the receiver's inputs are paid for by a
+    /// lower fee rate.
+    pub fn per_input_fee_breakdown(
+        &self,
+        min_feerate_sat_per_vb: Option<FeeRate>,
+    ) -> Result<Vec<(OutPoint, Amount)>, Error> {
+        let outpoints: Vec<OutPoint> =
+            self.contributed_inputs().map(|(txin, _)| txin.previous_output).collect();
+        if outpoints.is_empty() {
+            return Ok(Vec::new());
+        }
+        let input_fee = self.receiver_input_weight()? * self.fee_rate(min_feerate_sat_per_vb);
+        Ok(outpoints.into_iter().map(|outpoint| (outpoint, input_fee)).collect())
+    }
+
+    /// The fee rate fees are applied at: `min_feerate`, but no less than the sender's `minfeerate`
+    fn fee_rate(&self, min_feerate: Option<FeeRate>) -> FeeRate {
         let min_feerate = min_feerate.unwrap_or(FeeRate::MIN);
         log::trace!("min_feerate: {:?}", min_feerate);
         log::trace!("params.min_feerate: {:?}", self.params.min_feerate);
-        let min_feerate = max(min_feerate, self.params.min_feerate);
-        log::debug!("min_feerate: {:?}", min_feerate);
+        max(min_feerate, self.params.min_feerate)
+    }
 
+    /// The expected weight of a receiver input, assumed to match the sender's first input type
+    fn receiver_input_weight(&self) -> Result<bitcoin::Weight, Error> {
         // this error should never happen. We check for at least one input in the constructor
+        // The Original PSBT keeps the sender's signatures, which finalize_proposal clears from
+        // the proposal before fees are applied
         let input_pair = self
-            .payjoin_psbt
+            .original_psbt
             .input_pairs()
             .next()
             .ok_or(InternalRequestError::OriginalPsbtNotBroadcastable)?;
         let txo = input_pair.previous_txout().map_err(InternalRequestError::PrevTxOut)?;
-        let input_type = InputType::from_spent_input(txo, &self.payjoin_psbt.inputs[0])
+        let input_type = InputType::from_spent_input(txo, &self.original_psbt.inputs[0])
             .map_err(InternalRequestError::InputType)?;
-        let contribution_weight = input_type.expected_input_weight();
-        log::trace!("contribution_weight: {}", contribution_weight);
-        let mut additional_fee = contribution_weight * min_feerate;
+        Ok(input_type.expected_input_weight())
+    }
+
+    /// Just replace an output address with
+    ///
+    /// The substitute may be a different address type than the original. Any weight the new
+    /// script adds is paid by the receiver output once fees are applied.
+    /// Errors if the receiver output would be dust for the substitute script, or if the
+    /// substitute script is already paid by a sender output.
+    pub fn substitute_output_address(
+        &mut self,
+        substitute_address: bitcoin::Address,
+    ) -> Result<(), OutputSubstitutionError> {
+        if self.params.disable_output_substitution {
+            return Err(InternalOutputSubstitutionError::Disabled.into());
+        }
+        let script_pubkey = substitute_address.script_pubkey();
+        let duplicate =
+            self.payjoin_psbt.unsigned_tx.output.iter().enumerate().find(|(vout, txo)| {
+                !self.owned_vouts.contains(vout) && txo.script_pubkey == script_pubkey
+            });
+        if let Some((vout, _)) = duplicate {
+            return Err(InternalOutputSubstitutionError::DuplicateScript(vout).into());
+        }
+        let output = &mut self.payjoin_psbt.unsigned_tx.output[self.owned_vouts[0]];
+        let dust = script_pubkey.dust_value();
+        if output.value < dust.to_sat() {
+            return Err(InternalOutputSubstitutionError::OutputBelowDust(
+                Amount::from_sat(output.value),
+                dust,
+            )
+            .into());
+        }
+        output.script_pubkey = script_pubkey;
+        Ok(())
+    }
+
+    /// Make sure a receiver output paying `script` holds at least `min_value`, so that the
+    /// receiver can bump the payjoin with a child transaction (CPFP) should it get stuck.
+    ///
+    /// An existing receiver output paying `script` is preserved and topped up if needed.
+    /// Otherwise a new output paying `script` is appended after the sender's outputs. In both
+    /// cases the value is moved from the largest other receiver output, which must stay above
+    /// dust, so this fails if the sender disabled output substitution. The weight of an added
+    /// output is paid by the receiver when fees are applied, following the fee subtraction
+    /// strategy.
+    pub fn ensure_cpfp_anchor(
+        mut self,
+        min_value: Amount,
+        script: &Script,
+    ) -> Result<Self, OutputSubstitutionError> {
+        let outputs = &self.payjoin_psbt.unsigned_tx.output;
+        let duplicate = outputs.iter().enumerate().find(|(vout, txo)| {
+            !self.owned_vouts.contains(vout) && txo.script_pubkey.as_script() == script
+        });
+        if let Some((vout, _)) = duplicate {
+            return Err(InternalOutputSubstitutionError::DuplicateScript(vout).into());
+        }
+        let anchor = self
+            .owned_vouts
+            .iter()
+            .copied()
+            .find(|vout| outputs[*vout].script_pubkey.as_script() == script);
+        let anchor_value = max(min_value, script.dust_value());
+        let needed = match anchor {
+            Some(vout) => anchor_value.to_sat().saturating_sub(outputs[vout].value),
+            None => anchor_value.to_sat(),
+        };
+        if needed == 0 {
+            return Ok(self);
+        }
+        if self.params.disable_output_substitution {
+            return Err(InternalOutputSubstitutionError::Disabled.into());
+        }
+
+        let donor = self
+            .owned_vouts
+            .iter()
+            .copied()
+            .filter(|vout| Some(*vout) != anchor)
+            .max_by_key(|vout| outputs[*vout].value);
+        let available = donor.map_or(0, |vout| {
+            outputs[vout].value.saturating_sub(outputs[vout].script_pubkey.dust_value().to_sat())
+        });
+        let donor = match donor {
+            Some(vout) if available >= needed => vout,
+            _ =>
+                return Err(InternalOutputSubstitutionError::InsufficientValue(
+                    Amount::from_sat(needed),
+                    Amount::from_sat(available),
+                )
+                .into()),
+        };
+
+        self.payjoin_psbt.unsigned_tx.output[donor].value -= needed;
+        match anchor {
+            Some(vout) => self.payjoin_psbt.unsigned_tx.output[vout].value += needed,
+            None => {
+                self.payjoin_psbt
+                    .unsigned_tx
+                    .output
+                    .push(TxOut { value: needed, script_pubkey: script.to_owned() });
+                self.payjoin_psbt.outputs.push(Default::default());
+                self.owned_vouts.push(self.payjoin_psbt.unsigned_tx.output.len() - 1);
+            }
+        }
+        Ok(self)
+    }
+
+    /// Choose which receiver outputs pay the fees the receiver owes, such as the cost of a
+    /// larger substitute output script. Defaults to [`FeeSubtractionStrategy::FirstOutput`].
+    ///
+    /// Errors if a designated output does not belong to the receiver.
+    pub fn set_fee_subtraction_strategy(
+        &mut self,
+        strategy: FeeSubtractionStrategy,
+    ) -> Result<(), OutputSubstitutionError> {
+        if let FeeSubtractionStrategy::Designated(vout) = strategy {
+            if !self.owned_vouts.contains(&vout) {
+                return Err(InternalOutputSubstitutionError::NotReceiverOutput(vout).into());
+            }
+        }
+        self.fee_subtraction = strategy;
+        Ok(())
+    }
+
+    /// Set the fee rate the finalized proposal must pay to be relayed.
+    /// Defaults to [`FeeRate::BROADCAST_MIN`], 1 sat/vB.
+    pub fn set_min_relay_fee_rate(&mut self, min_relay_fee_rate: FeeRate) {
+        self.min_relay_fee_rate = min_relay_fee_rate;
+    }
+
+    /// The receiver output scripts that one of its contributed inputs also spends from.
+    ///
+    /// Paying an address the receiver is spending from links its inputs and outputs, and
+    /// usually means the wallet handed out an address it already used.
+    pub fn detect_receiver_address_reuse(&self) -> Vec<ScriptBuf> {
+        let input_scripts: HashSet<ScriptBuf> =
+            contributed_input_pairs(&self.original_psbt, &self.payjoin_psbt)
+                .filter_map(|input| {
+                    input.previous_txout().ok().map(|txo| txo.script_pubkey.clone())
+                })
+                .collect();
+        let mut reused: Vec<ScriptBuf> = Vec::new();
+        for vout in &self.owned_vouts {
+            let script = &self.payjoin_psbt.unsigned_tx.output[*vout].script_pubkey;
+            if input_scripts.contains(script) && !reused.contains(script) {
+                reused.push(script.clone());
+            }
+        }
+        reused
+    }
+
+    /// Make finalizing fail if [`ProvisionalProposal::detect_receiver_address_reuse`] finds
+    /// any reuse. By default the reuse is only logged as a warning.
+    pub fn reject_receiver_address_reuse(&mut self, reject: bool) {
+        self.reject_address_reuse = reject;
+    }
+
+    /// Apply additional fee contribution now that the receiver has contributed input
+    /// this is kind of a "build_proposal" step before we sign and finalize and extract
+    ///
+    /// WARNING: DO NOT ALTER INPUTS OR OUTPUTS AFTER THIS STEP
+    fn apply_fee(&mut self, min_feerate: Option<FeeRate>) -> Result<&Psbt, Error> {
+        let breakdown = self.per_input_fee_breakdown(min_feerate)?;
+        let min_feerate = self.fee_rate(min_feerate);
+        log::debug!("min_feerate: {:?}", min_feerate);
+
+        // The sender pays for the weight of every input the receiver contributed
+        let mut additional_fee: Amount = breakdown.iter().map(|(_, fee)| *fee).sum();
         let max_additional_fee_contribution =
             self.params.additional_fee_contribution.unwrap_or_default().0;
-        if additional_fee >= max_additional_fee_contribution {
-            // Cap fee at the sender's contribution to simplify this method
-            additional_fee = max_additional_fee_contribution;
+        if self.params.additional_fee_contribution.is_some()
+            && additional_fee > max_additional_fee_contribution
+        {
+            return Err(InternalRequestError::FeeContributionExceedsMaximum(
+                additional_fee,
+                max_additional_fee_contribution,
+            )
+            .into());
+        }
+        if self.params.additional_fee_contribution.is_none() {
+            // Without `maxadditionalfeecontribution` the sender pays nothing for receiver inputs
+            additional_fee = Amount::ZERO;
         }
         log::trace!("additional_fee: {}", additional_fee);
         if additional_fee > bitcoin::Amount::ZERO {
@@ -527,20 +1419,89 @@ impl ProvisionalProposal {
                 }
             }
         }
+
+        // The receiver pays for any weight its outputs add, whether from a larger substitute
+        // script or from an added output
+        let original_outputs = &self.original_psbt.unsigned_tx.output;
+        let payjoin_outputs = &self.payjoin_psbt.unsigned_tx.output;
+        let (original_size, substitute_size) =
+            self.owned_vouts.iter().fold((0, 0), |(original, substitute), vout| {
+                let script_size = payjoin_outputs[*vout].script_pubkey.encoded_size();
+                match original_outputs.get(*vout) {
+                    Some(txo) =>
+                        (original + txo.script_pubkey.encoded_size(), substitute + script_size),
+                    // 8 bytes encode the amount of an added output
+                    None => (original, substitute + 8 + script_size),
+                }
+            });
+        if substitute_size > original_size {
+            let receiver_fee =
+                Weight::from_non_witness_data_size(substitute_size - original_size) * min_feerate;
+            log::trace!("receiver substitution fee: {}", receiver_fee);
+            self.subtract_receiver_fee(receiver_fee)?;
+        }
         Ok(&self.payjoin_psbt)
     }
 
-    /// Return a Payjoin Proposal PSBT that the sender will find acceptable.
-    ///
-    /// This attempts to calculate any network fee owed by the receiver, subtract it from their output,
+    /// Deduct a fee owed by the receiver from its outputs according to the fee subtraction strategy
+    fn subtract_receiver_fee(&mut self, fee: Amount) -> Result<(), Error> {
+        let outputs = &self.payjoin_psbt.unsigned_tx.output;
+        let shares: Vec<(usize, u64)> = match self.fee_subtraction {
+            FeeSubtractionStrategy::FirstOutput => vec![(self.owned_vouts[0], fee.to_sat())],
+            FeeSubtractionStrategy::Designated(vout) => vec![(vout, fee.to_sat())],
+            FeeSubtractionStrategy::Largest => {
+                let largest = self
+                    .owned_vouts
+                    .iter()
+                    .max_by_key(|vout| outputs[**vout].value)
+                    .expect("owned_vouts is empty");
+                vec![(*largest, fee.to_sat())]
+            }
+            FeeSubtractionStrategy::Proportional => {
+                let total: u64 = self.owned_vouts.iter().map(|vout| outputs[*vout].value).sum();
+                let mut shares: Vec<(usize, u64)> = self
+                    .owned_vouts
+                    .iter()
+                    .map(|vout| {
+                        let share = fee.to_sat() as u128 * outputs[*vout].value as u128
+                            / total.max(1) as u128;
+                        (*vout, share as u64)
+                    })
+                    .collect();
+                // Any rounding remainder is paid by the first receiver output
+                let remainder = fee.to_sat() - shares.iter().map(|(_, share)| share).sum::<u64>();
+                shares[0].1 += remainder;
+                shares
+            }
+        };
+        for (vout, share) in shares {
+            let output = &mut self.payjoin_psbt.unsigned_tx.output[vout];
+            let dust = output.script_pubkey.dust_value();
+            match output.value.checked_sub(share) {
+                Some(remaining) if remaining >= dust.to_sat() => output.value = remaining,
+                remaining => {
+                    let value = Amount::from_sat(remaining.unwrap_or_default());
+                    return Err(Error::Server(Box::new(OutputSubstitutionError::from(
+                        InternalOutputSubstitutionError::OutputBelowDust(value, dust),
+                    ))));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Return a Payjoin Proposal PSBT that the sender will find acceptable.
+    ///
+    /// This attempts to calculate any network fee owed by the receiver, subtract it from their output,
     /// and return a PSBT that can produce a consensus-valid transaction that the sender will accept.
     ///
     /// wallet_process_psbt should sign and finalize receiver inputs
     fn prepare_psbt(mut self, processed_psbt: Psbt) -> Result<PayjoinProposal, RequestError> {
         self.payjoin_psbt = processed_psbt;
         log::trace!("Preparing PSBT {:#?}", self.payjoin_psbt);
+        // Keep the receiver's wallet metadata out of the proposal
+        strip_leaky_metadata(&mut self.payjoin_psbt);
         for input in self.payjoin_psbt.inputs_mut() {
-            input.bip32_derivation = BTreeMap::new();
             input.partial_sigs = BTreeMap::new();
         }
         for i in self.sender_input_indexes() {
@@ -553,12 +1514,62 @@ impl ProvisionalProposal {
         }
 
         Ok(PayjoinProposal {
+            original_psbt: self.original_psbt,
             payjoin_psbt: self.payjoin_psbt,
             owned_vouts: self.owned_vouts,
             params: self.params,
         })
     }
 
+    /// Check that the wallet-processed proposal pays at least the minimum relay fee rate.
+    ///
+    /// The weight is estimated with the sender's signatures from the Original PSBT. Receiver
+    /// inputs the wallet did not finalize are counted without their signatures.
+    fn check_min_relay_fee(&self, processed_psbt: &Psbt) -> Result<(), RequestError> {
+        let fee_rate = estimated_fee_rate(&self.original_psbt, processed_psbt)?;
+        log::trace!("proposal fee_rate: {:?}", fee_rate);
+        if fee_rate < self.min_relay_fee_rate {
+            return Err(InternalRequestError::ProposalBelowMinRelayFee(
+                fee_rate,
+                self.min_relay_fee_rate,
+            )
+            .into());
+        }
+        Ok(())
+    }
+
+    /// The sender rejects a proposal below its `minfeerate`, e.g. when the receiver's inputs
+    /// cost more than the sender's additional fee contribution covers.
+    fn check_sender_min_fee_rate(&self, processed_psbt: &Psbt) -> Result<(), RequestError> {
+        let fee_rate = estimated_fee_rate(&self.original_psbt, processed_psbt)?;
+        if fee_rate < self.params.min_feerate {
+            return Err(InternalRequestError::ProposalBelowSenderMinFeeRate(
+                fee_rate,
+                self.params.min_feerate,
+            )
+            .into());
+        }
+        Ok(())
+    }
+
+    fn check_max_effective_fee_rate(
+        &self,
+        processed_psbt: &Psbt,
+        max_effective_fee_rate: Option<FeeRate>,
+    ) -> Result<(), RequestError> {
+        let max_fee_rate = match max_effective_fee_rate {
+            Some(max_fee_rate) => max_fee_rate,
+            None => return Ok(()),
+        };
+        let fee_rate = estimated_fee_rate(&self.original_psbt, processed_psbt)?;
+        if fee_rate > max_fee_rate {
+            return Err(
+                InternalRequestError::ProposalAboveMaxFeeRate(fee_rate, max_fee_rate).into()
+            );
+        }
+        Ok(())
+    }
+
     fn sender_input_indexes(&self) -> Vec<usize> {
         // iterate proposal as mutable WITH the outpoint (previous_output) available too
         let mut original_inputs = self.original_psbt.input_pairs().peekable();
@@ -584,22 +1595,205 @@ impl ProvisionalProposal {
         wallet_process_psbt: impl Fn(&Psbt) -> Result<Psbt, Error>,
         min_feerate_sat_per_vb: Option<FeeRate>,
     ) -> Result<PayjoinProposal, Error> {
+        self.prepare_for_signing(min_feerate_sat_per_vb)?;
+        self.sign_and_prepare(wallet_process_psbt, None)
+    }
+
+    /// Like [`ProvisionalProposal::finalize_proposal`], but let `approve` inspect the fully
+    /// computed proposal before `wallet_process_psbt` signs it.
+    ///
+    /// If `approve` returns false, nothing is signed and a [`Error::BadRequest`] is returned
+    /// whose reply declines the payjoin with `original-psbt-rejected`. The same rejection is
+    /// returned when the signed proposal pays more than `max_effective_fee_rate`.
+    pub fn finalize_proposal_with_approval(
+        mut self,
+        approve: impl FnOnce(&ProposalSummary) -> Result<bool, Error>,
+        wallet_process_psbt: impl Fn(&Psbt) -> Result<Psbt, Error>,
+        min_feerate_sat_per_vb: Option<FeeRate>,
+        max_effective_fee_rate: Option<FeeRate>,
+    ) -> Result<PayjoinProposal, Error> {
+        self.prepare_for_signing(min_feerate_sat_per_vb)?;
+        if !approve(&self.summary()?)? {
+            return Err(InternalRequestError::ProposalDeclined.into());
+        }
+        self.sign_and_prepare(wallet_process_psbt, max_effective_fee_rate)
+    }
+
+    fn prepare_for_signing(&mut self, min_feerate: Option<FeeRate>) -> Result<(), Error> {
+        let reused = self.detect_receiver_address_reuse();
+        if !reused.is_empty() {
+            if self.reject_address_reuse {
+                return Err(Error::Server(Box::new(InputContributionError::from(
+                    InternalInputContributionError::AddressReuse(reused),
+                ))));
+            }
+            log::warn!("Receiver outputs reuse the scripts of its inputs: {:?}", reused);
+        }
         for i in self.sender_input_indexes() {
             log::trace!("Clearing sender script signatures for input {}", i);
             self.payjoin_psbt.inputs[i].final_script_sig = None;
             self.payjoin_psbt.inputs[i].final_script_witness = None;
             self.payjoin_psbt.inputs[i].tap_key_sig = None;
         }
-        let psbt = self.apply_fee(min_feerate_sat_per_vb)?;
-        let psbt = wallet_process_psbt(psbt)?;
+        self.apply_fee(min_feerate)?;
+        Ok(())
+    }
+
+    fn sign_and_prepare(
+        self,
+        wallet_process_psbt: impl Fn(&Psbt) -> Result<Psbt, Error>,
+        max_effective_fee_rate: Option<FeeRate>,
+    ) -> Result<PayjoinProposal, Error> {
+        let psbt = wallet_process_psbt(&self.payjoin_psbt)?;
+        self.check_min_relay_fee(&psbt)?;
+        self.check_sender_min_fee_rate(&psbt)?;
+        self.check_max_effective_fee_rate(&psbt, max_effective_fee_rate)?;
         let payjoin_proposal = self.prepare_psbt(psbt)?;
         Ok(payjoin_proposal)
     }
+
+    fn summary(&self) -> Result<ProposalSummary, Error> {
+        let value = |input: crate::psbt::InputPair| {
+            input.previous_txout().map(|txo| txo.value).map_err(InternalRequestError::PrevTxOut)
+        };
+        let contributed_inputs = contributed_input_pairs(&self.original_psbt, &self.payjoin_psbt)
+            .map(|input| Ok((input.txin.previous_output, Amount::from_sat(value(input)?))))
+            .collect::<Result<Vec<_>, InternalRequestError>>()?;
+        let overflow = || InternalRequestError::Psbt(bitcoin::psbt::Error::FeeOverflow);
+        let contributed_value = contributed_inputs
+            .iter()
+            .try_fold(Amount::ZERO, |total, (_, value)| total.checked_add(*value))
+            .ok_or_else(overflow)?;
+        let outputs = self.payjoin_psbt.unsigned_tx.output.clone();
+        let receiver_output_value = self
+            .owned_vouts
+            .iter()
+            .try_fold(Amount::ZERO, |total, vout| {
+                total.checked_add(Amount::from_sat(outputs[*vout].value))
+            })
+            .ok_or_else(overflow)?;
+        let receiver_net = receiver_output_value
+            .to_signed()
+            .ok()
+            .zip(contributed_value.to_signed().ok())
+            .and_then(|(received, contributed)| received.checked_sub(contributed))
+            .ok_or_else(overflow)?;
+        Ok(ProposalSummary {
+            fee: self.payjoin_psbt.fee().map_err(InternalRequestError::Psbt)?,
+            original_fee: self.original_psbt.fee().map_err(InternalRequestError::Psbt)?,
+            contributed_inputs,
+            receiver_vouts: self.owned_vouts.clone(),
+            receiver_net,
+            outputs,
+        })
+    }
+
+    /// Finalize a copy of the proposal and return the Payjoin Proposal PSBT it would produce.
+    ///
+    /// The proposal is left untouched, so it can be presented for approval and finalized only
+    /// once accepted. The copy goes through the same fee checks as
+    /// [`ProvisionalProposal::finalize_proposal_with_approval`], including the
+    /// `max_effective_fee_rate` bound, so a preview succeeds only if finalizing would.
+    /// `wallet_process_psbt` is called as it would be when finalizing.
+    pub fn preview_finalized_psbt(
+        &self,
+        wallet_process_psbt: impl Fn(&Psbt) -> Result<Psbt, Error>,
+        min_feerate_sat_per_vb: Option<FeeRate>,
+        max_effective_fee_rate: Option<FeeRate>,
+    ) -> Result<Psbt, Error> {
+        let mut proposal = self.clone();
+        proposal.prepare_for_signing(min_feerate_sat_per_vb)?;
+        let payjoin_proposal =
+            proposal.sign_and_prepare(wallet_process_psbt, max_effective_fee_rate)?;
+        Ok(payjoin_proposal.payjoin_psbt)
+    }
+}
+
+/// Whether `psbt` has global xpubs, BIP32 derivations or taproot key origins
+fn has_leaky_metadata(psbt: &Psbt) -> bool {
+    !psbt.xpub.is_empty()
+        || psbt
+            .inputs
+            .iter()
+            .any(|input| !input.bip32_derivation.is_empty() || !input.tap_key_origins.is_empty())
+        || psbt
+            .outputs
+            .iter()
+            .any(|output| !output.bip32_derivation.is_empty() || !output.tap_key_origins.is_empty())
+}
+
+fn strip_leaky_metadata(psbt: &mut Psbt) {
+    psbt.xpub_mut().clear();
+    for input in psbt.inputs_mut() {
+        input.bip32_derivation.clear();
+        input.tap_key_origins.clear();
+    }
+    for output in psbt.outputs_mut() {
+        output.bip32_derivation.clear();
+        output.tap_key_origins.clear();
+    }
+}
+
+/// The fee rate of the Payjoin Proposal once the sender signs its inputs again.
+///
+/// Sender inputs take their previous outputs and signatures from the Original PSBT, so their
+/// weight is what it was there. Receiver inputs count with whatever the wallet finalized.
+fn estimated_fee_rate(
+    original_psbt: &Psbt,
+    payjoin_psbt: &Psbt,
+) -> Result<FeeRate, InternalRequestError> {
+    let mut psbt = payjoin_psbt.clone();
+    for i in 0..psbt.inputs.len() {
+        let outpoint = psbt.unsigned_tx.input[i].previous_output;
+        if let Some(original) =
+            original_psbt.input_pairs().find(|original| original.txin.previous_output == outpoint)
+        {
+            let original = original.psbtin.clone();
+            let input = &mut psbt.inputs[i];
+            input.witness_utxo = original.witness_utxo;
+            input.non_witness_utxo = original.non_witness_utxo;
+            input.final_script_sig = original.final_script_sig;
+            input.final_script_witness = original.final_script_witness;
+        }
+    }
+    let fee = psbt.fee().map_err(InternalRequestError::Psbt)?;
+    Ok(fee / psbt.extract_tx().weight())
+}
+
+/// The outputs of `psbt` outside `excluded_vouts` that look like the sender's change.
+/// See [`ProvisionalProposal::likely_sender_change`] for the heuristics.
+fn likely_change_vouts(psbt: &Psbt, excluded_vouts: &[usize]) -> Vec<usize> {
+    fn same_script_type(a: &Script, b: &Script) -> bool {
+        a.is_p2pkh() == b.is_p2pkh()
+            && a.is_p2sh() == b.is_p2sh()
+            && a.witness_version() == b.witness_version()
+            && a.len() == b.len()
+    }
+
+    let input_scripts: Vec<ScriptBuf> = psbt
+        .input_pairs()
+        .filter_map(|input| input.previous_txout().ok().map(|txo| txo.script_pubkey.clone()))
+        .collect();
+    psbt.unsigned_tx
+        .output
+        .iter()
+        .enumerate()
+        .filter(|(vout, _)| !excluded_vouts.contains(vout))
+        .filter(|(_, txo)| {
+            let reused = input_scripts.iter().any(|script| *script == txo.script_pubkey);
+            let matches_inputs = !input_scripts.is_empty()
+                && input_scripts.iter().all(|script| same_script_type(script, &txo.script_pubkey));
+            let is_round = txo.value % 10_000 == 0;
+            reused || (matches_inputs && !is_round)
+        })
+        .map(|(vout, _)| vout)
+        .collect()
 }
 
 /// A mutable checked proposal that the receiver may contribute inputs to to make a payjoin.
 #[derive(Clone)]
 pub struct PayjoinProposal {
+    original_psbt: Psbt,
     payjoin_psbt: Psbt,
     params: Params,
     owned_vouts: Vec<usize>,
@@ -617,10 +1811,224 @@ impl PayjoinProposal {
     pub fn owned_vouts(&self) -> &Vec<usize> { &self.owned_vouts }
 
     pub fn psbt(&self) -> &Psbt { &self.payjoin_psbt }
+
+    /// The fee rate the sender will compute for the proposal once it signs its inputs.
+    ///
+    /// The weight counts the sender's signatures from the Original PSBT and the witnesses of
+    /// receiver inputs as finalized by the wallet. Receiver inputs left unsigned are counted
+    /// without their witnesses, overestimating the fee rate.
+    pub fn effective_fee_rate(&self) -> Result<FeeRate, Error> {
+        Ok(estimated_fee_rate(&self.original_psbt, &self.payjoin_psbt)?)
+    }
+
+    /// Check that `tx` is the transaction this proposal committed to before broadcasting it.
+    ///
+    /// The version, lock time, outpoints and sequences spent, and every output must match.
+    /// Signatures are not compared, so a transaction finalized from the proposal passes.
+    pub fn verify_against_committed(&self, tx: &bitcoin::Transaction) -> Result<(), Error> {
+        let committed = &self.payjoin_psbt.unsigned_tx;
+        if tx.version != committed.version {
+            return Err(CommittedTxError::from(InternalCommittedTxError::Version).into());
+        }
+        if tx.lock_time != committed.lock_time {
+            return Err(CommittedTxError::from(InternalCommittedTxError::LockTime).into());
+        }
+        if tx.input.len() != committed.input.len() {
+            return Err(CommittedTxError::from(InternalCommittedTxError::InputCount(
+                tx.input.len(),
+                committed.input.len(),
+            ))
+            .into());
+        }
+        if let Some(index) = tx.input.iter().zip(&committed.input).position(|(txin, expected)| {
+            txin.previous_output != expected.previous_output || txin.sequence != expected.sequence
+        }) {
+            return Err(CommittedTxError::from(InternalCommittedTxError::Input(index)).into());
+        }
+        if tx.output.len() != committed.output.len() {
+            return Err(CommittedTxError::from(InternalCommittedTxError::OutputCount(
+                tx.output.len(),
+                committed.output.len(),
+            ))
+            .into());
+        }
+        if let Some(index) = tx.output.iter().zip(&committed.output).position(|(a, b)| a != b) {
+            return Err(CommittedTxError::from(InternalCommittedTxError::Output(index)).into());
+        }
+        Ok(())
+    }
+
+    /// The Payjoin Proposal PSBT in the binary BIP 174 format, as stored in .psbt files
+    pub fn to_psbt_bytes(&self) -> Vec<u8> { self.payjoin_psbt.serialize() }
+
+    /// The Payjoin Proposal PSBT in the base64 BIP 174 format
+    pub fn to_psbt_base64(&self) -> String { self.payjoin_psbt.to_string() }
+
+    /// The number of inputs the receiver contributed to the Original PSBT
+    pub fn contributed_input_count(&self) -> usize { self.contributed_inputs().count() }
+
+    /// The total value of the inputs the receiver contributed to the Original PSBT
+    ///
+    /// Inputs whose previous output is unknown are not counted.
+    pub fn contributed_input_value(&self) -> Amount {
+        let sats = self
+            .contributed_inputs()
+            .filter_map(|input| input.previous_txout().ok().map(|txo| txo.value))
+            .sum();
+        Amount::from_sat(sats)
+    }
+
+    /// Estimate how well the payjoin resists common chain analysis heuristics.
+    ///
+    /// The report is advisory and meant for display. Never base protocol decisions on it.
+    pub fn privacy_metrics(&self) -> PrivacyReport {
+        let sender_input_count = self.original_psbt.inputs.len();
+        let receiver_input_count = self.contributed_input_count();
+        let input_count = sender_input_count + receiver_input_count;
+        // Ways to split the inputs between at most two owners
+        let input_ownership_interpretations =
+            1u64.checked_shl(input_count.saturating_sub(1) as u32).unwrap_or(u64::MAX);
+
+        let outputs = &self.payjoin_psbt.unsigned_tx.output;
+        // UIH2: an observer can tell an input was unnecessary to fund the outputs, which hints
+        // at a payjoin. Otherwise the optimal change heuristic (UIH1) reads it as a plain spend.
+        let unnecessary_input = (outputs.len() == 2).then(|| {
+            let min_in = self
+                .original_psbt
+                .input_pairs()
+                .chain(self.contributed_inputs())
+                .filter_map(|input| input.previous_txout().ok().map(|txo| txo.value))
+                .min()
+                .unwrap_or_else(|| Amount::MAX_MONEY.to_sat());
+            let min_out = outputs.iter().map(|txo| txo.value).min().expect("two outputs");
+            min_out >= min_in
+        });
+
+        PrivacyReport {
+            sender_input_count,
+            receiver_input_count,
+            input_ownership_interpretations,
+            defeats_common_input_ownership: receiver_input_count > 0,
+            unnecessary_input,
+        }
+    }
+
+    /// Estimate how many sender/receiver interpretations of the payjoin a chain analyst has to
+    /// consider.
+    ///
+    /// The model counts the ways to assign the inputs and outputs to two parties, a payer and a
+    /// payee, such that the payer spends at least one input, the payee receives at least one
+    /// output, and the payee gains a positive amount once the payer has paid the whole fee.
+    /// The payee may spend no inputs, which is the plain payment reading. It assumes exactly
+    /// two parties and ignores script types, round amounts and other fingerprints an analyst
+    /// could use to rule interpretations out, so it is an upper bound on plausible readings.
+    ///
+    /// Inputs without previous output information are left out. Beyond 20 inputs and outputs
+    /// combined the value check is skipped and the count of all assignments is returned,
+    /// saturating at `u64::MAX`.
+    pub fn interpretation_count(&self) -> u64 {
+        const MAX_ENUMERATED: usize = 20;
+
+        let inputs: Vec<u64> = self
+            .original_psbt
+            .input_pairs()
+            .chain(self.contributed_inputs())
+            .filter_map(|input| input.previous_txout().ok().map(|txo| txo.value))
+            .collect();
+        let outputs: Vec<u64> =
+            self.payjoin_psbt.unsigned_tx.output.iter().map(|txo| txo.value).collect();
+        if inputs.len() + outputs.len() > MAX_ENUMERATED {
+            let subsets = |n: usize| 1u64.checked_shl(n as u32).map_or(u64::MAX, |s| s - 1);
+            return subsets(inputs.len()).saturating_mul(subsets(outputs.len()));
+        }
+
+        let subset_sums = |values: &[u64]| -> Vec<u64> {
+            (0..1u32 << values.len())
+                .map(|mask| {
+                    values
+                        .iter()
+                        .enumerate()
+                        .filter(|(i, _)| mask & (1 << i) != 0)
+                        .map(|(_, v)| v)
+                        .sum()
+                })
+                .collect()
+        };
+        let fee = inputs.iter().sum::<u64>().saturating_sub(outputs.iter().sum());
+        let payer_inputs = subset_sums(&inputs);
+        let payer_outputs = subset_sums(&outputs);
+        let all_outputs = payer_outputs.len() - 1;
+        let mut count = 0;
+        // The payer spends the inputs in a non-empty mask and keeps the outputs in its mask,
+        // leaving at least one output to the payee
+        for spent in &payer_inputs[1..] {
+            for kept in &payer_outputs[..all_outputs] {
+                if spent.saturating_sub(*kept) > fee {
+                    count += 1;
+                }
+            }
+        }
+        count
+    }
+
+    fn contributed_inputs(&self) -> impl '_ + Iterator<Item = crate::psbt::InputPair<'_>> {
+        contributed_input_pairs(&self.original_psbt, &self.payjoin_psbt)
+    }
+}
+
+/// The unsigned Payjoin Proposal presented to the approver of
+/// [`ProvisionalProposal::finalize_proposal_with_approval`], with fees already applied.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProposalSummary {
+    /// The fee of the proposal, its input value less its output value
+    pub fee: Amount,
+    /// The fee of the sender's Original PSBT
+    pub original_fee: Amount,
+    /// The inputs the receiver contributed and their values
+    pub contributed_inputs: Vec<(OutPoint, Amount)>,
+    /// The outputs of the proposal, including any substitutions and additions
+    pub outputs: Vec<TxOut>,
+    /// The indexes of the receiver's outputs
+    pub receiver_vouts: Vec<usize>,
+    /// The receiver's outputs less its contributed inputs
+    pub receiver_net: bitcoin::SignedAmount,
+}
+
+/// Advisory indicators of how a payjoin resists common chain analysis heuristics.
+///
+/// A plain send has a single apparent owner for all of its inputs. This is meant for display,
+/// not for protocol decisions.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PrivacyReport {
+    pub sender_input_count: usize,
+    pub receiver_input_count: usize,
+    /// The ways the inputs could be split between at most two owners
+    pub input_ownership_interpretations: u64,
+    /// Whether the common-input-ownership heuristic wrongly attributes all inputs to the sender
+    pub defeats_common_input_ownership: bool,
+    /// Whether an input was unnecessary to fund the outputs (UIH2), which hints at a payjoin.
+    ///
+    /// None unless the payjoin has exactly two outputs.
+    pub unnecessary_input: Option<bool>,
+}
+
+/// The inputs of `payjoin_psbt` that are not spent by `original_psbt`
+fn contributed_input_pairs<'a>(
+    original_psbt: &'a Psbt,
+    payjoin_psbt: &'a Psbt,
+) -> impl 'a + Iterator<Item = crate::psbt::InputPair<'a>> {
+    let original_outpoints: Vec<&OutPoint> =
+        original_psbt.unsigned_tx.input.iter().map(|txin| &txin.previous_output).collect();
+    payjoin_psbt
+        .input_pairs()
+        .filter(move |input| !original_outpoints.contains(&&input.txin.previous_output))
 }
 
 #[cfg(test)]
 mod test {
+    use bitcoin::hashes::Hash;
+    use bitcoin::{Txid, WPubkeyHash};
+
     use super::*;
 
     struct MockHeaders {
@@ -643,6 +2051,14 @@ mod test {
     }
 
     fn proposal_from_test_vector() -> Result<UncheckedProposal, RequestError> {
+        proposal_from_test_vector_with_query(
+            "maxadditionalfeecontribution=182&additionalfeeoutputindex=0",
+        )
+    }
+
+    fn proposal_from_test_vector_with_query(
+        query: &str,
+    ) -> Result<UncheckedProposal, RequestError> {
         // OriginalPSBT Test Vector from BIP
         // | InputScriptType | Orginal PSBT Fee rate | maxadditionalfeecontribution | additionalfeeoutputindex|
         // |-----------------|-----------------------|------------------------------|-------------------------|
@@ -651,13 +2067,60 @@ mod test {
 
         let body = original_psbt.as_bytes();
         let headers = MockHeaders::new(body.len() as u64);
-        UncheckedProposal::from_request(
-            body,
+        UncheckedProposal::from_request(body, query, headers)
+    }
+
+    fn checked_proposal_from_test_vector() -> ProvisionalProposal {
+        checked_proposal_from_test_vector_with_query(
+            "maxadditionalfeecontribution=182&additionalfeeoutputindex=0",
+        )
+    }
+
+    fn checked_proposal_from_test_vector_with_query(query: &str) -> ProvisionalProposal {
+        use std::str::FromStr;
+
+        use bitcoin::{Address, Network};
+
+        proposal_from_test_vector_with_query(query)
+            .unwrap()
+            .assume_interactive_receiver()
+            .check_inputs_not_owned(|_| Ok(false))
+            .expect("No inputs should be owned")
+            .check_no_mixed_input_scripts()
+            .expect("No mixed input scripts")
+            .check_no_inputs_seen_before(|_| Ok(false))
+            .expect("No inputs should be seen before")
+            .identify_receiver_outputs(|script| {
+                let network = Network::Bitcoin;
+                Ok(Address::from_script(script, network)
+                    == Address::from_str("3CZZi7aWFugaCdUCS15dgrUUViupmB8bVM")
+                        .unwrap()
+                        .require_network(network))
+            })
+            .expect("Receiver output should be identified")
+    }
+
+    /// A previous output paying `value` to the all-zeros P2WPKH script
+    fn p2wpkh_txo(value: u64) -> TxOut {
+        TxOut { value, script_pubkey: ScriptBuf::new_v0_p2wpkh(&WPubkeyHash::all_zeros()) }
+    }
+
+    /// Output `vout` of the all-zeros txid
+    fn test_outpoint(vout: u32) -> OutPoint { OutPoint { txid: Txid::all_zeros(), vout } }
+
+    fn checked_proposal_with_contributed_input() -> ProvisionalProposal {
+        checked_proposal_with_contributed_input_with_query(
             "maxadditionalfeecontribution=182&additionalfeeoutputindex=0",
-            headers,
         )
     }
 
+    /// The checked proposal with a 100_000 sat P2WPKH input contributed by the receiver
+    fn checked_proposal_with_contributed_input_with_query(query: &str) -> ProvisionalProposal {
+        let mut proposal = checked_proposal_from_test_vector_with_query(query);
+        proposal.contribute_witness_input(p2wpkh_txo(100_000), test_outpoint(0));
+        proposal
+    }
+
     #[test]
     fn can_get_proposal_from_request() {
         let proposal = proposal_from_test_vector();
@@ -692,4 +2155,1147 @@ mod test {
 
         assert!(payjoin.is_ok(), "Payjoin should be a valid PSBT");
     }
+
+    #[test]
+    fn original_psbt_does_not_look_like_payjoin() {
+        let proposal = proposal_from_test_vector().unwrap();
+        assert!(!proposal.looks_like_payjoin());
+    }
+
+    #[test]
+    fn payjoin_proposal_looks_like_payjoin() {
+        use std::str::FromStr;
+
+        // Payjoin Proposal Test Vector from BIP 78
+        let payjoin_psbt = Psbt::from_str("cHNidP8BAJwCAAAAAo8nutGgJdyYGXWiBEb45Hoe9lWGbkxh/6bNiOJdCDuDAAAAAAD+////jye60aAl3JgZdaIERvjkeh72VYZuTGH/ps2I4l0IO4MBAAAAAP7///8CJpW4BQAAAAAXqRQd6EnwadJ0FQ46/q6NcutaawlEMIcACT0AAAAAABepFHdAltvPSGdDwi9DR+m0af6+i2d6h9MAAAAAAQEgqBvXBQAAAAAXqRTeTh6QYcpZE1sDWtXm1HmQRUNU0IcBBBYAFMeKRXJTVYKNVlgHTdUmDV/LaYUwIgYDFZrAGqDVh1TEtNi300ntHt/PCzYrT2tVEGcjooWPhRYYSFzWUDEAAIABAACAAAAAgAEAAAAAAAAAAAEBIICEHgAAAAAAF6kUyPLL+cphRyyI5GTUazV0hF2R2NWHAQcXFgAUX4BmVeWSTJIEwtUb5TlPS/ntohABCGsCRzBEAiBnu3tA3yWlT0WBClsXXS9j69Bt+waCs9JcjWtNjtv7VgIge2VYAaBeLPDB6HGFlpqOENXMldsJezF9Gs5amvDQRDQBIQJl1jz1tBt8hNx2owTm+4Du4isx0pmdKNMNIjjaMHFfrQABABYAFEb2Giu6c4KO5YW0pfw3lGp9jMUUIgICygvBWB5prpfx61y1HDAwo37kYP3YRJBvAjtunBAur3wYSFzWUDEAAIABAACAAAAAgAEAAAABAAAAAAA=").unwrap();
+        let proposal = UncheckedProposal { psbt: payjoin_psbt, params: Params::default() };
+        assert!(proposal.looks_like_payjoin());
+    }
+
+    #[test]
+    fn payjoin_proposal_counts_contributed_inputs() {
+        let mut provisional = checked_proposal_from_test_vector();
+        for (vout, value) in [(0, 10_000), (1, 20_000)] {
+            provisional.contribute_witness_input(p2wpkh_txo(value), test_outpoint(vout));
+        }
+
+        let psbt = provisional.payjoin_psbt.clone();
+        let payjoin = provisional.prepare_psbt(psbt).expect("Payjoin proposal should be prepared");
+
+        assert_eq!(payjoin.contributed_input_count(), 2);
+        assert_eq!(payjoin.contributed_input_value(), Amount::from_sat(30_000));
+    }
+
+    #[test]
+    fn privacy_metrics_reflect_contribution() {
+        let provisional = checked_proposal_from_test_vector();
+        let receiver_script = provisional.payjoin_psbt.unsigned_tx.output[1].script_pubkey.clone();
+        let psbt = provisional.payjoin_psbt.clone();
+        let plain = provisional.clone().prepare_psbt(psbt).unwrap().privacy_metrics();
+        assert_eq!(plain.input_ownership_interpretations, 1);
+        assert!(!plain.defeats_common_input_ownership);
+        assert_eq!(plain.unnecessary_input, Some(false));
+        let psbt = provisional.payjoin_psbt.clone();
+        // The payee receives the payment, or the change as well, or only the change
+        assert_eq!(provisional.clone().prepare_psbt(psbt).unwrap().interpretation_count(), 3);
+
+        // A receiver input larger than the sender's change keeps the smallest output below
+        // the smallest input
+        for (value, unnecessary_input) in [(100_000_000, false), (1_000_000, true)] {
+            let mut provisional = provisional.clone();
+            let txo = TxOut { value, script_pubkey: receiver_script.clone() };
+            provisional.contribute_witness_input(txo, test_outpoint(0));
+            let psbt = provisional.payjoin_psbt.clone();
+            let report = provisional.prepare_psbt(psbt).unwrap().privacy_metrics();
+            assert_eq!(report.sender_input_count, 1);
+            assert_eq!(report.receiver_input_count, 1);
+            assert_eq!(report.input_ownership_interpretations, 2);
+            assert!(report.defeats_common_input_ownership);
+            assert_eq!(report.unnecessary_input, Some(unnecessary_input));
+        }
+    }
+
+    #[test]
+    fn receiver_output_is_largest_after_large_contribution() {
+        let mut provisional = checked_proposal_from_test_vector();
+        // The 2M sat payment is smaller than the sender's change
+        assert!(!provisional.receiver_output_is_largest());
+        let receiver_script = provisional.payjoin_psbt.unsigned_tx.output[1].script_pubkey.clone();
+        let txo = TxOut { value: 100_000_000, script_pubkey: receiver_script };
+        provisional.contribute_witness_input(txo, test_outpoint(0));
+        assert!(provisional.receiver_output_is_largest());
+    }
+
+    #[test]
+    fn interpretation_count_rules_out_negative_payments() {
+        let mut provisional = checked_proposal_from_test_vector();
+        let receiver_script = provisional.payjoin_psbt.unsigned_tx.output[1].script_pubkey.clone();
+        let txo = TxOut { value: 100_000_000, script_pubkey: receiver_script };
+        provisional.contribute_witness_input(txo, test_outpoint(0));
+        let psbt = provisional.payjoin_psbt.clone();
+        let payjoin = provisional.prepare_psbt(psbt).unwrap();
+        // Of the 3 x 3 assignments, a payer spending only one input can't keep the 102M sat
+        // receiver output
+        assert_eq!(payjoin.interpretation_count(), 7);
+    }
+
+    #[test]
+    fn receiver_address_reuse_is_detected() {
+        let mut provisional = checked_proposal_with_contributed_input();
+        assert!(provisional.detect_receiver_address_reuse().is_empty());
+
+        let receiver_script = provisional.payjoin_psbt.unsigned_tx.output[1].script_pubkey.clone();
+        let txo = TxOut { value: 50_000, script_pubkey: receiver_script.clone() };
+        provisional.contribute_witness_input(txo, test_outpoint(1));
+        assert_eq!(provisional.detect_receiver_address_reuse(), vec![receiver_script]);
+
+        // Reuse is only warned about unless the receiver rejects it
+        assert!(provisional.clone().finalize_proposal(|psbt| Ok(psbt.clone()), None).is_ok());
+        provisional.reject_receiver_address_reuse(true);
+        match provisional.finalize_proposal(|psbt| Ok(psbt.clone()), None) {
+            Err(Error::Server(e)) =>
+                assert!(e.to_string().contains("reuse the scripts of its inputs")),
+            _ => panic!("address reuse should be rejected"),
+        }
+    }
+
+    #[test]
+    fn per_input_fee_breakdown_sums_to_additional_fee() {
+        let mut provisional = checked_proposal_with_contributed_input();
+        provisional.contribute_witness_input(p2wpkh_txo(50_000), test_outpoint(1));
+        let original_change = provisional.payjoin_psbt.unsigned_tx.output[0].value;
+        let feerate = Some(FeeRate::from_sat_per_vb_unchecked(1));
+
+        let breakdown = provisional.per_input_fee_breakdown(feerate).unwrap();
+        let mut outpoints: Vec<OutPoint> =
+            breakdown.iter().map(|(outpoint, _)| *outpoint).collect();
+        // Contributed inputs are inserted at random positions
+        outpoints.sort();
+        assert_eq!(outpoints, vec![test_outpoint(0), test_outpoint(1)]);
+        let total: Amount = breakdown.iter().map(|(_, fee)| *fee).sum();
+        let psbt = provisional.apply_fee(feerate).unwrap();
+        assert_eq!(Amount::from_sat(original_change - psbt.unsigned_tx.output[0].value), total);
+        // Each input is estimated at the sender's 91 vB P2SH-P2WPKH input weight
+        assert_eq!(total, Amount::from_sat(2 * 91));
+
+        let provisional = checked_proposal_from_test_vector();
+        assert!(provisional.per_input_fee_breakdown(feerate).unwrap().is_empty());
+    }
+
+    #[test]
+    fn payjoin_proposal_serializes_to_psbt() {
+        use std::str::FromStr;
+
+        let provisional = checked_proposal_from_test_vector();
+        let psbt = provisional.payjoin_psbt.clone();
+        let payjoin = provisional.prepare_psbt(psbt).expect("Payjoin proposal should be prepared");
+
+        assert_eq!(&Psbt::deserialize(&payjoin.to_psbt_bytes()).unwrap(), payjoin.psbt());
+        assert_eq!(&Psbt::from_str(&payjoin.to_psbt_base64()).unwrap(), payjoin.psbt());
+    }
+
+    #[test]
+    fn additional_fee_at_sender_limit_is_accepted() {
+        let mut payjoin = checked_proposal_with_contributed_input();
+        assert_eq!(payjoin.additional_fee_contribution_limit(), Some(Amount::from_sat(182)));
+        // 91 vB P2SH-P2WPKH input at 2 sat/vB costs exactly the 182 sat limit
+        let original_change = payjoin.payjoin_psbt.unsigned_tx.output[0].value;
+        let psbt = payjoin.apply_fee(FeeRate::from_sat_per_vb(2)).expect("fee within limit");
+        assert_eq!(psbt.unsigned_tx.output[0].value, original_change - 182);
+    }
+
+    #[test]
+    fn additional_fee_above_sender_limit_is_rejected() {
+        let mut payjoin = checked_proposal_with_contributed_input();
+        assert!(payjoin.apply_fee(FeeRate::from_sat_per_vb(3)).is_err());
+    }
+
+    #[test]
+    fn additional_fee_covers_every_contributed_input() {
+        let mut payjoin = checked_proposal_from_test_vector();
+        let original_tx = payjoin.payjoin_psbt.unsigned_tx.clone();
+        let inputs =
+            [(p2wpkh_txo(100_000), test_outpoint(0)), (p2wpkh_txo(50_000), test_outpoint(1))];
+        let contributed = payjoin
+            .contribute_witness_inputs_filtered(inputs, |_, _| Ok(true), VetoHandling::Abort)
+            .unwrap();
+        assert_eq!(contributed, 2);
+
+        // Two 91 vB inputs at 1 sat/vB cost exactly the 182 sat limit
+        let psbt = payjoin.clone().apply_fee(FeeRate::from_sat_per_vb(1)).unwrap().clone();
+        assert_eq!(psbt.unsigned_tx.output[0].value, original_tx.output[0].value - 182);
+        assert_eq!(psbt.unsigned_tx.output[1].value, original_tx.output[1].value + 150_000);
+        match payjoin.apply_fee(FeeRate::from_sat_per_vb(2)) {
+            Err(Error::BadRequest(e)) => assert_eq!(e.error_code(), "original-psbt-rejected"),
+            _ => panic!("expected the fee for both inputs to exceed the sender's limit"),
+        }
+    }
+
+    #[test]
+    fn sender_fee_parameters_are_exposed() {
+        let proposal = proposal_from_test_vector_with_query(
+            "maxadditionalfeecontribution=182&additionalfeeoutputindex=0&minfeerate=2",
+        )
+        .unwrap();
+        assert_eq!(proposal.additional_fee_contribution_limit(), Some(Amount::from_sat(182)));
+        assert_eq!(proposal.additional_fee_output_index(), Some(0));
+        assert_eq!(proposal.sender_min_fee_rate(), FeeRate::from_sat_per_vb_unchecked(2));
+
+        let proposal = proposal_from_test_vector_with_query("v=1").unwrap();
+        assert_eq!(proposal.additional_fee_contribution_limit(), None);
+        assert_eq!(proposal.additional_fee_output_index(), None);
+        assert_eq!(proposal.sender_min_fee_rate(), FeeRate::ZERO);
+    }
+
+    #[test]
+    fn finalized_proposal_must_pay_sender_min_fee_rate() {
+        // Without `maxadditionalfeecontribution` the sender pays nothing for the receiver's input
+        let checked = checked_proposal_with_contributed_input_with_query;
+        let wallet_process_psbt = |psbt: &Psbt| Ok(psbt.clone());
+
+        assert!(checked("minfeerate=1").finalize_proposal(wallet_process_psbt, None).is_ok());
+        match checked("minfeerate=2").finalize_proposal(wallet_process_psbt, None) {
+            Err(Error::BadRequest(e)) => assert_eq!(e.error_code(), "unavailable"),
+            _ => panic!("expected the proposal to be rejected below the sender's minfeerate"),
+        }
+    }
+
+    #[test]
+    fn declined_proposal_is_not_signed() {
+        use std::cell::{Cell, RefCell};
+
+        let proposal = checked_proposal_with_contributed_input();
+        let signed = Cell::new(false);
+        let wallet_process_psbt = |psbt: &Psbt| {
+            signed.set(true);
+            Ok(psbt.clone())
+        };
+        let feerate = Some(FeeRate::from_sat_per_vb_unchecked(2));
+
+        match proposal.clone().finalize_proposal_with_approval(
+            |_| Ok(false),
+            wallet_process_psbt,
+            feerate,
+            None,
+        ) {
+            Err(Error::BadRequest(e)) => assert_eq!(e.error_code(), "original-psbt-rejected"),
+            _ => panic!("expected a declined proposal"),
+        }
+        assert!(!signed.get());
+
+        let summary = RefCell::new(None);
+        let approve = |s: &ProposalSummary| {
+            summary.replace(Some(s.clone()));
+            Ok(true)
+        };
+        let payjoin = proposal
+            .finalize_proposal_with_approval(approve, wallet_process_psbt, feerate, None)
+            .unwrap();
+        assert!(signed.get());
+        let summary = summary.into_inner().unwrap();
+        // The sender pays 182 sats for the receiver's input on top of the Original PSBT fee
+        assert_eq!(summary.original_fee, Amount::from_sat(332));
+        assert_eq!(summary.fee, Amount::from_sat(332 + 182));
+        assert_eq!(summary.contributed_inputs.len(), 1);
+        assert_eq!(summary.contributed_inputs[0].1, Amount::from_sat(100_000));
+        assert_eq!(summary.receiver_net, bitcoin::SignedAmount::from_sat(2_000_000));
+        assert_eq!(summary.outputs, payjoin.psbt().unsigned_tx.output);
+    }
+
+    #[test]
+    fn proposal_above_max_effective_fee_rate_is_rejected() {
+        let wallet_process_psbt = |psbt: &Psbt| Ok(psbt.clone());
+        let finalize = |max_fee_rate| {
+            checked_proposal_with_contributed_input().finalize_proposal_with_approval(
+                |_| Ok(true),
+                wallet_process_psbt,
+                Some(FeeRate::from_sat_per_vb_unchecked(2)),
+                Some(FeeRate::from_sat_per_vb_unchecked(max_fee_rate)),
+            )
+        };
+
+        assert!(finalize(1_000).is_ok());
+        match finalize(1) {
+            Err(Error::BadRequest(e)) => assert_eq!(e.error_code(), "original-psbt-rejected"),
+            _ => panic!("expected the proposal to be rejected above the maximum fee rate"),
+        }
+    }
+
+    #[test]
+    fn summary_rejects_overflowing_prevout_values() {
+        let mut proposal = checked_proposal_with_contributed_input();
+        for input in proposal.payjoin_psbt.inputs.iter_mut() {
+            if let Some(txo) = input.witness_utxo.as_mut() {
+                txo.value = u64::MAX;
+            }
+        }
+        match proposal.summary() {
+            Err(Error::BadRequest(e)) => assert_eq!(e.error_code(), "psbt-error"),
+            _ => panic!("expected the overflowing input values to be rejected"),
+        }
+    }
+
+    #[test]
+    fn finalize_without_contributed_inputs_charges_no_additional_fee() {
+        let proposal = checked_proposal_from_test_vector();
+        let original_tx = proposal.original_psbt.unsigned_tx.clone();
+        let wallet_process_psbt = |psbt: &Psbt| Ok(psbt.clone());
+
+        let payjoin = proposal
+            .finalize_proposal(wallet_process_psbt, Some(FeeRate::from_sat_per_vb_unchecked(2)))
+            .unwrap();
+        assert_eq!(payjoin.contributed_input_count(), 0);
+        assert_eq!(payjoin.psbt().unsigned_tx.input, original_tx.input);
+        // The same inputs and outputs leave the fee at the Original PSBT's 332 sats
+        assert_eq!(payjoin.psbt().unsigned_tx.output, original_tx.output);
+    }
+
+    #[test]
+    fn finalized_original_psbt_is_accepted() {
+        let proposal = proposal_from_test_vector().unwrap();
+        assert!(proposal.require_sender_finalized().is_ok());
+    }
+
+    #[test]
+    fn unsigned_original_psbt_is_rejected() {
+        let mut proposal = proposal_from_test_vector().unwrap();
+        for input in proposal.psbt.inputs.iter_mut() {
+            input.final_script_sig = None;
+            input.final_script_witness = None;
+        }
+        assert!(proposal.require_sender_finalized().is_err());
+    }
+
+    #[test]
+    fn original_outputs_lists_payment_and_change() {
+        let proposal = proposal_from_test_vector().unwrap();
+        let outputs = proposal.original_outputs();
+        assert_eq!(outputs, proposal.extract_tx_to_schedule_broadcast().output);
+        assert_eq!(outputs.len(), 2);
+    }
+
+    #[test]
+    fn sender_inputs_carry_prevouts() {
+        let mut proposal = proposal_from_test_vector().unwrap();
+        let inputs = proposal.sender_inputs().unwrap();
+        assert_eq!(inputs.len(), 1);
+        assert_eq!(inputs[0].0, proposal.psbt.unsigned_tx.input[0].previous_output);
+        assert_eq!(Some(&inputs[0].1), proposal.psbt.inputs[0].witness_utxo.as_ref());
+
+        proposal.psbt.inputs[0].witness_utxo = None;
+        assert!(proposal.sender_inputs().is_err());
+    }
+
+    #[test]
+    fn original_inputs_uniform_type_detects_mixed_inputs() {
+        use bitcoin::AddressType;
+
+        let proposal = proposal_from_test_vector().unwrap();
+        assert_eq!(proposal.original_inputs_uniform_type(), Some(AddressType::P2sh));
+
+        let mut mixed = proposal.clone();
+        mixed
+            .psbt
+            .unsigned_tx
+            .input
+            .push(bitcoin::TxIn { previous_output: test_outpoint(0), ..Default::default() });
+        mixed.psbt.inputs.push(bitcoin::psbt::Input {
+            witness_utxo: Some(p2wpkh_txo(10_000)),
+            ..Default::default()
+        });
+        assert_eq!(mixed.original_inputs_uniform_type(), None);
+
+        let mut missing_prevout = proposal;
+        missing_prevout.psbt.inputs[0].witness_utxo = None;
+        missing_prevout.psbt.inputs[0].non_witness_utxo = None;
+        assert_eq!(missing_prevout.original_inputs_uniform_type(), None);
+    }
+
+    #[test]
+    fn sender_change_can_be_required() {
+        let proposal = proposal_from_test_vector().unwrap();
+        assert!(proposal.clone().require_sender_change().is_ok());
+
+        // A round amount to the sender's script type looks like a payment, not change
+        let mut round_change = proposal.clone();
+        round_change.psbt.unsigned_tx.output[0].value = 95_980_000;
+        assert!(round_change.require_sender_change().is_err());
+
+        let mut no_change = proposal;
+        no_change.psbt.unsigned_tx.output.remove(0);
+        no_change.psbt.outputs.remove(0);
+        assert!(no_change.require_sender_change().is_err());
+    }
+
+    #[test]
+    fn display_payload_summarizes_original_psbt() {
+        let proposal = proposal_from_test_vector().unwrap();
+        let info = proposal.as_display_payload();
+        assert_eq!(info.sender_input_count, 1);
+        assert_eq!(info.outputs.len(), 2);
+        assert_eq!(info.fee_rate.map(|rate| rate.to_sat_per_vb_floor()), Some(2));
+    }
+
+    #[test]
+    fn substitution_to_larger_script_is_paid_by_receiver() {
+        use std::str::FromStr;
+
+        use bitcoin::{Address, Network};
+
+        let mut payjoin = checked_proposal_from_test_vector();
+        let receiver_vout = payjoin.owned_vouts[0];
+        let original_value = payjoin.payjoin_psbt.unsigned_tx.output[receiver_vout].value;
+        // P2SH is 24 bytes encoded, P2TR is 35 bytes encoded
+        let p2tr =
+            Address::from_str("bc1p5d7rjq7g6rdk2yhzks9smlaqtedr4dekq08ge8ztwac72sfr9rusxg3297")
+                .unwrap()
+                .require_network(Network::Bitcoin)
+                .unwrap();
+        payjoin.substitute_output_address(p2tr).expect("Substitution should succeed");
+        let psbt = payjoin.apply_fee(FeeRate::from_sat_per_vb(2)).unwrap();
+        // 11 bytes at 2 sat/vB
+        assert_eq!(psbt.unsigned_tx.output[receiver_vout].value, original_value - 22);
+    }
+
+    #[test]
+    fn substitution_to_smaller_script_costs_receiver_nothing() {
+        use std::str::FromStr;
+
+        use bitcoin::{Address, Network};
+
+        let mut payjoin = checked_proposal_from_test_vector();
+        let receiver_vout = payjoin.owned_vouts[0];
+        let original_value = payjoin.payjoin_psbt.unsigned_tx.output[receiver_vout].value;
+        let p2wpkh = Address::from_str("bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4")
+            .unwrap()
+            .require_network(Network::Bitcoin)
+            .unwrap();
+        payjoin.substitute_output_address(p2wpkh).expect("Substitution should succeed");
+        let psbt = payjoin.apply_fee(FeeRate::from_sat_per_vb(2)).unwrap();
+        assert_eq!(psbt.unsigned_tx.output[receiver_vout].value, original_value);
+    }
+
+    #[test]
+    fn substitution_rejects_dust() {
+        use std::str::FromStr;
+
+        use bitcoin::{Address, Network};
+
+        let p2tr =
+            Address::from_str("bc1p5d7rjq7g6rdk2yhzks9smlaqtedr4dekq08ge8ztwac72sfr9rusxg3297")
+                .unwrap()
+                .require_network(Network::Bitcoin)
+                .unwrap();
+        let dust = p2tr.script_pubkey().dust_value().to_sat();
+
+        let mut payjoin = checked_proposal_from_test_vector();
+        let receiver_vout = payjoin.owned_vouts[0];
+        payjoin.payjoin_psbt.unsigned_tx.output[receiver_vout].value = dust - 1;
+        assert!(payjoin.substitute_output_address(p2tr.clone()).is_err());
+
+        payjoin.payjoin_psbt.unsigned_tx.output[receiver_vout].value = dust;
+        assert!(payjoin.substitute_output_address(p2tr).is_ok());
+        // Paying for the larger script would leave the output below dust
+        assert!(payjoin.apply_fee(FeeRate::from_sat_per_vb(2)).is_err());
+    }
+
+    #[test]
+    fn original_psbt_fingerprint_is_stable() {
+        let proposal = proposal_from_test_vector().unwrap();
+        let resubmitted = proposal_from_test_vector_with_query("v=1").unwrap();
+        assert_eq!(proposal.original_psbt_fingerprint(), resubmitted.original_psbt_fingerprint());
+
+        let body = proposal.psbt.to_string();
+        let headers = MockHeaders::new(body.len() as u64);
+        let round_trip = UncheckedProposal::from_request(body.as_bytes(), "", headers).unwrap();
+        assert_eq!(proposal.original_psbt_fingerprint(), round_trip.original_psbt_fingerprint());
+    }
+
+    #[test]
+    fn original_fee_rate_limit_is_inclusive() {
+        let fee_rate = proposal_from_test_vector().unwrap().psbt_fee_rate().unwrap();
+        let proposal = proposal_from_test_vector().unwrap();
+        assert!(proposal.limit_original_fee_rate(fee_rate).is_ok());
+        let proposal = proposal_from_test_vector().unwrap();
+        let below = FeeRate::from_sat_per_kwu(fee_rate.to_sat_per_kwu() - 1);
+        assert!(proposal.limit_original_fee_rate(below).is_err());
+    }
+
+    #[test]
+    fn original_weight_limit_is_inclusive() {
+        let weight =
+            proposal_from_test_vector().unwrap().extract_tx_to_schedule_broadcast().weight();
+        let proposal = proposal_from_test_vector().unwrap();
+        assert!(proposal.limit_original_weight(weight).is_ok());
+        let proposal = proposal_from_test_vector().unwrap();
+        assert!(proposal.limit_original_weight(weight - Weight::from_wu(1)).is_err());
+    }
+
+    #[test]
+    fn contributed_inputs_inherit_original_sequence() {
+        use bitcoin::Sequence;
+
+        for sequence in [Sequence::ENABLE_RBF_NO_LOCKTIME, Sequence::MAX] {
+            let mut provisional = checked_proposal_from_test_vector();
+            provisional.payjoin_psbt.unsigned_tx.input[0].sequence = sequence;
+            provisional.contribute_witness_input(p2wpkh_txo(10_000), test_outpoint(0));
+            assert!(provisional
+                .payjoin_psbt
+                .unsigned_tx
+                .input
+                .iter()
+                .all(|input| input.sequence == sequence));
+        }
+    }
+
+    #[test]
+    fn sender_change_is_guessed_from_script_type() {
+        let mut payjoin = checked_proposal_from_test_vector();
+        // The sender's P2SH change is not a round amount
+        assert_eq!(payjoin.likely_sender_change(), vec![0]);
+
+        payjoin.original_psbt.unsigned_tx.output[0].value = 96_000_000;
+        assert!(payjoin.likely_sender_change().is_empty());
+    }
+
+    #[test]
+    fn receiver_fee_follows_subtraction_strategy() {
+        use std::str::FromStr;
+
+        use bitcoin::{Address, Network};
+
+        let p2tr =
+            Address::from_str("bc1p5d7rjq7g6rdk2yhzks9smlaqtedr4dekq08ge8ztwac72sfr9rusxg3297")
+                .unwrap()
+                .require_network(Network::Bitcoin)
+                .unwrap();
+        let payjoin_with_strategy = |strategy| {
+            let mut payjoin = proposal_from_test_vector()
+                .unwrap()
+                .assume_interactive_receiver()
+                .check_inputs_not_owned(|_| Ok(false))
+                .expect("No inputs should be owned")
+                .check_no_mixed_input_scripts()
+                .expect("No mixed input scripts")
+                .check_no_inputs_seen_before(|_| Ok(false))
+                .expect("No inputs should be seen before")
+                .identify_receiver_outputs(|_| Ok(true))
+                .expect("Receiver outputs should be identified");
+            payjoin.payjoin_psbt.unsigned_tx.output[0].value = 1_000_000;
+            payjoin.payjoin_psbt.unsigned_tx.output[1].value = 3_000_000;
+            payjoin.substitute_output_address(p2tr.clone()).unwrap();
+            payjoin.set_fee_subtraction_strategy(strategy).unwrap();
+            // The larger substitute script costs the receiver 22 sats at 2 sat/vB
+            let psbt = payjoin.apply_fee(FeeRate::from_sat_per_vb(2)).unwrap();
+            (psbt.unsigned_tx.output[0].value, psbt.unsigned_tx.output[1].value)
+        };
+
+        assert_eq!(
+            payjoin_with_strategy(FeeSubtractionStrategy::FirstOutput),
+            (1_000_000 - 22, 3_000_000)
+        );
+        assert_eq!(
+            payjoin_with_strategy(FeeSubtractionStrategy::Designated(1)),
+            (1_000_000, 3_000_000 - 22)
+        );
+        assert_eq!(
+            payjoin_with_strategy(FeeSubtractionStrategy::Largest),
+            (1_000_000, 3_000_000 - 22)
+        );
+        assert_eq!(
+            payjoin_with_strategy(FeeSubtractionStrategy::Proportional),
+            (1_000_000 - 6, 3_000_000 - 16)
+        );
+    }
+
+    #[test]
+    fn designated_fee_output_must_belong_to_receiver() {
+        let mut payjoin = checked_proposal_from_test_vector();
+        assert!(payjoin
+            .set_fee_subtraction_strategy(FeeSubtractionStrategy::Designated(0))
+            .is_err());
+        assert!(payjoin
+            .set_fee_subtraction_strategy(FeeSubtractionStrategy::Designated(1))
+            .is_ok());
+    }
+
+    #[test]
+    fn substitution_rejects_sender_output_script() {
+        let mut payjoin = checked_proposal_from_test_vector();
+        let sender_script = payjoin.payjoin_psbt.unsigned_tx.output[0].script_pubkey.clone();
+        let sender_address =
+            bitcoin::Address::from_script(&sender_script, bitcoin::Network::Bitcoin).unwrap();
+        assert!(payjoin.substitute_output_address(sender_address).is_err());
+        assert_eq!(
+            payjoin.payjoin_psbt.unsigned_tx.output[1].script_pubkey,
+            payjoin.original_psbt.unsigned_tx.output[1].script_pubkey
+        );
+    }
+
+    #[test]
+    fn spent_inputs_are_rejected() {
+        let proposal = proposal_from_test_vector().unwrap();
+        assert!(proposal.require_unspent_inputs(|_| Ok(true)).is_ok());
+        let proposal = proposal_from_test_vector().unwrap();
+        assert!(proposal.require_unspent_inputs(|_| Ok(false)).is_err());
+    }
+
+    #[test]
+    fn confirmed_original_is_rejected() {
+        let proposal = proposal_from_test_vector().unwrap();
+        let txid = proposal.psbt.unsigned_tx.txid();
+        assert!(proposal.clone().require_unconfirmed(|_| Ok(false)).is_ok());
+        match proposal.require_unconfirmed(|confirmed| Ok(*confirmed == txid)) {
+            Err(Error::BadRequest(e)) => assert_eq!(e.error_code(), "original-psbt-rejected"),
+            _ => panic!("expected the confirmed original to be rejected"),
+        }
+    }
+
+    #[test]
+    fn leaky_metadata_is_rejected_or_stripped() {
+        use std::str::FromStr;
+
+        use bitcoin::bip32::{DerivationPath, ExtendedPubKey, Fingerprint};
+
+        let xpub = ExtendedPubKey::from_str("xpub661MyMwAqRbcFtXgS5sYJABqqG9YLmC4Q1Rdap9gSE8NqtwybGhePY2gZ29ESFjqJoCu1Rupje8YtGqsefD265TMg7usUDFdp6W1EGMcet8").unwrap();
+        let origin = (Fingerprint::default(), DerivationPath::from_str("m/84'/0'/0'").unwrap());
+
+        let proposal = proposal_from_test_vector().unwrap();
+        assert!(proposal.clone().require_no_leaky_metadata().is_ok());
+
+        let mut leaky = proposal;
+        leaky.psbt.xpub.insert(xpub, origin);
+        let stripped = leaky.clone().strip_leaky_metadata();
+        assert!(stripped.psbt.xpub.is_empty());
+        assert!(stripped.require_no_leaky_metadata().is_ok());
+        match leaky.require_no_leaky_metadata() {
+            Err(Error::BadRequest(e)) => assert_eq!(e.error_code(), "original-psbt-rejected"),
+            _ => panic!("expected the global xpub to be rejected"),
+        }
+    }
+
+    #[test]
+    fn proposal_omits_receiver_key_origins() {
+        use std::str::FromStr;
+
+        use bitcoin::bip32::{DerivationPath, ExtendedPubKey, Fingerprint};
+
+        let xpub = ExtendedPubKey::from_str("xpub661MyMwAqRbcFtXgS5sYJABqqG9YLmC4Q1Rdap9gSE8NqtwybGhePY2gZ29ESFjqJoCu1Rupje8YtGqsefD265TMg7usUDFdp6W1EGMcet8").unwrap();
+        let origin = (Fingerprint::default(), DerivationPath::from_str("m/84'/0'/0'").unwrap());
+        let provisional = checked_proposal_from_test_vector();
+        let mut psbt = provisional.payjoin_psbt.clone();
+        psbt.xpub.insert(xpub, origin.clone());
+        psbt.outputs[1].bip32_derivation.insert(xpub.public_key, origin);
+
+        let payjoin = provisional.prepare_psbt(psbt).unwrap();
+        assert!(!has_leaky_metadata(payjoin.psbt()));
+    }
+
+    #[test]
+    fn projected_output_count_counts_added_outputs() {
+        use bitcoin::WScriptHash;
+
+        let anchor_script = ScriptBuf::new_v0_p2wpkh(&WPubkeyHash::all_zeros());
+        let substitute_script = ScriptBuf::new_v0_p2wsh(&WScriptHash::all_zeros());
+        let substitute =
+            bitcoin::Address::from_script(&substitute_script, bitcoin::Network::Bitcoin).unwrap();
+        let mut payjoin = checked_proposal_from_test_vector();
+        assert_eq!(payjoin.projected_output_count(), 2);
+        payjoin.substitute_output_address(substitute).unwrap();
+        assert_eq!(payjoin.projected_output_count(), 2);
+        let payjoin = payjoin.ensure_cpfp_anchor(Amount::from_sat(10_000), &anchor_script).unwrap();
+        assert_eq!(payjoin.projected_output_count(), 3);
+    }
+
+    #[test]
+    fn cpfp_anchor_is_added_from_receiver_output() {
+        let anchor_script = ScriptBuf::new_v0_p2wpkh(&WPubkeyHash::all_zeros());
+        let payjoin = checked_proposal_from_test_vector();
+        let receiver_value = payjoin.payjoin_psbt.unsigned_tx.output[1].value;
+        let payjoin = payjoin.ensure_cpfp_anchor(Amount::from_sat(10_000), &anchor_script).unwrap();
+        assert_eq!(payjoin.owned_vouts, vec![1, 2]);
+        assert_eq!(payjoin.payjoin_psbt.unsigned_tx.output[1].value, receiver_value - 10_000);
+        assert_eq!(payjoin.payjoin_psbt.unsigned_tx.output[2].value, 10_000);
+
+        // An anchor that is already large enough is preserved
+        let mut payjoin =
+            payjoin.ensure_cpfp_anchor(Amount::from_sat(10_000), &anchor_script).unwrap();
+        assert_eq!(payjoin.payjoin_psbt.unsigned_tx.output.len(), 3);
+
+        // The 31 byte anchor output costs the receiver 62 sats at 2 sat/vB
+        let psbt = payjoin.apply_fee(FeeRate::from_sat_per_vb(2)).unwrap();
+        assert_eq!(psbt.unsigned_tx.output[1].value, receiver_value - 10_000 - 62);
+    }
+
+    #[test]
+    fn cpfp_anchor_needs_enough_receiver_value() {
+        let anchor_script = ScriptBuf::new_v0_p2wpkh(&WPubkeyHash::all_zeros());
+        let payjoin = checked_proposal_from_test_vector();
+        let receiver_value = payjoin.payjoin_psbt.unsigned_tx.output[1].value;
+        assert!(payjoin
+            .ensure_cpfp_anchor(Amount::from_sat(receiver_value), &anchor_script)
+            .is_err());
+    }
+
+    #[test]
+    fn undo_last_contribution_restores_proposal() {
+        use std::str::FromStr;
+
+        let payjoin = checked_proposal_from_test_vector();
+        let original_tx = payjoin.payjoin_psbt.unsigned_tx.clone();
+        assert!(payjoin.clone().undo_last_contribution().is_err());
+
+        let first = OutPoint::from_str(
+            "0000000000000000000000000000000000000000000000000000000000000001:0",
+        )
+        .unwrap();
+        let second = OutPoint::from_str(
+            "0000000000000000000000000000000000000000000000000000000000000002:0",
+        )
+        .unwrap();
+        let txo =
+            TxOut { value: 50_000, script_pubkey: original_tx.output[1].script_pubkey.clone() };
+        let mut with_first = payjoin.clone();
+        with_first.contribute_witness_input(txo.clone(), first);
+        let mut with_both = with_first.clone();
+        with_both.contribute_witness_input(txo.clone(), second);
+
+        let contributed: Vec<OutPoint> =
+            with_both.contributed_inputs().map(|(txin, _)| txin.previous_output).collect();
+        assert_eq!(contributed.len(), 2);
+        assert!(contributed.contains(&first) && contributed.contains(&second));
+
+        let undone = with_both.undo_last_contribution().unwrap();
+        assert_eq!(undone.payjoin_psbt.unsigned_tx, with_first.payjoin_psbt.unsigned_tx);
+        let undone = undone.undo_last_contribution().unwrap();
+        assert_eq!(undone.payjoin_psbt.unsigned_tx, original_tx);
+        assert_eq!(undone.payjoin_psbt.inputs.len(), original_tx.input.len());
+        assert!(undone.undo_last_contribution().is_err());
+
+        // The receiver output no longer holds the value the input added
+        let mut changed = with_first;
+        changed.payjoin_psbt.unsigned_tx.output[1].value = 0;
+        assert!(changed.undo_last_contribution().is_err());
+    }
+
+    #[test]
+    fn undo_last_contribution_removes_the_whole_input_set() {
+        let mut payjoin = checked_proposal_with_contributed_input();
+        let with_single = payjoin.payjoin_psbt.clone();
+        let inputs = [
+            (p2wpkh_txo(50_000), test_outpoint(1)),
+            (p2wpkh_txo(60_000), test_outpoint(2)),
+            (p2wpkh_txo(70_000), test_outpoint(3)),
+        ];
+        let contributed = payjoin
+            .contribute_witness_inputs_filtered(inputs, |_, _| Ok(true), VetoHandling::Abort)
+            .unwrap();
+        assert_eq!(contributed, 3);
+        assert_eq!(payjoin.contributed_inputs().count(), 4);
+
+        let payjoin = payjoin.undo_last_contribution().unwrap();
+        assert_eq!(payjoin.payjoin_psbt, with_single);
+        let contributed: Vec<OutPoint> =
+            payjoin.contributed_inputs().map(|(txin, _)| txin.previous_output).collect();
+        assert_eq!(contributed, vec![test_outpoint(0)]);
+
+        let payjoin = payjoin.undo_last_contribution().unwrap();
+        assert_eq!(payjoin.contributed_inputs().count(), 0);
+        assert!(payjoin.undo_last_contribution().is_err());
+    }
+
+    #[test]
+    fn vetoed_contributions_are_skipped_or_abort() {
+        use std::str::FromStr;
+
+        let allowed = OutPoint::from_str(
+            "0000000000000000000000000000000000000000000000000000000000000001:0",
+        )
+        .unwrap();
+        let blocked = OutPoint::from_str(
+            "0000000000000000000000000000000000000000000000000000000000000002:0",
+        )
+        .unwrap();
+        let mut payjoin = checked_proposal_from_test_vector();
+        let original_tx = payjoin.payjoin_psbt.unsigned_tx.clone();
+        let txo =
+            TxOut { value: 50_000, script_pubkey: original_tx.output[1].script_pubkey.clone() };
+        let inputs = vec![(txo.clone(), allowed), (txo, blocked)];
+        let policy = |outpoint: &OutPoint, _: &TxOut| Ok(*outpoint != blocked);
+
+        assert!(payjoin
+            .contribute_witness_inputs_filtered(inputs.clone(), policy, VetoHandling::Abort)
+            .is_err());
+        assert_eq!(payjoin.payjoin_psbt.unsigned_tx, original_tx);
+
+        let contributed =
+            payjoin.contribute_witness_inputs_filtered(inputs, policy, VetoHandling::Skip).unwrap();
+        assert_eq!(contributed, 1);
+        let contributed: Vec<OutPoint> =
+            payjoin.contributed_inputs().map(|(txin, _)| txin.previous_output).collect();
+        assert_eq!(contributed, vec![allowed]);
+    }
+
+    #[test]
+    fn request_error_json_keeps_code_with_custom_message() {
+        let error = RequestError::from(InternalRequestError::MissingPayment);
+        assert_eq!(error.error_code(), "original-psbt-rejected");
+
+        let reply: serde_json::Value =
+            serde_json::from_str(&error.to_json_with_message("Falta el \"pago\"")).unwrap();
+        assert_eq!(reply["errorCode"], "original-psbt-rejected");
+        assert_eq!(reply["message"], "Falta el \"pago\"");
+    }
+
+    #[test]
+    fn request_error_reply_uses_error_code() {
+        for error in [
+            InternalRequestError::MissingHeader("Content-Type"),
+            InternalRequestError::ContentLengthTooLarge(1),
+            InternalRequestError::MissingPayment,
+            InternalRequestError::ProposalBelowMinRelayFee(FeeRate::ZERO, FeeRate::BROADCAST_MIN),
+        ] {
+            let error = RequestError::from(error);
+            let reply: serde_json::Value = serde_json::from_str(&error.to_string()).unwrap();
+            assert_eq!(reply["errorCode"], error.error_code());
+        }
+    }
+
+    #[test]
+    fn request_error_canonical_bytes_are_stable() {
+        let error = RequestError::from(InternalRequestError::MissingPayment);
+        let bytes = error.to_canonical_bytes();
+        assert_eq!(
+            bytes,
+            br#"{"errorCode":"original-psbt-rejected","message":"Missing payment."}"#
+        );
+        assert_eq!(
+            RequestError::from(InternalRequestError::MissingPayment).to_canonical_bytes(),
+            bytes
+        );
+
+        let error = RequestError::from(InternalRequestError::VersionNotAccepted(
+            crate::Version::Two,
+            vec![crate::Version::One],
+        ));
+        assert_eq!(
+            error.to_canonical_bytes(),
+            br#"{"errorCode":"version-unsupported","message":"Payjoin version 2 is not accepted by this receiver.","supported":["1"]}"#
+        );
+        assert_eq!(
+            error.to_json_with_message("Falta el \"pago\""),
+            r#"{"errorCode":"version-unsupported","message":"Falta el \"pago\"","supported":["1"]}"#
+        );
+    }
+
+    #[test]
+    fn v1_proposal_is_rejected_by_v2_only_receiver() {
+        use crate::Version;
+
+        let original_psbt = "cHNidP8BAHMCAAAAAY8nutGgJdyYGXWiBEb45Hoe9lWGbkxh/6bNiOJdCDuDAAAAAAD+////AtyVuAUAAAAAF6kUHehJ8GnSdBUOOv6ujXLrWmsJRDCHgIQeAAAAAAAXqRR3QJbbz0hnQ8IvQ0fptGn+votneofTAAAAAAEBIKgb1wUAAAAAF6kU3k4ekGHKWRNbA1rV5tR5kEVDVNCHAQcXFgAUx4pFclNVgo1WWAdN1SYNX8tphTABCGsCRzBEAiB8Q+A6dep+Rz92vhy26lT0AjZn4PRLi8Bf9qoB/CMk0wIgP/Rj2PWZ3gEjUkTlhDRNAQ0gXwTO7t9n+V14pZ6oljUBIQMVmsAaoNWHVMS02LfTSe0e388LNitPa1UQZyOihY+FFgABABYAFEb2Giu6c4KO5YW0pfw3lGp9jMUUAAA=";
+        let body = original_psbt.as_bytes();
+        let parse = |query, accepted_versions| {
+            let headers = MockHeaders::new(body.len() as u64);
+            UncheckedProposal::from_request_with_versions(body, query, headers, accepted_versions)
+        };
+
+        // Senders that omit `v` speak version 1 too
+        for query in ["v=1", ""] {
+            let error = parse(query, &[Version::Two]).unwrap_err();
+            assert_eq!(error.error_code(), "version-unsupported");
+            let reply: serde_json::Value =
+                serde_json::from_str(&error.to_json_with_message("")).unwrap();
+            let supported: &[&str] = if Version::Two.is_supported() { &["2"] } else { &[] };
+            assert_eq!(reply["supported"], serde_json::json!(supported));
+            let reply: serde_json::Value = serde_json::from_str(&error.to_string()).unwrap();
+            assert_eq!(reply["supported"], serde_json::json!(supported));
+        }
+        assert!(parse("v=1", &[Version::One, Version::Two]).is_ok());
+        assert!(parse("v=1", crate::supported_versions()).is_ok());
+    }
+
+    #[test]
+    fn rejects_out_of_range_fee_output_index() {
+        // the test vector has 2 outputs
+        for index in [2, 3] {
+            let query =
+                format!("maxadditionalfeecontribution=182&additionalfeeoutputindex={}", index);
+            let error = proposal_from_test_vector_with_query(&query).unwrap_err();
+            assert_eq!(error.error_code(), "original-psbt-rejected");
+        }
+        assert!(proposal_from_test_vector_with_query(
+            "maxadditionalfeecontribution=182&additionalfeeoutputindex=1"
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn verify_against_committed_rejects_tampered_tx() {
+        let provisional = checked_proposal_from_test_vector();
+        let psbt = provisional.payjoin_psbt.clone();
+        let payjoin = provisional.prepare_psbt(psbt).expect("Payjoin proposal should be prepared");
+        let committed = payjoin.psbt().clone().extract_tx();
+        assert!(payjoin.verify_against_committed(&committed).is_ok());
+
+        let mut tampered = committed.clone();
+        tampered.output[0].value -= 1;
+        assert!(payjoin.verify_against_committed(&tampered).is_err());
+
+        let mut tampered = committed;
+        tampered.input[0].previous_output.vout += 1;
+        assert!(payjoin.verify_against_committed(&tampered).is_err());
+    }
+
+    #[test]
+    fn try_preserving_privacy_limited_picks_smallest_within_limit() {
+        let mut provisional = checked_proposal_from_test_vector();
+        // The receiver's 2_000_000 sat output is the smallest, so only larger inputs avoid UIH2
+        let candidates = HashMap::from([
+            (Amount::from_sat(1_000_000), test_outpoint(0)),
+            (Amount::from_sat(5_000_000), test_outpoint(1)),
+            (Amount::from_sat(3_000_000), test_outpoint(2)),
+        ]);
+
+        assert_eq!(
+            provisional.try_preserving_privacy_limited(candidates.clone(), 1).unwrap(),
+            test_outpoint(2)
+        );
+        let too_small = HashMap::from([(Amount::from_sat(1_000_000), test_outpoint(0))]);
+        assert!(provisional.try_preserving_privacy_limited(too_small, 1).is_err());
+        let limit_error = provisional.try_preserving_privacy_limited(candidates.clone(), 0);
+        assert!(limit_error.unwrap_err().to_string().contains("limit"));
+
+        let receiver_script = provisional.payjoin_psbt.unsigned_tx.output[1].script_pubkey.clone();
+        provisional.contribute_witness_input(
+            TxOut { value: 3_000_000, script_pubkey: receiver_script },
+            test_outpoint(2),
+        );
+        let limit_error = provisional.try_preserving_privacy_limited(candidates, 1);
+        assert!(limit_error.unwrap_err().to_string().contains("limit"));
+    }
+
+    #[test]
+    fn coin_selection_is_pluggable() {
+        struct LargestFirst;
+
+        impl CoinSelector for LargestFirst {
+            fn select(
+                &self,
+                candidates: &[(TxOut, OutPoint)],
+                context: &SelectionContext,
+            ) -> Result<Vec<(TxOut, OutPoint)>, SelectionError> {
+                assert_eq!(context.sender_inputs().len(), 1);
+                assert!(context.contributed_inputs().is_empty());
+                candidates
+                    .iter()
+                    .max_by_key(|(txo, _)| txo.value)
+                    .map(|input| vec![input.clone()])
+                    .ok_or_else(|| SelectionError::custom("no candidates"))
+            }
+        }
+
+        let mut provisional = checked_proposal_from_test_vector();
+        let receiver_script = provisional.payjoin_psbt.unsigned_tx.output[1].script_pubkey.clone();
+        let candidate = |value, vout| {
+            (TxOut { value, script_pubkey: receiver_script.clone() }, test_outpoint(vout))
+        };
+        let candidates =
+            [candidate(1_000_000, 0), candidate(3_000_000, 1), candidate(5_000_000, 2)];
+
+        // The built-in selector agrees with try_preserving_privacy
+        let selected = provisional.select_inputs(&PrivacyPreservingSelector, &candidates).unwrap();
+        assert_eq!(selected, vec![candidates[1].clone()]);
+
+        let selected = provisional.select_inputs(&LargestFirst, &candidates).unwrap();
+        assert_eq!(selected, vec![candidates[2].clone()]);
+        let error = provisional.select_inputs(&LargestFirst, &[]).unwrap_err();
+        assert!(error.to_string().contains("no candidates"));
+
+        let contributed = provisional
+            .contribute_witness_inputs_filtered(selected, |_, _| Ok(true), VetoHandling::Skip)
+            .unwrap();
+        assert_eq!(contributed, 1);
+        assert_eq!(provisional.selection_context().contributed_inputs().len(), 1);
+    }
+
+    #[test]
+    fn min_input_for_fee_target_covers_its_own_weight() {
+        let proposal = checked_proposal_from_test_vector();
+        let feerate = FeeRate::from_sat_per_vb_unchecked(2);
+        // the test vector's input is P2SH-P2WPKH, 91 vB
+        let min = proposal.min_input_for_fee_target(feerate).unwrap();
+        assert_eq!(min, Amount::from_sat(91 * 2 + 1));
+        let weight = bitcoin::Weight::from_non_witness_data_size(91);
+        assert!(min > weight * feerate);
+        assert_eq!(proposal.min_input_for_fee_target(FeeRate::ZERO).unwrap(), Amount::from_sat(1));
+    }
+
+    #[test]
+    fn finalized_proposal_must_pay_min_relay_fee() {
+        let proposal = checked_proposal_from_test_vector();
+        let original = &proposal.original_psbt;
+        let fee_rate = original.fee().unwrap() / original.clone().extract_tx().weight();
+        let wallet_process_psbt = |psbt: &Psbt| Ok(psbt.clone());
+
+        assert!(proposal.clone().finalize_proposal(wallet_process_psbt, None).is_ok());
+
+        let mut at_min = proposal.clone();
+        at_min.set_min_relay_fee_rate(fee_rate);
+        assert!(at_min.finalize_proposal(wallet_process_psbt, None).is_ok());
+
+        let mut below_min = proposal;
+        below_min.set_min_relay_fee_rate(FeeRate::from_sat_per_kwu(fee_rate.to_sat_per_kwu() + 1));
+        match below_min.finalize_proposal(wallet_process_psbt, None) {
+            Err(Error::BadRequest(e)) => assert_eq!(e.error_code(), "unavailable"),
+            _ => panic!("expected the proposal to be rejected below the min relay fee"),
+        }
+    }
+
+    #[test]
+    fn preview_matches_finalized_proposal() {
+        let proposal = checked_proposal_from_test_vector();
+        let wallet_process_psbt = |psbt: &Psbt| Ok(psbt.clone());
+        let feerate = Some(FeeRate::from_sat_per_vb_unchecked(1));
+
+        let preview = proposal.preview_finalized_psbt(wallet_process_psbt, feerate, None).unwrap();
+        let finalized = proposal.finalize_proposal(wallet_process_psbt, feerate).unwrap();
+        assert_eq!(&preview, finalized.psbt());
+    }
+
+    #[test]
+    fn preview_fails_above_max_effective_fee_rate() {
+        let proposal = checked_proposal_with_contributed_input();
+        let wallet_process_psbt = |psbt: &Psbt| Ok(psbt.clone());
+        let feerate = Some(FeeRate::from_sat_per_vb_unchecked(2));
+        let max_fee_rate = Some(FeeRate::from_sat_per_vb_unchecked(1));
+
+        match proposal.preview_finalized_psbt(wallet_process_psbt, feerate, max_fee_rate) {
+            Err(Error::BadRequest(e)) => assert_eq!(e.error_code(), "original-psbt-rejected"),
+            _ => panic!("expected the preview to be rejected above the maximum fee rate"),
+        }
+        assert!(proposal
+            .finalize_proposal_with_approval(
+                |_| Ok(true),
+                wallet_process_psbt,
+                feerate,
+                max_fee_rate
+            )
+            .is_err());
+    }
+
+    #[test]
+    fn effective_fee_rate_counts_receiver_witnesses() {
+        use bitcoin::Witness;
+
+        let proposal = checked_proposal_with_contributed_input();
+        let original_weight = proposal.original_psbt.clone().extract_tx().weight();
+        let outpoint = test_outpoint(0);
+        let sign = |psbt: &Psbt| {
+            let mut psbt = psbt.clone();
+            let index =
+                psbt.unsigned_tx.input.iter().position(|i| i.previous_output == outpoint).unwrap();
+            psbt.inputs[index].final_script_witness =
+                Some(Witness::from_slice(&[vec![0; 72], vec![0; 33]]));
+            Ok(psbt)
+        };
+        let payjoin = proposal.finalize_proposal(sign, None).unwrap();
+
+        // 41 bytes of input and a witness with a 72 byte signature and 33 byte public key
+        let weight =
+            original_weight + Weight::from_non_witness_data_size(41) + Weight::from_wu(108);
+        assert_eq!(payjoin.effective_fee_rate().unwrap(), Amount::from_sat(332) / weight);
+    }
+
+    #[test]
+    fn validate_inputs_reports_every_problem() {
+        let provisional = checked_proposal_from_test_vector();
+        let original_tx = provisional.payjoin_psbt.unsigned_tx.clone();
+        let receiver_script = original_tx.output[1].script_pubkey.clone();
+        let txo = |value| TxOut { value, script_pubkey: receiver_script.clone() };
+        let sender_outpoint = original_tx.input[0].previous_output;
+
+        assert!(provisional
+            .validate_inputs(&[(txo(50_000), test_outpoint(0)), (txo(60_000), test_outpoint(1))])
+            .is_ok());
+
+        let error = provisional
+            .validate_inputs(&[
+                (txo(50_000), test_outpoint(0)),
+                (txo(50_000), test_outpoint(0)),
+                (txo(50_000), sender_outpoint),
+                (txo(1), test_outpoint(2)),
+            ])
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains(&format!("{} (duplicate candidate)", test_outpoint(0))));
+        assert!(error.contains(&format!("{} (already spent by the proposal)", sender_outpoint)));
+        assert!(error.contains(&format!("{} (dust value", test_outpoint(2))));
+        assert_eq!(provisional.payjoin_psbt.unsigned_tx, original_tx);
+
+        // At 10 sat/vB the P2SH-P2WPKH input weight costs 910 sats
+        let provisional = checked_proposal_from_test_vector_with_query("minfeerate=10");
+        assert!(provisional.validate_inputs(&[(txo(911), test_outpoint(0))]).is_ok());
+        let error =
+            provisional.validate_inputs(&[(txo(600), test_outpoint(0))]).unwrap_err().to_string();
+        assert!(error.contains(&format!(
+            "{} (value of {}",
+            test_outpoint(0),
+            Amount::from_sat(600)
+        )));
+    }
+
+    #[test]
+    fn only_confirmed_inputs_are_contributed() {
+        let mut provisional = checked_proposal_from_test_vector();
+        let original_tx = provisional.payjoin_psbt.unsigned_tx.clone();
+        let receiver_script = original_tx.output[1].script_pubkey.clone();
+        let txo = |value| TxOut { value, script_pubkey: receiver_script.clone() };
+        let is_confirmed = |outpoint: &OutPoint| Ok(outpoint.vout != 1);
+
+        let mixed = [(txo(50_000), test_outpoint(0)), (txo(60_000), test_outpoint(1))];
+        let error = provisional
+            .contribute_confirmed_witness_inputs(mixed, is_confirmed)
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains(&format!("{} (unconfirmed)", test_outpoint(1))));
+        assert!(!error.contains(&format!("{} (", test_outpoint(0))));
+        assert_eq!(provisional.payjoin_psbt.unsigned_tx, original_tx);
+
+        let confirmed = [(txo(50_000), test_outpoint(0)), (txo(60_000), test_outpoint(2))];
+        assert_eq!(
+            provisional.contribute_confirmed_witness_inputs(confirmed, is_confirmed).unwrap(),
+            2
+        );
+        assert_eq!(provisional.contributed_inputs().count(), 2);
+
+        // The sender pays for both inputs, two 91 vB inputs at 1 sat/vB
+        let payjoin = provisional
+            .finalize_proposal(|psbt| Ok(psbt.clone()), Some(FeeRate::from_sat_per_vb_unchecked(1)))
+            .unwrap();
+        let outputs = &payjoin.psbt().unsigned_tx.output;
+        assert_eq!(outputs[0].value, original_tx.output[0].value - 182);
+        assert_eq!(outputs[1].value, original_tx.output[1].value + 110_000);
+    }
+
+    #[test]
+    fn recent_payjoin_outputs_are_avoided() {
+        let provisional = checked_proposal_from_test_vector();
+        let original_tx = provisional.payjoin_psbt.unsigned_tx.clone();
+        let receiver_script = original_tx.output[1].script_pubkey.clone();
+        let txo = |value| TxOut { value, script_pubkey: receiver_script.clone() };
+        let candidates = [
+            (txo(50_000), test_outpoint(0)),
+            (txo(60_000), test_outpoint(1)),
+            (txo(70_000), test_outpoint(2)),
+        ];
+
+        let kept = provisional
+            .avoid_recent_payjoin_outputs(&candidates, |outpoint| Ok(outpoint.vout == 1))
+            .unwrap();
+        assert_eq!(kept, vec![candidates[0].clone(), candidates[2].clone()]);
+        assert!(provisional
+            .avoid_recent_payjoin_outputs(&candidates, |_| Err(Error::Server("lookup".into())))
+            .is_err());
+        assert_eq!(provisional.payjoin_psbt.unsigned_tx, original_tx);
+    }
 }
@@ -4,15 +4,17 @@ use std::fmt;
 use bitcoin::FeeRate;
 use log::warn;
 
-#[cfg(feature = "v2")]
-pub(crate) const SUPPORTED_VERSIONS: [&str; 2] = ["1", "2"];
-#[cfg(not(feature = "v2"))]
-pub(crate) const SUPPORTED_VERSIONS: [&str; 1] = ["1"];
+use crate::Version;
+
+/// The `v` parameter values this build accepts
+pub(crate) fn supported_version_params() -> Vec<&'static str> {
+    crate::supported_versions().iter().map(|version| version.as_str()).collect()
+}
 
 #[derive(Debug, Clone)]
 pub(crate) struct Params {
-    // version
-    // v: usize,
+    // version, BIP 78 senders that omit it speak version 1
+    pub v: Version,
     // disableoutputsubstitution
     pub disable_output_substitution: bool,
     // maxadditionalfeecontribution, additionalfeeoutputindex
@@ -24,6 +26,7 @@ pub(crate) struct Params {
 impl Default for Params {
     fn default() -> Self {
         Params {
+            v: Version::One,
             disable_output_substitution: false,
             additional_fee_contribution: None,
             min_feerate: FeeRate::ZERO,
@@ -47,9 +50,10 @@ impl Params {
         for (k, v) in pairs {
             match (k.borrow(), v.borrow()) {
                 ("v", v) =>
-                    if !SUPPORTED_VERSIONS.contains(&v) {
-                        return Err(Error::UnknownVersion);
-                    },
+                    params.v = *crate::supported_versions()
+                        .iter()
+                        .find(|version| version.as_str() == v)
+                        .ok_or(Error::UnknownVersion)?,
                 ("additionalfeeoutputindex", index) =>
                     additional_fee_output_index = match index.parse::<usize>() {
                         Ok(index) => Some(index),
@@ -1,4 +1,6 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 use bitcoin::psbt::Psbt;
 use bitcoin::{base64, Amount, FeeRate, OutPoint, Script, TxOut};
@@ -6,14 +8,24 @@ use serde::ser::SerializeStruct;
 use serde::{Deserialize, Serialize, Serializer};
 use url::Url;
 
-use super::{Error, InternalRequestError, RequestError, SelectionError};
+use super::error::{InternalDirectoryUrlError, InternalRecoveryCodeError, RecoveryCodeError};
+use super::{
+    CoinSelector, DirectoryUrlError, Error, InputContributionError, InternalRequestError,
+    OutputSubstitutionError, RequestError, SelectionContext, SelectionError,
+};
 use crate::psbt::PsbtExt;
 use crate::receive::optional_parameters::Params;
-use crate::{OhttpKeys, Request};
+use crate::{
+    DirectoryUrl, OhttpKeys, ParameterAttestation, PjUri, PjUriBuilder, RelayUrl, Request, Version,
+    V2_DIRECTORY_CONTENT_TYPE,
+};
 
 #[derive(Debug, Clone)]
 pub struct V2Context {
     directory: url::Url,
+    layout: DirectoryLayout,
+    content_type: String,
+    require_v2_only: bool,
     ohttp_keys: OhttpKeys,
     ohttp_relay: url::Url,
     s: bitcoin::secp256k1::KeyPair,
@@ -23,6 +35,8 @@ pub struct V2Context {
 #[derive(Debug, Clone)]
 pub struct Enroller {
     directory: url::Url,
+    layout: DirectoryLayout,
+    content_type: String,
     ohttp_keys: OhttpKeys,
     ohttp_relay: url::Url,
     s: bitcoin::secp256k1::KeyPair,
@@ -30,25 +44,54 @@ pub struct Enroller {
 
 #[cfg(feature = "v2")]
 impl Enroller {
-    pub fn from_directory_config(directory: Url, ohttp_keys: OhttpKeys, ohttp_relay: Url) -> Self {
+    /// Configure enrollment with a payjoin directory through an OHTTP relay.
+    ///
+    /// The directory must be reached over https unless `allow_insecure` is set, which is only
+    /// meant for testing against a local directory.
+    pub fn from_directory_config(
+        directory: DirectoryUrl,
+        ohttp_keys: OhttpKeys,
+        ohttp_relay: RelayUrl,
+        allow_insecure: bool,
+    ) -> Result<Self, DirectoryUrlError> {
+        let (directory, ohttp_relay) = (directory.into(), ohttp_relay.into());
+        check_directory_url(&directory, &ohttp_relay, allow_insecure)?;
         let secp = bitcoin::secp256k1::Secp256k1::new();
         let (sk, _) = secp.generate_keypair(&mut rand::rngs::OsRng);
-        Enroller {
+        Ok(Enroller {
             directory,
+            layout: DirectoryLayout::default(),
+            content_type: V2_DIRECTORY_CONTENT_TYPE.to_owned(),
             ohttp_keys,
             ohttp_relay,
             s: bitcoin::secp256k1::KeyPair::from_secret_key(&secp, &sk),
-        }
+        })
+    }
+
+    /// Address session resources with the directory's `layout` instead of the flat default.
+    ///
+    /// Enrollment still posts to the directory URL itself.
+    pub fn with_directory_layout(mut self, layout: DirectoryLayout) -> Self {
+        self.layout = layout;
+        self
+    }
+
+    /// Send writes to the directory with `content_type` instead of
+    /// [`V2_DIRECTORY_CONTENT_TYPE`], for directories that expect another `Content-Type`.
+    pub fn with_directory_content_type(mut self, content_type: impl Into<String>) -> Self {
+        self.content_type = content_type.into();
+        self
     }
 
     pub fn extract_req(&mut self) -> Result<(Request, ohttp::ClientResponse), Error> {
         let url = self.ohttp_relay.clone();
-        let subdirectory = subdir_path_from_pubkey(&self.s.public_key());
+        let subdirectory = session_id_for(&self.s.public_key());
         let (body, ctx) = crate::v2::ohttp_encapsulate(
             &mut self.ohttp_keys,
             "POST",
             self.directory.as_str(),
             Some(subdirectory.as_bytes()),
+            Some(&self.content_type),
         )?;
         let req = Request { url, body };
         Ok((req, ctx))
@@ -66,15 +109,99 @@ impl Enroller {
 
         let ctx = Enrolled {
             directory: self.directory,
+            layout: self.layout,
+            content_type: self.content_type,
             ohttp_keys: self.ohttp_keys,
             ohttp_relay: self.ohttp_relay,
             s: self.s,
+            metrics: SessionMetrics::default(),
+            accept_raw_psbt: false,
+            require_v2_only: false,
+            rate_limit: None,
+            termination: None,
+            label: None,
+            accepted_versions: crate::supported_versions().to_vec(),
         };
         Ok(ctx)
     }
 }
 
-fn subdir_path_from_pubkey(pubkey: &bitcoin::secp256k1::PublicKey) -> String {
+fn check_directory_url(
+    directory: &Url,
+    ohttp_relay: &Url,
+    allow_insecure: bool,
+) -> Result<(), DirectoryUrlError> {
+    if directory.scheme() != "https" && !allow_insecure {
+        return Err(
+            InternalDirectoryUrlError::InsecureScheme(directory.scheme().to_string()).into()
+        );
+    }
+    let is_onion = |url: &Url| url.host_str().map_or(false, |host| host.ends_with(".onion"));
+    if is_onion(directory) && !is_onion(ohttp_relay) {
+        log::warn!(
+            "Directory is an onion service but the OHTTP relay is not, which may leak metadata"
+        );
+    }
+    Ok(())
+}
+
+const PSBT_MAGIC: &[u8] = b"psbt\xff";
+
+/// Split a payload carrying a raw binary PSBT into the PSBT and the padded query.
+///
+/// The query follows the last newline, since the binary PSBT may itself contain newlines.
+fn split_raw_psbt_payload(body: &[u8]) -> Option<(&[u8], &[u8])> {
+    if !body.starts_with(PSBT_MAGIC) {
+        return None;
+    }
+    let newline = body.iter().rposition(|byte| *byte == b'\n')?;
+    Some((&body[..newline], &body[newline + 1..]))
+}
+
+/// Strip the `\0` padding that follows the query of a v2 payload.
+///
+/// Any amount of trailing padding, including none, is accepted. A `\0` left once the padding
+/// is stripped means the payload was corrupted, and is rejected rather than parsed.
+fn trim_query_padding(padded_query: &str) -> Result<&str, InternalRequestError> {
+    let query = padded_query.trim_end_matches('\0');
+    if query.contains('\0') {
+        return Err(InternalRequestError::CorruptQueryPadding);
+    }
+    Ok(query)
+}
+
+/// The target of a redirect response, if the directory sent one
+fn redirect_location(response: &bhttp::Message) -> Option<&str> {
+    let status = response.control().status()?;
+    if !(300..400).contains(&status) {
+        return None;
+    }
+    std::str::from_utf8(response.header().get(b"location")?).ok()
+}
+
+/// How a directory lays out session resources under its URL
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DirectoryLayout {
+    /// `{directory}/{session_id}`, as served by payjoin-directory
+    #[default]
+    Flat,
+    /// `{directory}/v2/{session_id}`, for directories that namespace by protocol version
+    Versioned,
+}
+
+impl DirectoryLayout {
+    /// The path of the session with static key `pubkey`, relative to the directory
+    fn session_path(&self, pubkey: &bitcoin::secp256k1::PublicKey) -> String {
+        match self {
+            DirectoryLayout::Flat => session_id_for(pubkey),
+            DirectoryLayout::Versioned => format!("v2/{}", session_id_for(pubkey)),
+        }
+    }
+}
+
+/// The directory subdirectory, and thus session id, of the receiver with static key `pubkey`
+pub fn session_id_for(pubkey: &bitcoin::secp256k1::PublicKey) -> String {
     let pubkey = pubkey.serialize();
     let b64_config = base64::Config::new(base64::CharacterSet::UrlSafe, false);
     base64::encode_config(pubkey, b64_config)
@@ -83,9 +210,154 @@ fn subdir_path_from_pubkey(pubkey: &bitcoin::secp256k1::PublicKey) -> String {
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Enrolled {
     directory: url::Url,
+    layout: DirectoryLayout,
+    content_type: String,
     ohttp_keys: OhttpKeys,
     ohttp_relay: url::Url,
     s: bitcoin::secp256k1::KeyPair,
+    metrics: SessionMetrics,
+    accept_raw_psbt: bool,
+    require_v2_only: bool,
+    rate_limit: Option<RateLimit>,
+    termination: Option<TerminationReason>,
+    label: Option<String>,
+    accepted_versions: Vec<Version>,
+}
+
+/// The outcome of processing a directory response to an [`Enrolled`] session's poll
+#[derive(Debug)]
+pub enum Polled<P = UncheckedProposal> {
+    /// A proposal arrived and the session that polled for it is consumed
+    Proposal(P),
+    /// No proposal is available yet. Poll again with the returned session.
+    Pending(Box<Enrolled>),
+    /// The directory moved the session to the given directory URL. The returned session polls
+    /// the new location, so persist it again before polling.
+    Redirected(Box<Enrolled>, Url),
+    /// The response could not be processed, e.g. because it failed to decrypt or the proposal
+    /// was malformed or rate limited. The session is handed back to keep polling.
+    Rejected(Error, Box<Enrolled>),
+}
+
+impl<P> Polled<P> {
+    /// Map the proposal, leaving a pending session untouched
+    pub fn map<Q>(self, f: impl FnOnce(P) -> Q) -> Polled<Q> {
+        match self {
+            Polled::Proposal(proposal) => Polled::Proposal(f(proposal)),
+            Polled::Pending(enrolled) => Polled::Pending(enrolled),
+            Polled::Redirected(enrolled, directory) => Polled::Redirected(enrolled, directory),
+            Polled::Rejected(error, enrolled) => Polled::Rejected(error, enrolled),
+        }
+    }
+}
+
+/// Proposals received within the limit's interval, shared by clones of a session
+#[derive(Debug, Clone)]
+struct RateLimit {
+    max_proposals: usize,
+    interval: Duration,
+    recent: Arc<Mutex<VecDeque<Instant>>>,
+}
+
+impl RateLimit {
+    fn check(&self, now: Instant) -> Result<(), RequestError> {
+        let mut recent = self.recent.lock().unwrap_or_else(|e| e.into_inner());
+        while recent.front().map_or(false, |seen| now.duration_since(*seen) >= self.interval) {
+            recent.pop_front();
+        }
+        if recent.len() >= self.max_proposals {
+            return Err(InternalRequestError::RateLimited.into());
+        }
+        recent.push_back(now);
+        Ok(())
+    }
+}
+
+impl PartialEq for RateLimit {
+    fn eq(&self, other: &Self) -> bool {
+        self.max_proposals == other.max_proposals && self.interval == other.interval
+    }
+}
+
+impl Eq for RateLimit {}
+
+/// Counters describing how much an enrolled session has talked to the directory.
+///
+/// Metrics track the current process only and are not serialized with the session.
+#[derive(Debug, Clone)]
+pub struct SessionMetrics {
+    created: Instant,
+    poll_attempts: u64,
+    proposals_received: u64,
+    bytes_sent: u64,
+    bytes_received: u64,
+}
+
+impl Default for SessionMetrics {
+    fn default() -> Self {
+        Self {
+            created: Instant::now(),
+            poll_attempts: 0,
+            proposals_received: 0,
+            bytes_sent: 0,
+            bytes_received: 0,
+        }
+    }
+}
+
+impl PartialEq for SessionMetrics {
+    fn eq(&self, other: &Self) -> bool {
+        self.poll_attempts == other.poll_attempts
+            && self.proposals_received == other.proposals_received
+            && self.bytes_sent == other.bytes_sent
+            && self.bytes_received == other.bytes_received
+    }
+}
+
+impl Eq for SessionMetrics {}
+
+impl SessionMetrics {
+    /// The time elapsed since the session was created or restored in this process
+    pub fn elapsed(&self) -> Duration { self.created.elapsed() }
+
+    /// The number of requests extracted to poll the directory
+    pub fn poll_attempts(&self) -> u64 { self.poll_attempts }
+
+    /// The number of responses that contained a proposal
+    pub fn proposals_received(&self) -> u64 { self.proposals_received }
+
+    /// The number of encapsulated bytes sent to the relay
+    pub fn bytes_sent(&self) -> u64 { self.bytes_sent }
+
+    /// The number of encapsulated bytes received from the relay
+    pub fn bytes_received(&self) -> u64 { self.bytes_received }
+}
+
+/// Whether the directory holds a proposal for a session, as reported by
+/// [`Enrolled::process_status_res`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionStatus {
+    /// No proposal has arrived yet
+    Empty,
+    /// A proposal is waiting to be fetched with [`Enrolled::extract_req`]
+    ProposalWaiting,
+    /// The directory no longer holds the session
+    Gone,
+}
+
+/// Why an [`Enrolled`] session ended, recorded with [`Enrolled::mark_expired`] and its
+/// siblings so stored sessions keep an explicit terminal state.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TerminationReason {
+    /// The session outlived the payjoin URI it was created for
+    Expired,
+    /// A proposal was rejected with this error reply to the sender
+    Rejected(String),
+    /// A Payjoin Proposal was posted back to the sender
+    Completed,
+    /// The receiver abandoned the session
+    Cancelled,
 }
 
 impl Serialize for Enrolled {
@@ -93,11 +365,17 @@ impl Serialize for Enrolled {
     where
         S: Serializer,
     {
-        let mut state = serializer.serialize_struct("Enrolled", 4)?;
+        let mut state = serializer.serialize_struct("Enrolled", 10)?;
         state.serialize_field("directory", &self.directory.to_string())?;
+        state.serialize_field("directory_layout", &self.layout)?;
+        state.serialize_field("directory_content_type", &self.content_type)?;
         state.serialize_field("ohttp_keys", &self.ohttp_keys)?;
         state.serialize_field("ohttp_relay", &self.ohttp_relay.to_string())?;
         state.serialize_field("s", &self.s)?;
+        state.serialize_field("require_v2_only", &self.require_v2_only)?;
+        state.serialize_field("termination", &self.termination)?;
+        state.serialize_field("label", &self.label)?;
+        state.serialize_field("accepted_versions", &self.accepted_versions)?;
 
         state.end()
     }
@@ -117,9 +395,15 @@ impl<'de> Deserialize<'de> for Enrolled {
         #[serde(field_identifier, rename_all = "snake_case")]
         enum Field {
             Directory,
+            DirectoryLayout,
+            DirectoryContentType,
             OhttpKeys,
             OhttpRelay,
             S,
+            RequireV2Only,
+            Termination,
+            Label,
+            AcceptedVersions,
         }
 
         struct EnrolledVisitor;
@@ -136,9 +420,15 @@ impl<'de> Deserialize<'de> for Enrolled {
                 V: MapAccess<'de>,
             {
                 let mut directory = None;
+                let mut layout = None;
+                let mut content_type = None;
                 let mut ohttp_keys = None;
                 let mut ohttp_relay = None;
                 let mut s = None;
+                let mut require_v2_only = None;
+                let mut termination = None;
+                let mut label = None;
+                let mut accepted_versions = None;
                 while let Some(key) = map.next_key()? {
                     match key {
                         Field::Directory => {
@@ -148,6 +438,18 @@ impl<'de> Deserialize<'de> for Enrolled {
                             let url_str: String = map.next_value()?;
                             directory = Some(url::Url::parse(&url_str).map_err(de::Error::custom)?);
                         }
+                        Field::DirectoryLayout => {
+                            if layout.is_some() {
+                                return Err(de::Error::duplicate_field("directory_layout"));
+                            }
+                            layout = Some(map.next_value()?);
+                        }
+                        Field::DirectoryContentType => {
+                            if content_type.is_some() {
+                                return Err(de::Error::duplicate_field("directory_content_type"));
+                            }
+                            content_type = Some(map.next_value()?);
+                        }
                         Field::OhttpKeys => {
                             if ohttp_keys.is_some() {
                                 return Err(de::Error::duplicate_field("ohttp_keys"));
@@ -168,6 +470,30 @@ impl<'de> Deserialize<'de> for Enrolled {
                             }
                             s = Some(map.next_value()?);
                         }
+                        Field::RequireV2Only => {
+                            if require_v2_only.is_some() {
+                                return Err(de::Error::duplicate_field("require_v2_only"));
+                            }
+                            require_v2_only = Some(map.next_value()?);
+                        }
+                        Field::Termination => {
+                            if termination.is_some() {
+                                return Err(de::Error::duplicate_field("termination"));
+                            }
+                            termination = Some(map.next_value()?);
+                        }
+                        Field::Label => {
+                            if label.is_some() {
+                                return Err(de::Error::duplicate_field("label"));
+                            }
+                            label = Some(map.next_value()?);
+                        }
+                        Field::AcceptedVersions => {
+                            if accepted_versions.is_some() {
+                                return Err(de::Error::duplicate_field("accepted_versions"));
+                            }
+                            accepted_versions = Some(map.next_value()?);
+                        }
                     }
                 }
                 let directory = directory.ok_or_else(|| de::Error::missing_field("directory"))?;
@@ -176,80 +502,693 @@ impl<'de> Deserialize<'de> for Enrolled {
                 let ohttp_relay =
                     ohttp_relay.ok_or_else(|| de::Error::missing_field("ohttp_relay"))?;
                 let s = s.ok_or_else(|| de::Error::missing_field("s"))?;
-                Ok(Enrolled { directory, ohttp_keys, ohttp_relay, s })
+                Ok(Enrolled {
+                    directory,
+                    // Sessions stored before layouts were configurable are flat
+                    layout: layout.unwrap_or_default(),
+                    content_type: content_type
+                        .unwrap_or_else(|| V2_DIRECTORY_CONTENT_TYPE.to_owned()),
+                    ohttp_keys,
+                    ohttp_relay,
+                    s,
+                    metrics: Default::default(),
+                    accept_raw_psbt: false,
+                    require_v2_only: require_v2_only.unwrap_or_default(),
+                    rate_limit: None,
+                    termination: termination.unwrap_or_default(),
+                    label: label.unwrap_or_default(),
+                    // Sessions stored before the allowlist was persisted accept every version
+                    accepted_versions: accepted_versions
+                        .unwrap_or_else(|| crate::supported_versions().to_vec()),
+                })
             }
         }
 
-        const FIELDS: &[&str] = &["directory", "ohttp_keys", "ohttp_relay", "s"];
+        const FIELDS: &[&str] = &[
+            "directory",
+            "directory_layout",
+            "directory_content_type",
+            "ohttp_keys",
+            "ohttp_relay",
+            "s",
+            "require_v2_only",
+            "termination",
+            "label",
+            "accepted_versions",
+        ];
         deserializer.deserialize_struct("Enrolled", FIELDS, EnrolledVisitor)
     }
 }
 
+// A recovery code is the URL-safe base64 encoding of
+// version (1) | flags (1) | secret key (32) | directory | relay | [content type] | OHTTP KeyConfig
+// where each string is prefixed by its length as a big-endian u16.
+const RECOVERY_CODE_VERSION: u8 = 1;
+const RECOVERY_FLAG_VERSIONED_LAYOUT: u8 = 1 << 0;
+const RECOVERY_FLAG_REQUIRE_V2_ONLY: u8 = 1 << 1;
+// Set when a custom directory Content-Type follows the relay, prefixed like the URLs
+const RECOVERY_FLAG_CONTENT_TYPE: u8 = 1 << 2;
+// Set when the session does not accept v1 or v2 proposals, respectively
+const RECOVERY_FLAG_REJECT_V1: u8 = 1 << 3;
+const RECOVERY_FLAG_REJECT_V2: u8 = 1 << 4;
+
+fn recovery_flag_rejecting(version: Version) -> u8 {
+    match version {
+        Version::One => RECOVERY_FLAG_REJECT_V1,
+        Version::Two => RECOVERY_FLAG_REJECT_V2,
+    }
+}
+
+struct RecoveryCodeReader<'a>(&'a [u8]);
+
+impl<'a> RecoveryCodeReader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], InternalRecoveryCodeError> {
+        if self.0.len() < len {
+            return Err(InternalRecoveryCodeError::UnexpectedEnd);
+        }
+        let (taken, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    fn str(&mut self) -> Result<&'a str, InternalRecoveryCodeError> {
+        let len = u16::from_be_bytes([self.take(1)?[0], self.take(1)?[0]]);
+        std::str::from_utf8(self.take(len as usize)?).map_err(InternalRecoveryCodeError::Utf8)
+    }
+
+    fn url(&mut self) -> Result<Url, InternalRecoveryCodeError> {
+        Url::parse(self.str()?).map_err(InternalRecoveryCodeError::Url)
+    }
+}
+
+/// The non-secret parts of an [`Enrolled`] session, e.g. for a watch-only service tracking its
+/// status. Unlike a serialized [`Enrolled`], it carries no key material.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PublicSessionInfo {
+    /// The payjoin directory the session is enrolled with
+    pub directory: String,
+    /// The session's subdirectory identifier, derived from the receiver's public key
+    pub session_id: String,
+    /// The directory resource proposals for the session are posted to
+    pub session_url: String,
+    /// The key identifier of the directory's OHTTP key configuration
+    pub ohttp_key_id: Option<u8>,
+}
+
+/// A field that differs between two [`Enrolled`] sessions, as reported by [`Enrolled::diff`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FieldDiff {
+    pub field: &'static str,
+    pub left: String,
+    pub right: String,
+}
+
 impl Enrolled {
+    /// Report which persisted fields differ between two sessions.
+    ///
+    /// The receiver key pair is compared by its public key so that no secret material ends up
+    /// in the report. Metrics are not persisted and are not compared.
+    pub fn diff(&self, other: &Enrolled) -> Vec<FieldDiff> {
+        let fields = [
+            ("directory", self.directory.to_string(), other.directory.to_string()),
+            ("directory_layout", format!("{:?}", self.layout), format!("{:?}", other.layout)),
+            ("directory_content_type", self.content_type.clone(), other.content_type.clone()),
+            (
+                "ohttp_keys",
+                self.ohttp_keys.encode().map(base64::encode).unwrap_or_default(),
+                other.ohttp_keys.encode().map(base64::encode).unwrap_or_default(),
+            ),
+            ("ohttp_relay", self.ohttp_relay.to_string(), other.ohttp_relay.to_string()),
+            ("s", self.s.public_key().to_string(), other.s.public_key().to_string()),
+            (
+                "require_v2_only",
+                self.require_v2_only.to_string(),
+                other.require_v2_only.to_string(),
+            ),
+            ("termination", format!("{:?}", self.termination), format!("{:?}", other.termination)),
+            ("label", format!("{:?}", self.label), format!("{:?}", other.label)),
+            (
+                "accepted_versions",
+                format!("{:?}", self.accepted_versions),
+                format!("{:?}", other.accepted_versions),
+            ),
+        ];
+        fields
+            .into_iter()
+            .filter(|(_, left, right)| left != right)
+            .map(|(field, left, right)| FieldDiff { field, left, right })
+            .collect()
+    }
+
+    /// Why the session ended, or `None` while it is still live
+    pub fn termination(&self) -> Option<&TerminationReason> { self.termination.as_ref() }
+
+    /// Record that the session expired
+    pub fn mark_expired(&mut self) { self.termination = Some(TerminationReason::Expired) }
+
+    /// Record that a proposal was rejected with `error`, keeping the JSON reply sent back
+    pub fn mark_rejected(&mut self, error: &RequestError) {
+        self.termination = Some(TerminationReason::Rejected(error.to_string()))
+    }
+
+    /// Record that the Payjoin Proposal was posted back to the sender
+    pub fn mark_completed(&mut self) { self.termination = Some(TerminationReason::Completed) }
+
+    /// Record that the receiver abandoned the session
+    pub fn mark_cancelled(&mut self) { self.termination = Some(TerminationReason::Cancelled) }
+
+    /// The operator's label for this session, if any
+    pub fn label(&self) -> Option<&str> { self.label.as_deref() }
+
+    /// Attach a label, such as an invoice number, for local bookkeeping.
+    ///
+    /// The label is persisted with the session but never sent to the directory or the sender.
+    pub fn set_label(&mut self, label: impl Into<String>) { self.label = Some(label.into()) }
+
+    /// Check that `ohttp_relay` is not served from the directory's authority.
+    ///
+    /// A relay and directory on the same host and port can link the receiver's network
+    /// address to its session, defeating OHTTP. The error is advisory: callers may log it and
+    /// carry on, or refuse to poll through that relay.
+    pub fn check_relay_directory_separation(
+        &self,
+        ohttp_relay: &RelayUrl,
+    ) -> Result<(), DirectoryUrlError> {
+        let authority =
+            |url: &Url| (url.host_str().map(str::to_owned), url.port_or_known_default());
+        let relay = ohttp_relay.as_url();
+        if authority(relay) == authority(&self.directory) {
+            return Err(InternalDirectoryUrlError::SharedRelayAuthority(relay.to_string()).into());
+        }
+        Ok(())
+    }
+
+    /// The parts of the session safe to share with a watch-only service
+    pub fn public_session_info(&self) -> PublicSessionInfo {
+        PublicSessionInfo {
+            directory: self.directory.to_string(),
+            session_id: session_id_for(&self.s.public_key()),
+            session_url: self.fallback_target(),
+            ohttp_key_id: self.ohttp_keys.encode().ok().and_then(|config| config.first().copied()),
+        }
+    }
+
+    /// Rebuild an enrolled session from the receiver's static key pair.
+    ///
+    /// The subdirectory is derived from the public key, so a receiver that backed up only its
+    /// secret key and directory can resume polling for proposals without enrolling again.
+    pub fn recover(
+        s: bitcoin::secp256k1::KeyPair,
+        directory: DirectoryUrl,
+        ohttp_keys: OhttpKeys,
+        ohttp_relay: RelayUrl,
+    ) -> Self {
+        Enrolled {
+            directory: directory.into(),
+            layout: DirectoryLayout::default(),
+            content_type: V2_DIRECTORY_CONTENT_TYPE.to_owned(),
+            ohttp_keys,
+            ohttp_relay: ohttp_relay.into(),
+            s,
+            metrics: SessionMetrics::default(),
+            accept_raw_psbt: false,
+            require_v2_only: false,
+            rate_limit: None,
+            termination: None,
+            label: None,
+            accepted_versions: crate::supported_versions().to_vec(),
+        }
+    }
+
+    /// Serialize the session to CBOR, a more compact alternative to JSON for storage.
+    ///
+    /// The same fields are stored as with any other serde format, including the secret key.
+    #[cfg(feature = "cbor")]
+    pub fn to_cbor(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        ciborium::into_writer(self, &mut bytes).expect("serializing a session to a Vec");
+        bytes
+    }
+
+    /// Deserialize a session serialized with [`Enrolled::to_cbor`]
+    #[cfg(feature = "cbor")]
+    pub fn from_cbor(bytes: &[u8]) -> Result<Self, super::CborError> {
+        Ok(ciborium::from_reader(bytes)?)
+    }
+
+    /// Encode the session as a compact recovery code, e.g. to migrate it to another device
+    /// through a QR code.
+    ///
+    /// The code contains the receiver's secret key. Anyone who scans it can read and answer
+    /// the session's proposals, so only display it to the device taking over the session.
+    /// Metrics, the rate limit and whether raw PSBTs are accepted are not part of the code.
+    ///
+    /// Errors if a URL or the content type is longer than `u16::MAX` bytes.
+    pub fn to_recovery_code(&self) -> Result<String, RecoveryCodeError> {
+        let mut bytes = vec![RECOVERY_CODE_VERSION, self.recovery_flags()];
+        bytes.extend_from_slice(&self.s.secret_bytes());
+        let mut strs = vec![self.directory.as_str(), self.ohttp_relay.as_str()];
+        if self.content_type != V2_DIRECTORY_CONTENT_TYPE {
+            strs.push(&self.content_type);
+        }
+        for str in strs {
+            let len = u16::try_from(str.len())
+                .map_err(|_| InternalRecoveryCodeError::FieldTooLong(str.len()))?;
+            bytes.extend_from_slice(&len.to_be_bytes());
+            bytes.extend_from_slice(str.as_bytes());
+        }
+        bytes.extend(self.ohttp_keys.encode().map_err(InternalRecoveryCodeError::OhttpKeys)?);
+        Ok(base64::encode_config(bytes, base64::Config::new(base64::CharacterSet::UrlSafe, false)))
+    }
+
+    /// Decode a session encoded by [`Enrolled::to_recovery_code`]
+    pub fn from_recovery_code(code: &str) -> Result<Self, RecoveryCodeError> {
+        let bytes =
+            base64::decode_config(code, base64::Config::new(base64::CharacterSet::UrlSafe, false))
+                .map_err(InternalRecoveryCodeError::Base64)?;
+        let mut reader = RecoveryCodeReader(&bytes);
+        let version = reader.take(1)?[0];
+        if version != RECOVERY_CODE_VERSION {
+            return Err(InternalRecoveryCodeError::UnsupportedVersion(version).into());
+        }
+        let flags = reader.take(1)?[0];
+        let s = bitcoin::secp256k1::KeyPair::from_seckey_slice(
+            &bitcoin::secp256k1::Secp256k1::new(),
+            reader.take(32)?,
+        )
+        .map_err(InternalRecoveryCodeError::SecretKey)?;
+        let directory = reader.url()?;
+        let ohttp_relay = reader.url()?;
+        let content_type = match flags & RECOVERY_FLAG_CONTENT_TYPE {
+            0 => V2_DIRECTORY_CONTENT_TYPE,
+            _ => reader.str()?,
+        };
+        let ohttp_keys =
+            OhttpKeys::decode(reader.0).map_err(InternalRecoveryCodeError::OhttpKeys)?;
+        Ok(Enrolled {
+            directory,
+            layout: if flags & RECOVERY_FLAG_VERSIONED_LAYOUT != 0 {
+                DirectoryLayout::Versioned
+            } else {
+                DirectoryLayout::Flat
+            },
+            content_type: content_type.to_owned(),
+            ohttp_keys,
+            ohttp_relay,
+            s,
+            metrics: SessionMetrics::default(),
+            accept_raw_psbt: false,
+            require_v2_only: flags & RECOVERY_FLAG_REQUIRE_V2_ONLY != 0,
+            rate_limit: None,
+            termination: None,
+            label: None,
+            accepted_versions: crate::supported_versions()
+                .iter()
+                .filter(|version| flags & recovery_flag_rejecting(**version) == 0)
+                .copied()
+                .collect(),
+        })
+    }
+
+    fn recovery_flags(&self) -> u8 {
+        let mut flags = 0;
+        if self.layout == DirectoryLayout::Versioned {
+            flags |= RECOVERY_FLAG_VERSIONED_LAYOUT;
+        }
+        if self.require_v2_only {
+            flags |= RECOVERY_FLAG_REQUIRE_V2_ONLY;
+        }
+        if self.content_type != V2_DIRECTORY_CONTENT_TYPE {
+            flags |= RECOVERY_FLAG_CONTENT_TYPE;
+        }
+        for version in [Version::One, Version::Two] {
+            if !self.accepted_versions.contains(&version) {
+                flags |= recovery_flag_rejecting(version);
+            }
+        }
+        flags
+    }
+
+    /// Extract a request polling the directory for a proposal.
+    ///
+    /// To retry after a transport error, re-send the same [`Request`] and keep its
+    /// `ClientResponse`. Each call encapsulates a new request with its own `ClientResponse`.
     pub fn extract_req(&mut self) -> Result<(Request, ohttp::ClientResponse), Error> {
+        let relay = self.ohttp_relay.clone();
+        self.extract_req_to(relay)
+    }
+
+    /// Extract a request polling the directory through `ohttp_relay` instead of the session's
+    /// relay, e.g. to fail over when the relay is down.
+    ///
+    /// Each extracted request is encapsulated on its own and answered only through the
+    /// `ClientResponse` returned with it, whichever relay carries it. Switching relays leaves
+    /// earlier requests and their `ClientResponse`s valid, but a response must be processed
+    /// with the `ClientResponse` of the request it answers. The session's relay is unchanged.
+    pub fn extract_req_for_relay(
+        &mut self,
+        ohttp_relay: RelayUrl,
+    ) -> Result<(Request, ohttp::ClientResponse), Error> {
+        self.extract_req_to(ohttp_relay.into())
+    }
+
+    fn extract_req_to(&mut self, url: Url) -> Result<(Request, ohttp::ClientResponse), Error> {
         let (body, ohttp_ctx) = self.fallback_req_body()?;
-        let url = self.ohttp_relay.clone();
+        self.metrics.poll_attempts += 1;
+        self.metrics.bytes_sent += body.len() as u64;
         let req = Request { url, body };
         Ok((req, ohttp_ctx))
     }
 
+    /// Extract a request asking the directory whether a proposal is waiting, without
+    /// fetching it.
+    ///
+    /// The request is a `HEAD` of the session's subdirectory, the resource polled by
+    /// [`Enrolled::extract_req`], sent through the session's relay. A directory answers as it
+    /// would a poll but without the body, and without waiting for a proposal to arrive. Parse
+    /// the response with [`Enrolled::process_status_res`].
+    pub fn extract_status_req(&mut self) -> Result<(Request, ohttp::ClientResponse), Error> {
+        let target = self.fallback_target();
+        let (body, ohttp_ctx) =
+            crate::v2::ohttp_encapsulate(&mut self.ohttp_keys, "HEAD", &target, None, None)?;
+        self.metrics.bytes_sent += body.len() as u64;
+        Ok((Request { url: self.ohttp_relay.clone(), body }, ohttp_ctx))
+    }
+
+    /// Map the directory's answer to [`Enrolled::extract_status_req`] to a [`SessionStatus`].
+    ///
+    /// `200 OK` means a proposal is waiting, and `202 Accepted` or `204 No Content` that none is.
+    /// `404 Not Found` and `410 Gone` mean the directory no longer holds the session. Any
+    /// other status is an error.
+    pub fn process_status_res(
+        &mut self,
+        mut body: impl std::io::Read,
+        context: ohttp::ClientResponse,
+    ) -> Result<SessionStatus, Error> {
+        let mut buf = Vec::new();
+        body.read_to_end(&mut buf).map_err(InternalRequestError::Io)?;
+        self.metrics.bytes_received += buf.len() as u64;
+        let response = crate::v2::ohttp_decapsulate_response(context, &buf)?;
+        match response.control().status() {
+            Some(200) => Ok(SessionStatus::ProposalWaiting),
+            Some(202) | Some(204) => Ok(SessionStatus::Empty),
+            Some(404) | Some(410) => Ok(SessionStatus::Gone),
+            status => Err(DirectoryUrlError::from(InternalDirectoryUrlError::UnexpectedStatus(
+                status.unwrap_or_default(),
+            ))
+            .into()),
+        }
+    }
+
     /// The response can either be an UncheckedProposal or an ACCEPTED message
     /// indicating no UncheckedProposal is available yet.
-    pub fn process_res(
-        &self,
+    ///
+    /// The session is consumed once a proposal arrives, so it can't be polled again while the
+    /// proposal is processed. Otherwise it is handed back for the next poll, in
+    /// [`Polled::Pending`] or, along with the error, in [`Polled::Rejected`].
+    pub fn process_res(self, body: impl std::io::Read, context: ohttp::ClientResponse) -> Polled {
+        self.process_res_with_payload(body, context).map(|(proposal, _)| proposal)
+    }
+
+    /// Like [`Enrolled::process_res`], but also return the decrypted sender payload for
+    /// receivers that must archive the request they received.
+    ///
+    /// The payload is the sender's Original PSBT and query parameters in plaintext. It reveals
+    /// the sender's inputs and the payment, so store it with the same care as wallet data.
+    ///
+    /// If the directory redirects the session to another path on the same origin, the session's
+    /// directory is updated so the next request follows the redirect, and the session is
+    /// returned in [`Polled::Redirected`]. Redirects to another origin are rejected.
+    pub fn process_res_with_payload(
+        mut self,
+        body: impl std::io::Read,
+        context: ohttp::ClientResponse,
+    ) -> Polled<(UncheckedProposal, Vec<u8>)> {
+        let directory = self.directory.clone();
+        match self.process_poll_res(body, context) {
+            Ok(Some(proposal)) => Polled::Proposal(proposal),
+            Ok(None) if self.directory != directory => {
+                let directory = self.directory.clone();
+                Polled::Redirected(Box::new(self), directory)
+            }
+            Ok(None) => Polled::Pending(Box::new(self)),
+            Err(e) => Polled::Rejected(e, Box::new(self)),
+        }
+    }
+
+    fn process_poll_res(
+        &mut self,
         mut body: impl std::io::Read,
         context: ohttp::ClientResponse,
-    ) -> Result<Option<UncheckedProposal>, Error> {
+    ) -> Result<Option<(UncheckedProposal, Vec<u8>)>, Error> {
         let mut buf = Vec::new();
         let _ = body.read_to_end(&mut buf);
+        self.metrics.bytes_received += buf.len() as u64;
         log::trace!("decapsulating directory response");
-        let response = crate::v2::ohttp_decapsulate(context, &buf)?;
+        let response = crate::v2::ohttp_decapsulate_response(context, &buf)?;
+        if let Some(location) = redirect_location(&response) {
+            self.follow_redirect(location)?;
+            return Ok(None);
+        }
+        let response = response.content().to_vec();
         if response.is_empty() {
             log::debug!("response is empty");
             return Ok(None);
         }
+        if let Some(rate_limit) = &self.rate_limit {
+            rate_limit.check(Instant::now())?;
+        }
+        self.metrics.proposals_received += 1;
         // parse v1 or v2 proposal
+        let is_raw_psbt = self.accept_raw_psbt && response.starts_with(PSBT_MAGIC);
         match String::from_utf8(response.clone()) {
+            Err(_) if is_raw_psbt => {
+                let context = self.v2_context(None);
+                let proposal = UncheckedProposal::from_v2_payload(
+                    response.clone(),
+                    context,
+                    true,
+                    &self.accepted_versions,
+                )?;
+                Ok(Some((proposal, response)))
+            }
             Ok(proposal) => {
-                let context = V2Context {
-                    directory: self.directory.clone(),
-                    ohttp_keys: self.ohttp_keys.clone(),
-                    ohttp_relay: self.ohttp_relay.clone(),
-                    s: self.s,
-                    e: None,
-                };
+                let context = self.v2_context(None);
                 log::debug!("Received proposal: {}", proposal);
-                Ok(Some(UncheckedProposal::from_v2_payload(proposal.into_bytes(), context)?))
+                let payload = proposal.into_bytes();
+                let proposal = UncheckedProposal::from_v2_payload(
+                    payload.clone(),
+                    context,
+                    self.accept_raw_psbt,
+                    &self.accepted_versions,
+                )?;
+                Ok(Some((proposal, payload)))
             }
             Err(_) => {
-                let (proposal, e) = crate::v2::decrypt_message_a(&response, self.s.secret_key())?;
+                let (payload, e) = crate::v2::decrypt_message_a(&response, self.s.secret_key())?;
                 log::debug!("Some e: {}", e);
-                let context = V2Context {
-                    directory: self.directory.clone(),
-                    ohttp_keys: self.ohttp_keys.clone(),
-                    ohttp_relay: self.ohttp_relay.clone(),
-                    s: self.s,
-                    e: Some(e),
-                };
-                let proposal = UncheckedProposal::from_v2_payload(proposal, context)?;
-
-                Ok(Some(proposal))
+                let context = self.v2_context(Some(e));
+                let proposal = UncheckedProposal::from_v2_payload(
+                    payload.clone(),
+                    context,
+                    self.accept_raw_psbt,
+                    &self.accepted_versions,
+                )?;
+
+                Ok(Some((proposal, payload)))
             }
         }
     }
 
+    /// Parse a plaintext v1 request, the Original PSBT in base64 and its query, as if the
+    /// directory had relayed it to this session.
+    ///
+    /// This runs the same parsing as a polled proposal, including the query padding checks,
+    /// so receiver validation can be tested without a directory.
+    pub fn unchecked_from_v1_payload(
+        &self,
+        base64: &str,
+        query: &str,
+    ) -> Result<UncheckedProposal, Error> {
+        let payload = format!("{}\n{}", base64, query).into_bytes();
+        Ok(UncheckedProposal::from_v2_payload(
+            payload,
+            self.v2_context(None),
+            false,
+            &self.accepted_versions,
+        )?)
+    }
+
+    fn v2_context(&self, e: Option<bitcoin::secp256k1::PublicKey>) -> V2Context {
+        V2Context {
+            directory: self.directory.clone(),
+            layout: self.layout,
+            content_type: self.content_type.clone(),
+            require_v2_only: self.require_v2_only,
+            ohttp_keys: self.ohttp_keys.clone(),
+            ohttp_relay: self.ohttp_relay.clone(),
+            s: self.s,
+            e,
+        }
+    }
+
+    fn follow_redirect(&mut self, location: &str) -> Result<(), DirectoryUrlError> {
+        let unsupported = || InternalDirectoryUrlError::UnsupportedRedirect(location.to_string());
+        let target = self.directory.join(location).map_err(|_| unsupported())?;
+        if target.origin() != self.directory.origin() {
+            return Err(unsupported().into());
+        }
+        let directory = target
+            .as_str()
+            .strip_suffix(&self.layout.session_path(&self.s.public_key()))
+            .and_then(|directory| Url::parse(directory).ok())
+            .ok_or_else(unsupported)?;
+        log::info!("Directory moved from {} to {}", self.directory, directory);
+        self.directory = directory;
+        Ok(())
+    }
+
     fn fallback_req_body(&mut self) -> Result<(Vec<u8>, ohttp::ClientResponse), Error> {
         let fallback_target = format!("{}{}", &self.directory, self.fallback_target());
         log::trace!("Fallback request target: {}", fallback_target.as_str());
         let fallback_target = self.fallback_target();
-        Ok(crate::v2::ohttp_encapsulate(&mut self.ohttp_keys, "GET", &fallback_target, None)?)
+        Ok(crate::v2::ohttp_encapsulate(&mut self.ohttp_keys, "GET", &fallback_target, None, None)?)
+    }
+
+    pub fn metrics(&self) -> &SessionMetrics { &self.metrics }
+
+    /// The number of polling requests extracted since the session was created.
+    ///
+    /// Together with [`Enrolled::elapsed_since_creation`] this lets a wallet tell when a sender
+    /// may be having trouble. Both restart when a session is restored from storage.
+    pub fn polls_since_creation(&self) -> u32 {
+        u32::try_from(self.metrics.poll_attempts).unwrap_or(u32::MAX)
+    }
+
+    /// The time elapsed since the session was created
+    pub fn elapsed_since_creation(&self) -> Duration { self.metrics.elapsed() }
+
+    /// Refuse to answer plaintext v1 proposals relayed by the directory.
+    ///
+    /// Without a sender key, the Payjoin Proposal can only be returned in plaintext, where the
+    /// directory could replace its outputs. With this set, [`PayjoinProposal::extract_v2_req`]
+    /// errors instead. The setting is persisted with the session.
+    pub fn require_v2_only(&mut self, require: bool) { self.require_v2_only = require; }
+
+    /// Also accept payloads whose Original PSBT is raw binary rather than base64.
+    ///
+    /// BIP 77 requires base64, but some non-conforming senders post the raw PSBT. This is off by
+    /// default, and is not persisted with the session.
+    pub fn accept_raw_psbt(&mut self, accept: bool) { self.accept_raw_psbt = accept; }
+
+    /// Reject proposals whose protocol version is not one of `versions` with a
+    /// `version-unsupported` error.
+    ///
+    /// Encrypted proposals are version 2 and plaintext fallbacks version 1, whatever their `v`
+    /// parameter declares. Unlike [`Enrolled::require_v2_only`], which refuses to answer v1
+    /// proposals, this rejects them as they are parsed. All supported versions are accepted by
+    /// default. The setting is persisted with the session and carried by its recovery code.
+    pub fn accept_versions(&mut self, versions: &[Version]) {
+        self.accepted_versions = versions.to_vec();
+    }
+
+    /// Reject proposals beyond `max_proposals` within any `interval`, to throttle senders
+    /// probing a non-interactive receiver.
+    ///
+    /// Proposals are unlimited by default. Clones of the session share the limit, which is not
+    /// persisted with the session.
+    pub fn limit_proposal_rate(&mut self, max_proposals: usize, interval: Duration) {
+        self.rate_limit = Some(RateLimit {
+            max_proposals,
+            interval,
+            recent: Arc::new(Mutex::new(VecDeque::new())),
+        });
+    }
+
+    /// Inject an Original PSBT and sender query parameters as if they had arrived from the
+    /// directory, so the receiver typestate chain can be exercised offline.
+    #[cfg(feature = "simulate")]
+    pub fn simulate_proposal(
+        &self,
+        original_psbt: Psbt,
+        query: &str,
+    ) -> Result<UncheckedProposal, RequestError> {
+        let body = format!("{}\n{}", original_psbt, query);
+        UncheckedProposal::from_v2_payload(
+            body.into_bytes(),
+            self.v2_context(None),
+            self.accept_raw_psbt,
+            &self.accepted_versions,
+        )
+    }
+
+    /// Sign the payjoin URI's `address` and this session's directory and OHTTP keys with its
+    /// static key, so senders that pinned the receiver's public key can verify the URI out of
+    /// band.
+    pub fn attest_parameters(&self, address: &bitcoin::Address) -> ParameterAttestation {
+        ParameterAttestation::sign(
+            &self.s,
+            address.script_pubkey(),
+            self.directory.clone(),
+            self.ohttp_keys.clone(),
+        )
+    }
+
+    /// Start a payjoin URI that directs senders to this session's subdirectory
+    pub fn pj_uri_builder(&self, address: bitcoin::Address) -> PjUriBuilder {
+        let pj = Url::parse(&self.fallback_target())
+            .expect("directory url joined with a url-safe subdirectory is a valid url");
+        PjUriBuilder::new(address, pj, Some(self.ohttp_keys.clone()))
+    }
+
+    /// Build a payjoin URI for an invoice of `amount` paid to `address`.
+    ///
+    /// Returns None if `amount` is zero.
+    pub fn pj_uri_with_amount<'a>(
+        &self,
+        address: bitcoin::Address,
+        amount: Amount,
+    ) -> Option<PjUri<'a>> {
+        if amount == Amount::ZERO {
+            return None;
+        }
+        Some(self.pj_uri_builder(address).amount(amount).build())
+    }
+
+    /// Build a payjoin URI paid to `address` that asks the payer's wallet to display `label`
+    /// and `message`. They are percent-encoded as BIP 21 parameters.
+    pub fn pj_uri_with_metadata<'a>(
+        &self,
+        address: bitcoin::Address,
+        label: Option<String>,
+        message: Option<String>,
+        amount: Option<Amount>,
+    ) -> PjUri<'a> {
+        let mut builder = self.pj_uri_builder(address);
+        if let Some(label) = label {
+            builder = builder.label(label);
+        }
+        if let Some(message) = message {
+            builder = builder.message(message);
+        }
+        if let Some(amount) = amount {
+            builder = builder.amount(amount);
+        }
+        builder.build()
     }
 
     pub fn fallback_target(&self) -> String {
-        let pubkey = &self.s.public_key().serialize();
-        let b64_config = base64::Config::new(base64::CharacterSet::UrlSafe, false);
-        let pubkey_base64 = base64::encode_config(pubkey, b64_config);
-        format!("{}{}", &self.directory, pubkey_base64)
+        format!("{}{}", &self.directory, self.layout.session_path(&self.s.public_key()))
+    }
+
+    /// Address session resources with the directory's `layout`, e.g. after
+    /// [`Enrolled::recover`]ing a session enrolled with a versioned directory.
+    pub fn set_directory_layout(&mut self, layout: DirectoryLayout) { self.layout = layout; }
+
+    /// Post the payjoin proposal to the directory with `content_type` instead of
+    /// [`V2_DIRECTORY_CONTENT_TYPE`].
+    pub fn set_directory_content_type(&mut self, content_type: impl Into<String>) {
+        self.content_type = content_type.into();
     }
 }
 
@@ -269,18 +1208,39 @@ pub struct UncheckedProposal {
 }
 
 impl UncheckedProposal {
-    fn from_v2_payload(body: Vec<u8>, context: V2Context) -> Result<Self, RequestError> {
-        let buf_as_string = String::from_utf8(body).map_err(InternalRequestError::Utf8)?;
-        log::debug!("{}", &buf_as_string);
-        let (base64, padded_query) = buf_as_string.split_once('\n').unwrap_or_default();
-        let query = padded_query.trim_matches('\0');
-        log::trace!("Received query: {}, base64: {}", query, base64); // my guess is no \n so default is wrong
-        let unchecked_psbt = Psbt::from_str(base64).map_err(InternalRequestError::ParsePsbt)?;
+    fn from_v2_payload(
+        body: Vec<u8>,
+        context: V2Context,
+        accept_raw_psbt: bool,
+        accepted_versions: &[Version],
+    ) -> Result<Self, RequestError> {
+        let (unchecked_psbt, query) = match split_raw_psbt_payload(&body) {
+            Some((raw_psbt, padded_query)) if accept_raw_psbt => {
+                log::warn!("Sender posted a raw PSBT instead of base64");
+                let psbt = Psbt::deserialize(raw_psbt).map_err(InternalRequestError::Psbt)?;
+                let padded_query =
+                    String::from_utf8(padded_query.to_vec()).map_err(InternalRequestError::Utf8)?;
+                (psbt, trim_query_padding(&padded_query)?.to_string())
+            }
+            _ => {
+                let buf_as_string = String::from_utf8(body).map_err(InternalRequestError::Utf8)?;
+                log::debug!("{}", &buf_as_string);
+                let (base64, padded_query) = buf_as_string.split_once('\n').unwrap_or_default();
+                let query = trim_query_padding(padded_query)?;
+                log::trace!("Received query: {}, base64: {}", query, base64); // my guess is no \n so default is wrong
+                let psbt = Psbt::from_str(base64).map_err(InternalRequestError::ParsePsbt)?;
+                (psbt, query.to_string())
+            }
+        };
         let psbt = unchecked_psbt.validate().map_err(InternalRequestError::InconsistentPsbt)?;
         log::debug!("Received original psbt: {:?}", psbt);
         let params = Params::from_query_pairs(url::form_urlencoded::parse(query.as_bytes()))
             .map_err(InternalRequestError::SenderParams)?;
         log::debug!("Received request with params: {:?}", params);
+        // This crate's v2 senders declare `v=1`, so the transport decides the version
+        let version = if context.e.is_some() { Version::Two } else { Version::One };
+        super::check_version(version, accepted_versions)?;
+        super::check_params(&psbt, &params)?;
         let inner = super::UncheckedProposal { psbt, params };
         Ok(Self { inner, context })
     }
@@ -290,23 +1250,141 @@ impl UncheckedProposal {
         self.inner.extract_tx_to_schedule_broadcast()
     }
 
-    /// Call after checking that the Original PSBT can be broadcast.
-    ///
-    /// Receiver MUST check that the Original PSBT from the sender
-    /// can be broadcast, i.e. `testmempoolaccept` bitcoind rpc returns { "allowed": true,.. }
-    /// for `extract_tx_to_sheculed_broadcast()` before calling this method.
-    ///
-    /// Do this check if you generate bitcoin uri to receive Payjoin on sender request without manual human approval, like a payment processor.
-    /// Such so called "non-interactive" receivers are otherwise vulnerable to probing attacks.
-    /// If a sender can make requests at will, they can learn which bitcoin the receiver owns at no cost.
-    /// Broadcasting the Original PSBT after some time in the failure case makes incurs sender cost and prevents probing.
+    /// Check that every input of the Original PSBT carries a final scriptSig or witness.
+    pub fn require_sender_finalized(self) -> Result<Self, Error> {
+        let inner = self.inner.require_sender_finalized()?;
+        Ok(Self { inner, context: self.context })
+    }
+
+    /// Whether the proposal arrived encrypted to the receiver's key rather than as a
+    /// plaintext v1 fallback relayed by the directory
+    pub fn received_encrypted(&self) -> bool { self.context.e.is_some() }
+
+    /// Reject a proposal encrypted with an ephemeral key the receiver has seen before.
     ///
-    /// Call this after checking downstream.
-    pub fn check_broadcast_suitability(
+    /// A sender picks a fresh ephemeral key `e` for every session. One that reappears across
+    /// sessions links them to the same sender, whether through a buggy sender or an attempt to
+    /// correlate the receiver's sessions. Remember the keys of accepted proposals and let
+    /// `is_ephemeral_key_seen` look them up. Plaintext v1 fallback proposals carry no
+    /// ephemeral key and pass. Return original-psbt-rejected otherwise.
+    pub fn require_unseen_ephemeral_key(
         self,
-        min_fee_rate: Option<FeeRate>,
-        can_broadcast: impl Fn(&bitcoin::Transaction) -> Result<bool, Error>,
-    ) -> Result<MaybeInputsOwned, Error> {
+        is_ephemeral_key_seen: impl Fn(&bitcoin::secp256k1::PublicKey) -> Result<bool, Error>,
+    ) -> Result<Self, Error> {
+        if let Some(e) = &self.context.e {
+            match is_ephemeral_key_seen(e) {
+                Ok(false) => (),
+                Ok(true) =>
+                    return Err(RequestError::from(InternalRequestError::EphemeralKeyReused).into()),
+                Err(e) => return Err(Error::Server(e.into())),
+            }
+        }
+        Ok(self)
+    }
+
+    /// Check that every input of the Original PSBT spends an output that still exists.
+    pub fn require_unspent_inputs(
+        self,
+        is_unspent: impl Fn(&OutPoint) -> Result<bool, Error>,
+    ) -> Result<Self, Error> {
+        let inner = self.inner.require_unspent_inputs(is_unspent)?;
+        Ok(Self { inner, context: self.context })
+    }
+
+    /// Reject an Original PSBT that carries metadata about the sender's wallet.
+    pub fn require_no_leaky_metadata(self) -> Result<Self, Error> {
+        let inner = self.inner.require_no_leaky_metadata()?;
+        Ok(Self { inner, context: self.context })
+    }
+
+    /// Remove global xpubs and key origins from the Original PSBT.
+    pub fn strip_leaky_metadata(self) -> Self {
+        Self { inner: self.inner.strip_leaky_metadata(), context: self.context }
+    }
+
+    /// Check that the Original PSBT transaction is not confirmed yet.
+    pub fn require_unconfirmed(
+        self,
+        is_confirmed: impl Fn(&bitcoin::Txid) -> Result<bool, Error>,
+    ) -> Result<Self, Error> {
+        let inner = self.inner.require_unconfirmed(is_confirmed)?;
+        Ok(Self { inner, context: self.context })
+    }
+
+    /// Reject an Original PSBT that does not appear to pay change back to the sender.
+    pub fn require_sender_change(self) -> Result<Self, Error> {
+        let inner = self.inner.require_sender_change()?;
+        Ok(Self { inner, context: self.context })
+    }
+
+    /// Reject an Original PSBT whose fee rate exceeds `max_fee_rate`.
+    pub fn limit_original_fee_rate(self, max_fee_rate: bitcoin::FeeRate) -> Result<Self, Error> {
+        let inner = self.inner.limit_original_fee_rate(max_fee_rate)?;
+        Ok(Self { inner, context: self.context })
+    }
+
+    /// Reject an Original PSBT whose transaction weighs more than `max_weight`.
+    pub fn limit_original_weight(self, max_weight: bitcoin::Weight) -> Result<Self, Error> {
+        let inner = self.inner.limit_original_weight(max_weight)?;
+        Ok(Self { inner, context: self.context })
+    }
+
+    /// The outputs of the Original PSBT, including the payment and any sender change
+    pub fn original_outputs(&self) -> Vec<TxOut> { self.inner.original_outputs() }
+
+    /// A canonical hash of the Original PSBT transaction for deduplicating resubmissions
+    pub fn original_psbt_fingerprint(&self) -> bitcoin::hashes::sha256::Hash {
+        self.inner.original_psbt_fingerprint()
+    }
+
+    /// The sender's `maxadditionalfeecontribution`
+    pub fn additional_fee_contribution_limit(&self) -> Option<bitcoin::Amount> {
+        self.inner.additional_fee_contribution_limit()
+    }
+
+    /// The sender's `additionalfeeoutputindex`
+    pub fn additional_fee_output_index(&self) -> Option<usize> {
+        self.inner.additional_fee_output_index()
+    }
+
+    /// The sender's `minfeerate`, zero if not declared
+    pub fn sender_min_fee_rate(&self) -> bitcoin::FeeRate { self.inner.sender_min_fee_rate() }
+
+    /// The sender's inputs with the previous outputs they spend
+    pub fn sender_inputs(&self) -> Result<Vec<(OutPoint, TxOut)>, Error> {
+        self.inner.sender_inputs()
+    }
+
+    pub fn original_inputs_uniform_type(&self) -> Option<bitcoin::AddressType> {
+        self.inner.original_inputs_uniform_type()
+    }
+
+    /// Summarize the Original PSBT for a confirmation screen
+    pub fn as_display_payload(&self) -> super::DisplayInfo { self.inner.as_display_payload() }
+
+    /// Advisory heuristic that flags an Original PSBT which already looks like a payjoin.
+    ///
+    /// See [`super::UncheckedProposal::looks_like_payjoin`] for the heuristic and its
+    /// false-positive profile.
+    pub fn looks_like_payjoin(&self) -> bool { self.inner.looks_like_payjoin() }
+
+    /// Call after checking that the Original PSBT can be broadcast.
+    ///
+    /// Receiver MUST check that the Original PSBT from the sender
+    /// can be broadcast, i.e. `testmempoolaccept` bitcoind rpc returns { "allowed": true,.. }
+    /// for `extract_tx_to_sheculed_broadcast()` before calling this method.
+    ///
+    /// Do this check if you generate bitcoin uri to receive Payjoin on sender request without manual human approval, like a payment processor.
+    /// Such so called "non-interactive" receivers are otherwise vulnerable to probing attacks.
+    /// If a sender can make requests at will, they can learn which bitcoin the receiver owns at no cost.
+    /// Broadcasting the Original PSBT after some time in the failure case makes incurs sender cost and prevents probing.
+    ///
+    /// Call this after checking downstream.
+    pub fn check_broadcast_suitability(
+        self,
+        min_fee_rate: Option<FeeRate>,
+        can_broadcast: impl Fn(&bitcoin::Transaction) -> Result<bool, Error>,
+    ) -> Result<MaybeInputsOwned, Error> {
         let inner = self.inner.check_broadcast_suitability(min_fee_rate, can_broadcast)?;
         Ok(MaybeInputsOwned { inner, context: self.context })
     }
@@ -435,23 +1513,140 @@ impl ProvisionalProposal {
         self.inner.try_preserving_privacy(candidate_inputs)
     }
 
+    pub fn validate_inputs(
+        &self,
+        inputs: &[(TxOut, OutPoint)],
+    ) -> Result<(), InputContributionError> {
+        self.inner.validate_inputs(inputs)
+    }
+
+    pub fn try_preserving_privacy_limited(
+        &self,
+        candidate_inputs: HashMap<Amount, OutPoint>,
+        max_inputs: usize,
+    ) -> Result<OutPoint, SelectionError> {
+        self.inner.try_preserving_privacy_limited(candidate_inputs, max_inputs)
+    }
+
+    pub fn select_inputs(
+        &self,
+        selector: &impl CoinSelector,
+        candidates: &[(TxOut, OutPoint)],
+    ) -> Result<Vec<(TxOut, OutPoint)>, SelectionError> {
+        self.inner.select_inputs(selector, candidates)
+    }
+
+    pub fn selection_context(&self) -> SelectionContext { self.inner.selection_context() }
+
+    /// Advisory guess at which outputs the sender keeps as change.
+    ///
+    /// See [`super::ProvisionalProposal::likely_sender_change`] for the heuristics used.
+    pub fn likely_sender_change(&self) -> Vec<usize> { self.inner.likely_sender_change() }
+
+    pub fn receiver_output_is_largest(&self) -> bool { self.inner.receiver_output_is_largest() }
+
     pub fn contribute_witness_input(&mut self, txo: TxOut, outpoint: OutPoint) {
         self.inner.contribute_witness_input(txo, outpoint)
     }
 
+    pub fn contribute_witness_inputs_filtered(
+        &mut self,
+        inputs: impl IntoIterator<Item = (TxOut, OutPoint)>,
+        policy: impl Fn(&OutPoint, &TxOut) -> Result<bool, Error>,
+        on_veto: super::VetoHandling,
+    ) -> Result<usize, Error> {
+        self.inner.contribute_witness_inputs_filtered(inputs, policy, on_veto)
+    }
+
+    pub fn avoid_recent_payjoin_outputs(
+        &self,
+        candidates: &[(TxOut, OutPoint)],
+        is_recent_payjoin_output: impl Fn(&OutPoint) -> Result<bool, Error>,
+    ) -> Result<Vec<(TxOut, OutPoint)>, Error> {
+        self.inner.avoid_recent_payjoin_outputs(candidates, is_recent_payjoin_output)
+    }
+
+    pub fn contribute_confirmed_witness_inputs(
+        &mut self,
+        inputs: impl IntoIterator<Item = (TxOut, OutPoint)>,
+        is_confirmed: impl Fn(&OutPoint) -> Result<bool, Error>,
+    ) -> Result<usize, Error> {
+        self.inner.contribute_confirmed_witness_inputs(inputs, is_confirmed)
+    }
+
     pub fn contribute_non_witness_input(&mut self, tx: bitcoin::Transaction, outpoint: OutPoint) {
         self.inner.contribute_non_witness_input(tx, outpoint)
     }
 
+    pub fn contributed_inputs(
+        &self,
+    ) -> impl '_ + Iterator<Item = (&bitcoin::TxIn, &bitcoin::psbt::Input)> {
+        self.inner.contributed_inputs()
+    }
+
+    pub fn undo_last_contribution(self) -> Result<Self, InputContributionError> {
+        let inner = self.inner.undo_last_contribution()?;
+        Ok(Self { inner, context: self.context })
+    }
+
     pub fn is_output_substitution_disabled(&self) -> bool {
         self.inner.is_output_substitution_disabled()
     }
 
     /// Just replace an output address with
-    pub fn substitute_output_address(&mut self, substitute_address: bitcoin::Address) {
+    pub fn substitute_output_address(
+        &mut self,
+        substitute_address: bitcoin::Address,
+    ) -> Result<(), OutputSubstitutionError> {
         self.inner.substitute_output_address(substitute_address)
     }
 
+    /// Make sure a receiver output paying `script` holds at least `min_value` for CPFP.
+    pub fn ensure_cpfp_anchor(
+        self,
+        min_value: Amount,
+        script: &Script,
+    ) -> Result<Self, OutputSubstitutionError> {
+        let inner = self.inner.ensure_cpfp_anchor(min_value, script)?;
+        Ok(Self { inner, context: self.context })
+    }
+
+    pub fn set_fee_subtraction_strategy(
+        &mut self,
+        strategy: super::FeeSubtractionStrategy,
+    ) -> Result<(), OutputSubstitutionError> {
+        self.inner.set_fee_subtraction_strategy(strategy)
+    }
+
+    pub fn projected_output_count(&self) -> usize { self.inner.projected_output_count() }
+
+    pub fn additional_fee_contribution_limit(&self) -> Option<Amount> {
+        self.inner.additional_fee_contribution_limit()
+    }
+
+    pub fn min_input_for_fee_target(&self, target: FeeRate) -> Result<Amount, Error> {
+        self.inner.min_input_for_fee_target(target)
+    }
+
+    pub fn per_input_fee_breakdown(
+        &self,
+        min_feerate_sat_per_vb: Option<FeeRate>,
+    ) -> Result<Vec<(OutPoint, Amount)>, Error> {
+        self.inner.per_input_fee_breakdown(min_feerate_sat_per_vb)
+    }
+
+    pub fn set_min_relay_fee_rate(&mut self, min_relay_fee_rate: FeeRate) {
+        self.inner.set_min_relay_fee_rate(min_relay_fee_rate)
+    }
+
+    pub fn detect_receiver_address_reuse(&self) -> Vec<bitcoin::ScriptBuf> {
+        self.inner.detect_receiver_address_reuse()
+    }
+
+    pub fn reject_receiver_address_reuse(&mut self, reject: bool) {
+        self.inner.reject_receiver_address_reuse(reject)
+    }
+
     pub fn finalize_proposal(
         self,
         wallet_process_psbt: impl Fn(&Psbt) -> Result<Psbt, Error>,
@@ -460,6 +1655,35 @@ impl ProvisionalProposal {
         let inner = self.inner.finalize_proposal(wallet_process_psbt, min_feerate_sat_per_vb)?;
         Ok(PayjoinProposal { inner, context: self.context })
     }
+
+    pub fn finalize_proposal_with_approval(
+        self,
+        approve: impl FnOnce(&super::ProposalSummary) -> Result<bool, Error>,
+        wallet_process_psbt: impl Fn(&Psbt) -> Result<Psbt, Error>,
+        min_feerate_sat_per_vb: Option<FeeRate>,
+        max_effective_fee_rate: Option<FeeRate>,
+    ) -> Result<PayjoinProposal, Error> {
+        let inner = self.inner.finalize_proposal_with_approval(
+            approve,
+            wallet_process_psbt,
+            min_feerate_sat_per_vb,
+            max_effective_fee_rate,
+        )?;
+        Ok(PayjoinProposal { inner, context: self.context })
+    }
+
+    pub fn preview_finalized_psbt(
+        &self,
+        wallet_process_psbt: impl Fn(&Psbt) -> Result<Psbt, Error>,
+        min_feerate_sat_per_vb: Option<FeeRate>,
+        max_effective_fee_rate: Option<FeeRate>,
+    ) -> Result<Psbt, Error> {
+        self.inner.preview_finalized_psbt(
+            wallet_process_psbt,
+            min_feerate_sat_per_vb,
+            max_effective_fee_rate,
+        )
+    }
 }
 
 /// A mutable checked proposal that the receiver may contribute inputs to to make a payjoin.
@@ -482,9 +1706,41 @@ impl PayjoinProposal {
 
     pub fn psbt(&self) -> &Psbt { self.inner.psbt() }
 
+    pub fn effective_fee_rate(&self) -> Result<FeeRate, Error> { self.inner.effective_fee_rate() }
+
+    pub fn to_psbt_bytes(&self) -> Vec<u8> { self.inner.to_psbt_bytes() }
+
+    pub fn verify_against_committed(&self, tx: &bitcoin::Transaction) -> Result<(), Error> {
+        self.inner.verify_against_committed(tx)
+    }
+
+    pub fn privacy_metrics(&self) -> super::PrivacyReport { self.inner.privacy_metrics() }
+
+    pub fn interpretation_count(&self) -> u64 { self.inner.interpretation_count() }
+
+    pub fn to_psbt_base64(&self) -> String { self.inner.to_psbt_base64() }
+
+    pub fn contributed_input_count(&self) -> usize { self.inner.contributed_input_count() }
+
+    pub fn contributed_input_value(&self) -> Amount { self.inner.contributed_input_value() }
+
     pub fn extract_v1_req(&self) -> String { base64::encode(self.inner.payjoin_psbt.serialize()) }
 
     #[cfg(feature = "v2")]
+    /// The directory resource [`PayjoinProposal::extract_v2_req`] posts the proposal to.
+    ///
+    /// It only depends on the session, so a receiver restarting after posting but before
+    /// processing the response can look the resource up to decide whether to post again.
+    pub fn delivery_target(&self) -> Url {
+        let target = format!(
+            "{}{}/payjoin",
+            self.context.directory.as_str(),
+            self.context.layout.session_path(&self.context.s.public_key())
+        );
+        // The session path is URL-safe base64, so appending it keeps the directory URL valid
+        Url::parse(&target).expect("directory URL with session path is a valid URL")
+    }
+
     pub fn extract_v2_req(&mut self) -> Result<(Request, ohttp::ClientResponse), Error> {
         let body = match self.context.e {
             Some(e) => {
@@ -492,19 +1748,20 @@ impl PayjoinProposal {
                 log::debug!("THERE IS AN e: {}", e);
                 crate::v2::encrypt_message_b(&mut payjoin_bytes, e)
             }
+            None if self.context.require_v2_only =>
+                return Err(
+                    RequestError::from(InternalRequestError::PlaintextFallbackRefused).into()
+                ),
             None => Ok(self.extract_v1_req().as_bytes().to_vec()),
         }?;
-        let post_payjoin_target = format!(
-            "{}{}/payjoin",
-            self.context.directory.as_str(),
-            subdir_path_from_pubkey(&self.context.s.public_key())
-        );
+        let post_payjoin_target = self.delivery_target();
         log::debug!("Payjoin post target: {}", post_payjoin_target.as_str());
         let (body, ctx) = crate::v2::ohttp_encapsulate(
             &mut self.context.ohttp_keys,
             "POST",
-            &post_payjoin_target,
+            post_payjoin_target.as_str(),
             Some(&body),
+            Some(&self.context.content_type),
         )?;
         let url = self.context.ohttp_relay.clone();
         let req = Request { url, body };
@@ -524,33 +1781,927 @@ impl PayjoinProposal {
     }
 }
 
+/// Collect the inputs every proposal in `proposals` spends.
+///
+/// A receiver running several sessions at once can check a candidate input against this set
+/// before contributing it, so that no UTXO ends up in two in-flight payjoins. Sender inputs
+/// are included, since each proposal spends all of its inputs.
+pub fn aggregate_locked_utxos<'a>(
+    proposals: impl IntoIterator<Item = &'a PayjoinProposal>,
+) -> HashSet<OutPoint> {
+    proposals.into_iter().flat_map(|proposal| proposal.utxos_to_be_locked().copied()).collect()
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
 
-    #[test]
-    #[cfg(feature = "v2")]
-    fn enrolled_ser_de_roundtrip() {
+    // OriginalPSBT Test Vector from BIP
+    const ORIGINAL_PSBT: &str = "cHNidP8BAHMCAAAAAY8nutGgJdyYGXWiBEb45Hoe9lWGbkxh/6bNiOJdCDuDAAAAAAD+////AtyVuAUAAAAAF6kUHehJ8GnSdBUOOv6ujXLrWmsJRDCHgIQeAAAAAAAXqRR3QJbbz0hnQ8IvQ0fptGn+votneofTAAAAAAEBIKgb1wUAAAAAF6kU3k4ekGHKWRNbA1rV5tR5kEVDVNCHAQcXFgAUx4pFclNVgo1WWAdN1SYNX8tphTABCGsCRzBEAiB8Q+A6dep+Rz92vhy26lT0AjZn4PRLi8Bf9qoB/CMk0wIgP/Rj2PWZ3gEjUkTlhDRNAQ0gXwTO7t9n+V14pZ6oljUBIQMVmsAaoNWHVMS02LfTSe0e388LNitPa1UQZyOihY+FFgABABYAFEb2Giu6c4KO5YW0pfw3lGp9jMUUAAA=";
+
+    fn test_key_config(key_id: u8) -> ohttp::KeyConfig {
         use ohttp::hpke::{Aead, Kdf, Kem};
-        use ohttp::{KeyId, SymmetricSuite};
-        const KEY_ID: KeyId = 1;
-        const KEM: Kem = Kem::X25519Sha256;
+        use ohttp::SymmetricSuite;
         const SYMMETRIC: &[SymmetricSuite] =
             &[ohttp::SymmetricSuite::new(Kdf::HkdfSha256, Aead::ChaCha20Poly1305)];
 
+        ohttp::KeyConfig::new(key_id, Kem::X25519Sha256, Vec::from(SYMMETRIC)).unwrap()
+    }
+
+    fn test_keypair(byte: u8) -> bitcoin::secp256k1::KeyPair {
+        bitcoin::secp256k1::KeyPair::from_seckey_slice(
+            &bitcoin::secp256k1::Secp256k1::new(),
+            &[byte; 32],
+        )
+        .unwrap()
+    }
+
+    /// A session on `https://directory.com` reached through `https://relay.com`
+    fn test_enrolled() -> Enrolled {
+        Enrolled::recover(
+            test_keypair(1),
+            DirectoryUrl::try_from("https://directory.com").unwrap(),
+            OhttpKeys(test_key_config(1)),
+            RelayUrl::try_from("https://relay.com").unwrap(),
+        )
+    }
+
+    /// The context of a proposal received by [`test_enrolled`], from sender key `e`
+    fn test_context(e: Option<bitcoin::secp256k1::PublicKey>) -> V2Context {
+        V2Context {
+            directory: url::Url::parse("https://directory.com").unwrap(),
+            layout: DirectoryLayout::Flat,
+            content_type: V2_DIRECTORY_CONTENT_TYPE.to_owned(),
+            require_v2_only: false,
+            ohttp_keys: OhttpKeys(test_key_config(1)),
+            ohttp_relay: url::Url::parse("https://relay.com").unwrap(),
+            s: test_keypair(1),
+            e,
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "v2")]
+    fn enrolled_ser_de_roundtrip() {
         let enrolled = Enrolled {
             directory: url::Url::parse("https://directory.com").unwrap(),
-            ohttp_keys: OhttpKeys(
-                ohttp::KeyConfig::new(KEY_ID, KEM, Vec::from(SYMMETRIC)).unwrap(),
-            ),
+            layout: DirectoryLayout::Versioned,
+            content_type: V2_DIRECTORY_CONTENT_TYPE.to_owned(),
+            ohttp_keys: OhttpKeys(test_key_config(1)),
             ohttp_relay: url::Url::parse("https://relay.com").unwrap(),
-            s: bitcoin::secp256k1::KeyPair::from_secret_key(
-                &bitcoin::secp256k1::Secp256k1::new(),
-                &bitcoin::secp256k1::SecretKey::from_slice(&[1; 32]).unwrap(),
-            ),
+            s: test_keypair(1),
+            metrics: SessionMetrics::default(),
+            accept_raw_psbt: false,
+            require_v2_only: false,
+            rate_limit: None,
+            termination: None,
+            label: None,
+            accepted_versions: crate::supported_versions().to_vec(),
         };
         let serialized = serde_json::to_string(&enrolled).unwrap();
         let deserialized: Enrolled = serde_json::from_str(&serialized).unwrap();
         assert_eq!(enrolled, deserialized);
     }
+
+    #[test]
+    #[cfg(feature = "cbor")]
+    fn enrolled_cbor_roundtrip_matches_json() {
+        let mut enrolled = test_enrolled();
+        enrolled.mark_completed();
+
+        let cbor = enrolled.to_cbor();
+        let from_cbor = Enrolled::from_cbor(&cbor).unwrap();
+        let json = serde_json::to_string(&enrolled).unwrap();
+        let from_json: Enrolled = serde_json::from_str(&json).unwrap();
+        assert_eq!(from_cbor, from_json);
+        assert_eq!(from_cbor.s, enrolled.s);
+        assert!(cbor.len() < json.len());
+        assert!(Enrolled::from_cbor(&cbor[..cbor.len() - 1]).is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "v2")]
+    fn termination_reason_is_persisted() {
+        let mut enrolled = test_enrolled();
+        assert_eq!(enrolled.termination(), None);
+        // Sessions stored before terminations were recorded are live
+        let mut stored = serde_json::to_value(&enrolled).unwrap();
+        stored.as_object_mut().unwrap().remove("termination");
+        assert_eq!(serde_json::from_value::<Enrolled>(stored).unwrap().termination(), None);
+
+        let error = RequestError::from(InternalRequestError::MissingPayment);
+        enrolled.mark_rejected(&error);
+        assert_eq!(enrolled.termination(), Some(&TerminationReason::Rejected(error.to_string())));
+        let serialized = serde_json::to_string(&enrolled).unwrap();
+        assert_eq!(serde_json::from_str::<Enrolled>(&serialized).unwrap(), enrolled);
+
+        enrolled.mark_completed();
+        assert_eq!(enrolled.termination(), Some(&TerminationReason::Completed));
+    }
+
+    #[test]
+    #[cfg(feature = "v2")]
+    fn label_is_persisted() {
+        let mut enrolled = test_enrolled();
+        assert_eq!(enrolled.label(), None);
+        // Sessions stored before labels existed are unlabeled
+        let mut stored = serde_json::to_value(&enrolled).unwrap();
+        stored.as_object_mut().unwrap().remove("label");
+        assert_eq!(serde_json::from_value::<Enrolled>(stored).unwrap().label(), None);
+
+        let unlabeled = enrolled.clone();
+        enrolled.set_label("invoice-42");
+        let serialized = serde_json::to_string(&enrolled).unwrap();
+        let deserialized: Enrolled = serde_json::from_str(&serialized).unwrap();
+        assert_eq!(deserialized.label(), Some("invoice-42"));
+        assert_eq!(deserialized, enrolled);
+        assert_eq!(unlabeled.diff(&enrolled)[0].field, "label");
+    }
+
+    #[test]
+    #[cfg(feature = "v2")]
+    fn directory_layout_shapes_session_paths() {
+        let mut enrolled = test_enrolled();
+        let session_id = session_id_for(&enrolled.s.public_key());
+        assert_eq!(enrolled.fallback_target(), format!("https://directory.com/{}", session_id));
+
+        enrolled.set_directory_layout(DirectoryLayout::Versioned);
+        assert_eq!(enrolled.fallback_target(), format!("https://directory.com/v2/{}", session_id));
+
+        // Sessions stored without a layout stay flat
+        let mut stored: serde_json::Value = serde_json::to_value(&enrolled).unwrap();
+        assert_eq!(stored["directory_layout"], "versioned");
+        stored.as_object_mut().unwrap().remove("directory_layout");
+        let restored: Enrolled = serde_json::from_value(stored).unwrap();
+        assert_eq!(restored.fallback_target(), format!("https://directory.com/{}", session_id));
+    }
+
+    #[test]
+    #[cfg(feature = "v2")]
+    fn enrolled_diff_reports_changed_fields() {
+        let enrolled = test_enrolled();
+        assert!(enrolled.diff(&enrolled.clone()).is_empty());
+
+        let mut other = enrolled.clone();
+        other.ohttp_relay = url::Url::parse("https://other-relay.com").unwrap();
+        other.s = test_keypair(2);
+        let diff = enrolled.diff(&other);
+        assert_eq!(diff.iter().map(|d| d.field).collect::<Vec<_>>(), vec!["ohttp_relay", "s"]);
+        let secret = enrolled.s.display_secret().to_string();
+        assert!(diff.iter().all(|d| !d.left.contains(&secret)));
+
+        let mut v2_only = enrolled.clone();
+        v2_only.require_v2_only(true);
+        let diff = enrolled.diff(&v2_only);
+        assert_eq!(
+            diff,
+            vec![FieldDiff {
+                field: "require_v2_only",
+                left: "false".to_owned(),
+                right: "true".to_owned()
+            }]
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "v2")]
+    fn enrolled_recovers_from_secret_key() {
+        let secp = bitcoin::secp256k1::Secp256k1::new();
+        let directory = DirectoryUrl::try_from("https://directory.com").unwrap();
+        let ohttp_keys = OhttpKeys(test_key_config(1));
+        let ohttp_relay = RelayUrl::try_from("https://relay.com").unwrap();
+        let enroller = Enroller::from_directory_config(
+            directory.clone(),
+            ohttp_keys.clone(),
+            ohttp_relay.clone(),
+            false,
+        )
+        .unwrap();
+        let enrolled = Enrolled {
+            directory: enroller.directory,
+            layout: enroller.layout,
+            content_type: enroller.content_type,
+            ohttp_keys: enroller.ohttp_keys,
+            ohttp_relay: enroller.ohttp_relay,
+            s: enroller.s,
+            metrics: SessionMetrics::default(),
+            accept_raw_psbt: false,
+            require_v2_only: false,
+            rate_limit: None,
+            termination: None,
+            label: None,
+            accepted_versions: crate::supported_versions().to_vec(),
+        };
+
+        // Only the secret key survives
+        let secret_key =
+            bitcoin::secp256k1::SecretKey::from_slice(&enroller.s.secret_bytes()).unwrap();
+        let s = bitcoin::secp256k1::KeyPair::from_secret_key(&secp, &secret_key);
+        let recovered = Enrolled::recover(s, directory, ohttp_keys, ohttp_relay);
+        assert_eq!(recovered.fallback_target(), enrolled.fallback_target());
+        assert_eq!(recovered, enrolled);
+    }
+
+    #[test]
+    #[cfg(feature = "v2")]
+    fn recovery_code_roundtrips() {
+        let mut enrolled = test_enrolled();
+        enrolled.set_directory_layout(DirectoryLayout::Versioned);
+        enrolled.require_v2_only(true);
+        enrolled.set_directory_content_type("application/octet-stream");
+
+        let code = enrolled.to_recovery_code().unwrap();
+        assert!(code.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'));
+        let recovered = Enrolled::from_recovery_code(&code).unwrap();
+        assert_eq!(recovered, enrolled);
+        assert_eq!(recovered.fallback_target(), enrolled.fallback_target());
+
+        assert!(Enrolled::from_recovery_code(&code[..code.len() / 2]).is_err());
+        let mut bytes =
+            base64::decode_config(&code, base64::Config::new(base64::CharacterSet::UrlSafe, false))
+                .unwrap();
+        bytes[0] = 0;
+        let unsupported =
+            base64::encode_config(bytes, base64::Config::new(base64::CharacterSet::UrlSafe, false));
+        assert!(Enrolled::from_recovery_code(&unsupported).is_err());
+
+        enrolled.set_directory_content_type("a".repeat(u16::MAX as usize + 1));
+        assert!(enrolled.to_recovery_code().is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "v2")]
+    fn public_session_info_has_no_secrets() {
+        let s = test_keypair(1);
+        let enrolled = Enrolled::recover(
+            s,
+            DirectoryUrl::try_from("https://directory.com").unwrap(),
+            OhttpKeys(test_key_config(7)),
+            RelayUrl::try_from("https://relay.com").unwrap(),
+        );
+
+        let info = enrolled.public_session_info();
+        assert_eq!(info.session_id, session_id_for(&s.public_key()));
+        assert_eq!(info.session_url, enrolled.fallback_target());
+        assert_eq!(info.ohttp_key_id, Some(7));
+
+        let json = serde_json::to_string(&info).unwrap();
+        assert_eq!(serde_json::from_str::<PublicSessionInfo>(&json).unwrap(), info);
+        let stored = serde_json::to_value(&enrolled).unwrap();
+        assert!(!json.contains(stored["s"].as_str().unwrap()));
+        assert!(!json.contains(&s.display_secret().to_string()));
+        let fields = serde_json::from_str::<serde_json::Value>(&json).unwrap();
+        let mut keys: Vec<_> = fields.as_object().unwrap().keys().cloned().collect();
+        keys.sort();
+        assert_eq!(keys, ["directory", "ohttp_key_id", "session_id", "session_url"]);
+    }
+
+    #[test]
+    #[cfg(feature = "v2")]
+    fn relay_sharing_directory_authority_is_flagged() {
+        let enrolled = test_enrolled();
+
+        let separate = RelayUrl::try_from("https://relay.com").unwrap();
+        assert!(enrolled.check_relay_directory_separation(&separate).is_ok());
+        let other_port = RelayUrl::try_from("https://directory.com:8443").unwrap();
+        assert!(enrolled.check_relay_directory_separation(&other_port).is_ok());
+        let same_host = RelayUrl::try_from("https://directory.com:443/relay").unwrap();
+        assert!(enrolled.check_relay_directory_separation(&same_host).is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "v2")]
+    fn enroller_rejects_insecure_directory() {
+        let ohttp_keys = OhttpKeys(test_key_config(1));
+        let relay = RelayUrl::try_from("https://relay.com").unwrap();
+        let insecure = DirectoryUrl::try_from("http://127.0.0.1:8080").unwrap();
+
+        assert!(Enroller::from_directory_config(
+            insecure.clone(),
+            ohttp_keys.clone(),
+            relay.clone(),
+            false
+        )
+        .is_err());
+        assert!(Enroller::from_directory_config(insecure, ohttp_keys.clone(), relay.clone(), true)
+            .is_ok());
+        assert!(Enroller::from_directory_config(
+            DirectoryUrl::try_from("https://directory.com").unwrap(),
+            ohttp_keys,
+            relay,
+            false
+        )
+        .is_ok());
+    }
+
+    #[test]
+    #[cfg(feature = "v2")]
+    fn enrolled_counts_poll_attempts() {
+        let mut enrolled = test_enrolled();
+        assert_eq!(enrolled.metrics(), &SessionMetrics::default());
+        assert_eq!(enrolled.polls_since_creation(), 0);
+        let elapsed = enrolled.elapsed_since_creation();
+
+        let (req, _) = enrolled.extract_req().unwrap();
+        let (_, _) = enrolled.extract_req().unwrap();
+        assert_eq!(enrolled.metrics().poll_attempts(), 2);
+        assert_eq!(enrolled.polls_since_creation(), 2);
+        assert!(enrolled.elapsed_since_creation() >= elapsed);
+        assert_eq!(enrolled.metrics().bytes_sent(), 2 * req.body.len() as u64);
+        assert_eq!(enrolled.metrics().proposals_received(), 0);
+    }
+
+    #[test]
+    #[cfg(feature = "v2")]
+    fn requests_for_another_relay_are_independent() {
+        let mut enrolled = test_enrolled();
+        let server = ohttp::Server::new(enrolled.ohttp_keys.0.clone()).unwrap();
+        let fallback = RelayUrl::try_from("https://fallback-relay.com").unwrap();
+
+        let (primary_req, primary_ctx) = enrolled.extract_req().unwrap();
+        let (fallback_req, fallback_ctx) = enrolled.extract_req_for_relay(fallback).unwrap();
+        assert_eq!(primary_req.url.as_str(), "https://relay.com/");
+        assert_eq!(fallback_req.url.as_str(), "https://fallback-relay.com/");
+        assert_eq!(enrolled.extract_req().unwrap().0.url, primary_req.url);
+
+        // Each response is read with the context of the request it answers
+        let respond = |req: &Request, content: &[u8]| {
+            let (_, server_response) = server.decapsulate(&req.body).unwrap();
+            let mut response = bhttp::Message::response(200);
+            response.write_content(content);
+            let mut bhttp_response = Vec::new();
+            response.write_bhttp(bhttp::Mode::KnownLength, &mut bhttp_response).unwrap();
+            server_response.encapsulate(&bhttp_response).unwrap()
+        };
+        let fallback_res = respond(&fallback_req, b"fallback");
+        let primary_res = respond(&primary_req, b"primary");
+        assert_eq!(crate::v2::ohttp_decapsulate(fallback_ctx, &fallback_res).unwrap(), b"fallback");
+        assert_eq!(crate::v2::ohttp_decapsulate(primary_ctx, &primary_res).unwrap(), b"primary");
+    }
+
+    #[test]
+    #[cfg(feature = "v2")]
+    fn enrolled_pj_uri_with_amount() {
+        let enrolled = test_enrolled();
+        let address = bitcoin::Address::from_str("12c6DSiU4Rq3P4ZxziKxzrL5LmMBrzjrJX")
+            .unwrap()
+            .assume_checked();
+
+        let uri = enrolled.pj_uri_with_amount(address.clone(), Amount::ONE_BTC).unwrap();
+        assert_eq!(uri.amount, Some(Amount::ONE_BTC));
+        assert_eq!(uri.extras.endpoint.as_str(), enrolled.fallback_target());
+        assert!(enrolled.pj_uri_with_amount(address, Amount::ZERO).is_none());
+    }
+
+    #[test]
+    #[cfg(feature = "v2")]
+    fn enrolled_pj_uri_with_metadata_roundtrips() {
+        use std::borrow::Cow;
+
+        use crate::uri::UriExt;
+        use crate::Uri;
+
+        let enrolled = test_enrolled();
+        let address = bitcoin::Address::from_str("12c6DSiU4Rq3P4ZxziKxzrL5LmMBrzjrJX")
+            .unwrap()
+            .assume_checked();
+        let label = "Café & Bar #1".to_string();
+        let message = "order=42?pay now".to_string();
+
+        let uri = enrolled.pj_uri_with_metadata(
+            address.clone(),
+            Some(label.clone()),
+            Some(message.clone()),
+            Some(Amount::ONE_BTC),
+        );
+        let qr_string = uri.to_string();
+        let parsed = Uri::try_from(qr_string.as_str())
+            .unwrap()
+            .assume_checked()
+            .check_pj_supported()
+            .unwrap_or_else(|_| panic!("{} should support payjoin", qr_string));
+        assert_eq!(parsed.address, address);
+        assert_eq!(parsed.amount, Some(Amount::ONE_BTC));
+        let parsed_label: Cow<'_, str> = parsed.label.unwrap().try_into().unwrap();
+        let parsed_message: Cow<'_, str> = parsed.message.unwrap().try_into().unwrap();
+        assert_eq!(parsed_label, label);
+        assert_eq!(parsed_message, message);
+        assert_eq!(parsed.extras.endpoint.as_str(), enrolled.fallback_target());
+
+        let bare = enrolled.pj_uri_with_metadata(address, None, None, None);
+        assert!(bare.label.is_none() && bare.message.is_none() && bare.amount.is_none());
+    }
+
+    #[test]
+    #[cfg(feature = "simulate")]
+    fn enrolled_simulates_proposal() {
+        let enrolled = test_enrolled();
+        let original_psbt = Psbt::from_str(ORIGINAL_PSBT).unwrap();
+        let proposal = enrolled
+            .simulate_proposal(
+                original_psbt.clone(),
+                "maxadditionalfeecontribution=182&additionalfeeoutputindex=0",
+            )
+            .expect("Simulated proposal should be valid");
+        assert_eq!(proposal.extract_tx_to_schedule_broadcast(), original_psbt.extract_tx());
+        assert!(proposal
+            .assume_interactive_receiver()
+            .check_inputs_not_owned(|_| Ok(false))
+            .is_ok());
+    }
+
+    #[test]
+    #[cfg(feature = "v2")]
+    fn require_v2_only_refuses_plaintext_fallback() {
+        let psbt = Psbt::from_str(ORIGINAL_PSBT).unwrap();
+        let proposal = |require_v2_only| PayjoinProposal {
+            inner: crate::receive::PayjoinProposal {
+                original_psbt: psbt.clone(),
+                payjoin_psbt: psbt.clone(),
+                params: Params::default(),
+                owned_vouts: vec![1],
+            },
+            context: V2Context { require_v2_only, ..test_context(None) },
+        };
+
+        assert!(proposal(false).extract_v2_req().is_ok());
+        assert!(matches!(
+            proposal(true).extract_v2_req(),
+            Err(Error::BadRequest(ref e)) if e.error_code() == "original-psbt-rejected"
+        ));
+    }
+
+    #[test]
+    #[cfg(feature = "v2")]
+    fn locked_utxos_are_aggregated_across_sessions() {
+        use bitcoin::hashes::Hash;
+
+        let original_psbt = Psbt::from_str(ORIGINAL_PSBT).unwrap();
+        let receiver_outpoint = OutPoint { txid: bitcoin::Txid::all_zeros(), vout: 0 };
+        let proposal = |receiver_inputs: &[OutPoint]| {
+            let mut payjoin_psbt = original_psbt.clone();
+            for outpoint in receiver_inputs {
+                payjoin_psbt
+                    .unsigned_tx
+                    .input
+                    .push(bitcoin::TxIn { previous_output: *outpoint, ..Default::default() });
+                payjoin_psbt.inputs.push(Default::default());
+            }
+            PayjoinProposal {
+                inner: crate::receive::PayjoinProposal {
+                    original_psbt: original_psbt.clone(),
+                    payjoin_psbt,
+                    params: Params::default(),
+                    owned_vouts: vec![1],
+                },
+                context: test_context(None),
+            }
+        };
+
+        let sessions = [proposal(&[receiver_outpoint]), proposal(&[receiver_outpoint])];
+        let locked = aggregate_locked_utxos(&sessions);
+        let sender_outpoint = original_psbt.unsigned_tx.input[0].previous_output;
+        assert_eq!(locked, HashSet::from([sender_outpoint, receiver_outpoint]));
+        let spent = sessions.iter().map(|p| p.utxos_to_be_locked().count()).sum::<usize>();
+        assert!(locked.len() < spent, "the shared receiver input is a conflict");
+        assert!(aggregate_locked_utxos(&[]).is_empty());
+    }
+
+    #[test]
+    #[cfg(feature = "v2")]
+    fn directory_writes_carry_content_type() {
+        let key_config = test_key_config(1);
+        let server = ohttp::Server::new(key_config.clone()).unwrap();
+        let content_type = |body: &[u8]| {
+            let (bhttp_req, _) = server.decapsulate(body).unwrap();
+            let req = bhttp::Message::read_bhttp(&mut std::io::Cursor::new(bhttp_req)).unwrap();
+            req.header().get(b"content-type").map(|value| value.to_vec())
+        };
+
+        let mut enroller = Enroller::from_directory_config(
+            DirectoryUrl::try_from("https://directory.com").unwrap(),
+            OhttpKeys(key_config.clone()),
+            RelayUrl::try_from("https://relay.com").unwrap(),
+            false,
+        )
+        .unwrap()
+        .with_directory_content_type("application/octet-stream");
+        let (req, _) = enroller.extract_req().unwrap();
+        assert_eq!(content_type(&req.body), Some(b"application/octet-stream".to_vec()));
+
+        let psbt = Psbt::from_str(ORIGINAL_PSBT).unwrap();
+        let mut proposal = PayjoinProposal {
+            inner: crate::receive::PayjoinProposal {
+                original_psbt: psbt.clone(),
+                payjoin_psbt: psbt,
+                params: Params::default(),
+                owned_vouts: vec![1],
+            },
+            context: V2Context { ohttp_keys: OhttpKeys(key_config), ..test_context(None) },
+        };
+        let (req, _) = proposal.extract_v2_req().unwrap();
+        assert_eq!(content_type(&req.body), Some(V2_DIRECTORY_CONTENT_TYPE.as_bytes().to_vec()));
+    }
+
+    #[test]
+    #[cfg(feature = "v2")]
+    fn delivery_target_is_where_the_proposal_is_posted() {
+        let key_config = test_key_config(1);
+        let server = ohttp::Server::new(key_config.clone()).unwrap();
+        let psbt = Psbt::from_str(ORIGINAL_PSBT).unwrap();
+        let mut proposal = PayjoinProposal {
+            inner: crate::receive::PayjoinProposal {
+                original_psbt: psbt.clone(),
+                payjoin_psbt: psbt,
+                params: Params::default(),
+                owned_vouts: vec![1],
+            },
+            context: V2Context {
+                layout: DirectoryLayout::Versioned,
+                ohttp_keys: OhttpKeys(key_config),
+                ..test_context(None)
+            },
+        };
+
+        let target = proposal.delivery_target();
+        assert_eq!(
+            target.as_str(),
+            format!(
+                "https://directory.com/v2/{}/payjoin",
+                session_id_for(&test_keypair(1).public_key())
+            )
+        );
+        let (req, _) = proposal.extract_v2_req().unwrap();
+        let (bhttp_req, _) = server.decapsulate(&req.body).unwrap();
+        let req = bhttp::Message::read_bhttp(&mut std::io::Cursor::new(bhttp_req)).unwrap();
+        assert_eq!(req.control().path(), Some(target.path().as_bytes()));
+        assert_eq!(proposal.delivery_target(), target);
+    }
+
+    #[test]
+    #[cfg(feature = "v2")]
+    fn reused_ephemeral_key_is_rejected() {
+        use std::cell::RefCell;
+
+        let proposal = |session: u8, e: Option<bitcoin::secp256k1::PublicKey>| UncheckedProposal {
+            inner: crate::receive::UncheckedProposal {
+                psbt: Psbt::from_str(ORIGINAL_PSBT).unwrap(),
+                params: Params::default(),
+            },
+            context: V2Context { s: test_keypair(session), ..test_context(e) },
+        };
+
+        let seen = RefCell::new(HashSet::new());
+        let is_seen = |e: &bitcoin::secp256k1::PublicKey| Ok(!seen.borrow_mut().insert(*e));
+        let e = test_keypair(3).public_key();
+        assert!(proposal(1, Some(e)).require_unseen_ephemeral_key(is_seen).is_ok());
+        assert!(proposal(2, Some(test_keypair(4).public_key()))
+            .require_unseen_ephemeral_key(is_seen)
+            .is_ok());
+        assert!(matches!(
+            proposal(2, Some(e)).require_unseen_ephemeral_key(is_seen),
+            Err(Error::BadRequest(ref e)) if e.error_code() == "original-psbt-rejected"
+        ));
+        assert!(proposal(2, None).require_unseen_ephemeral_key(|_| Ok(true)).is_ok());
+    }
+
+    #[test]
+    fn query_padding_is_trimmed_or_rejected() {
+        assert_eq!(trim_query_padding("v=2").unwrap(), "v=2");
+        assert_eq!(trim_query_padding("v=2\0\0\0").unwrap(), "v=2");
+        assert_eq!(trim_query_padding("").unwrap(), "");
+        assert_eq!(trim_query_padding("\0\0").unwrap(), "");
+        assert!(trim_query_padding("v=2\0&minfeerate=1\0").is_err());
+        assert!(trim_query_padding("\0v=2").is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "v2")]
+    fn unchecked_from_v1_payload_parses_like_a_polled_proposal() {
+        let enrolled = test_enrolled();
+
+        let proposal =
+            enrolled.unchecked_from_v1_payload(ORIGINAL_PSBT, "v=1&minfeerate=1\0\0\0").unwrap();
+        assert!(!proposal.received_encrypted());
+        assert_eq!(proposal.sender_min_fee_rate(), FeeRate::from_sat_per_vb_unchecked(1));
+        assert!(enrolled.unchecked_from_v1_payload(ORIGINAL_PSBT, "v=1\0&minfeerate=1").is_err());
+        assert!(enrolled.unchecked_from_v1_payload("not a psbt", "v=1").is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "v2")]
+    fn v1_proposal_is_rejected_by_v2_only_session() {
+        let mut enrolled = test_enrolled();
+        assert!(enrolled.unchecked_from_v1_payload(ORIGINAL_PSBT, "v=1").is_ok());
+
+        enrolled.accept_versions(&[Version::Two]);
+        // A plaintext fallback is v1 even if it claims otherwise
+        for query in ["v=1", "v=2", ""] {
+            match enrolled.unchecked_from_v1_payload(ORIGINAL_PSBT, query) {
+                Err(Error::BadRequest(e)) => {
+                    assert_eq!(e.error_code(), "version-unsupported");
+                    let reply: serde_json::Value =
+                        serde_json::from_str(&e.to_json_with_message("")).unwrap();
+                    assert_eq!(reply["supported"], serde_json::json!(["2"]));
+                }
+                _ => panic!("v1 proposal should be rejected"),
+            }
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "v2")]
+    fn accepted_versions_are_persisted() {
+        let mut enrolled = test_enrolled();
+        // Sessions stored before the allowlist was persisted accept every version
+        let mut stored = serde_json::to_value(&enrolled).unwrap();
+        stored.as_object_mut().unwrap().remove("accepted_versions");
+        assert_eq!(serde_json::from_value::<Enrolled>(stored).unwrap(), enrolled);
+
+        let accepting_all = enrolled.clone();
+        enrolled.accept_versions(&[Version::Two]);
+        assert_eq!(accepting_all.diff(&enrolled)[0].field, "accepted_versions");
+        let serialized = serde_json::to_value(&enrolled).unwrap();
+        assert_eq!(serialized["accepted_versions"], serde_json::json!(["2"]));
+        let deserialized: Enrolled = serde_json::from_value(serialized).unwrap();
+        assert_eq!(deserialized.accepted_versions, [Version::Two]);
+        assert_eq!(deserialized, enrolled);
+        let recovered =
+            Enrolled::from_recovery_code(&enrolled.to_recovery_code().unwrap()).unwrap();
+        assert_eq!(recovered.accepted_versions, [Version::Two]);
+        #[cfg(feature = "cbor")]
+        assert_eq!(Enrolled::from_cbor(&enrolled.to_cbor()).unwrap(), enrolled);
+    }
+
+    #[test]
+    #[cfg(feature = "v2")]
+    fn enrolled_returns_raw_payload() {
+        let mut enrolled = test_enrolled();
+        let server = ohttp::Server::new(enrolled.ohttp_keys.0.clone()).unwrap();
+
+        // Act as the directory answering the poll with a v1 payload
+        let (req, ctx) = enrolled.extract_req().unwrap();
+        let (_, server_response) = server.decapsulate(&req.body).unwrap();
+        let payload = format!("{}\nv=1", ORIGINAL_PSBT).into_bytes();
+        let mut response = bhttp::Message::response(200);
+        response.write_content(&payload);
+        let mut bhttp_response = Vec::new();
+        response.write_bhttp(bhttp::Mode::KnownLength, &mut bhttp_response).unwrap();
+        let body = server_response.encapsulate(&bhttp_response).unwrap();
+
+        let (proposal, raw) = match enrolled.process_res_with_payload(body.as_slice(), ctx) {
+            Polled::Proposal(proposal) => proposal,
+            _ => panic!("expected a proposal"),
+        };
+        assert_eq!(raw, payload);
+        assert_eq!(
+            proposal.extract_tx_to_schedule_broadcast(),
+            Psbt::from_str(ORIGINAL_PSBT).unwrap().extract_tx()
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "v2")]
+    fn sender_disabled_output_substitution_is_honored() {
+        let context = test_context(Some(test_keypair(2).public_key()));
+        let payload = format!("{}\nv=2&disableoutputsubstitution=true", ORIGINAL_PSBT);
+        let proposal = UncheckedProposal::from_v2_payload(
+            payload.into_bytes(),
+            context,
+            false,
+            crate::supported_versions(),
+        )
+        .unwrap();
+        let receiver_script =
+            proposal.extract_tx_to_schedule_broadcast().output[1].script_pubkey.clone();
+        let mut payjoin = proposal
+            .assume_interactive_receiver()
+            .check_inputs_not_owned(|_| Ok(false))
+            .unwrap()
+            .check_no_mixed_input_scripts()
+            .unwrap()
+            .check_no_inputs_seen_before(|_| Ok(false))
+            .unwrap()
+            .identify_receiver_outputs(|script| Ok(*script == receiver_script))
+            .unwrap();
+        assert!(payjoin.is_output_substitution_disabled());
+
+        let substitute = bitcoin::Address::from_str(
+            "bc1p5d7rjq7g6rdk2yhzks9smlaqtedr4dekq08ge8ztwac72sfr9rusxg3297",
+        )
+        .unwrap()
+        .assume_checked();
+        let err = payjoin.substitute_output_address(substitute).unwrap_err();
+        assert_eq!(err.to_string(), "The sender disabled output substitution");
+    }
+
+    #[test]
+    fn session_id_is_url_safe_base64_pubkey() {
+        let s = test_keypair(1);
+        assert_eq!(session_id_for(&s.public_key()), "AxuExVZ7EmRAmV0-1aq6BWXXHhg0YEgZ_5wX9enV3QeP");
+    }
+
+    #[test]
+    #[cfg(feature = "v2")]
+    fn enrolled_follows_same_origin_redirect() {
+        let enrolled = test_enrolled();
+        let server = ohttp::Server::new(enrolled.ohttp_keys.0.clone()).unwrap();
+        let session_id = session_id_for(&enrolled.s.public_key());
+        let redirect_to = |mut enrolled: Enrolled, location: &str| {
+            let (req, ctx) = enrolled.extract_req().unwrap();
+            let (_, server_response) = server.decapsulate(&req.body).unwrap();
+            let mut response = bhttp::Message::response(307);
+            response.put_header("location", location);
+            let mut bhttp_response = Vec::new();
+            response.write_bhttp(bhttp::Mode::KnownLength, &mut bhttp_response).unwrap();
+            let body = server_response.encapsulate(&bhttp_response).unwrap();
+            enrolled.process_res(body.as_slice(), ctx)
+        };
+
+        let moved = format!("/v2/{}", session_id);
+        let enrolled = match redirect_to(enrolled, &moved) {
+            Polled::Redirected(enrolled, directory) => {
+                assert_eq!(directory.as_str(), "https://directory.com/v2/");
+                *enrolled
+            }
+            _ => panic!("expected the session to follow the redirect"),
+        };
+        assert_eq!(enrolled.fallback_target(), format!("https://directory.com/v2/{}", session_id));
+
+        // Rejected redirects leave the session where it was
+        let cross_origin = format!("https://evil.com/{}", session_id);
+        let enrolled = match redirect_to(enrolled, &cross_origin) {
+            Polled::Rejected(_, enrolled) => *enrolled,
+            _ => panic!("expected the redirect to be rejected"),
+        };
+        assert!(matches!(redirect_to(enrolled, "/elsewhere"), Polled::Rejected(..)));
+    }
+
+    #[test]
+    #[cfg(feature = "v2")]
+    fn status_res_maps_directory_status_codes() {
+        use std::io::Cursor;
+
+        let mut enrolled = test_enrolled();
+        let server = ohttp::Server::new(enrolled.ohttp_keys.0.clone()).unwrap();
+        let mut status_for = |status: u16| {
+            let (req, ctx) = enrolled.extract_status_req().unwrap();
+            assert_eq!(req.url, enrolled.ohttp_relay);
+            let (bhttp_req, server_response) = server.decapsulate(&req.body).unwrap();
+            let request = bhttp::Message::read_bhttp(&mut Cursor::new(&bhttp_req)).unwrap();
+            assert_eq!(request.control().method(), Some(&b"HEAD"[..]));
+            assert!(request.content().is_empty());
+            let mut bhttp_response = Vec::new();
+            bhttp::Message::response(status)
+                .write_bhttp(bhttp::Mode::KnownLength, &mut bhttp_response)
+                .unwrap();
+            let body = server_response.encapsulate(&bhttp_response).unwrap();
+            enrolled.process_status_res(body.as_slice(), ctx)
+        };
+
+        assert_eq!(status_for(200).unwrap(), SessionStatus::ProposalWaiting);
+        assert_eq!(status_for(202).unwrap(), SessionStatus::Empty);
+        assert_eq!(status_for(204).unwrap(), SessionStatus::Empty);
+        assert_eq!(status_for(404).unwrap(), SessionStatus::Gone);
+        assert_eq!(status_for(410).unwrap(), SessionStatus::Gone);
+        assert!(status_for(500).is_err());
+        assert_eq!(enrolled.metrics().poll_attempts(), 0);
+
+        struct FailingRead;
+        impl std::io::Read for FailingRead {
+            fn read(&mut self, _: &mut [u8]) -> std::io::Result<usize> {
+                Err(std::io::ErrorKind::ConnectionReset.into())
+            }
+        }
+        let (_, ctx) = enrolled.extract_status_req().unwrap();
+        match enrolled.process_status_res(FailingRead, ctx) {
+            Err(Error::BadRequest(e)) => assert_eq!(e.error_code(), "io-error"),
+            _ => panic!("a failed read should be an error"),
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "v2")]
+    fn raw_psbt_payload_is_opt_in() {
+        let context = test_context(None);
+        let original_psbt = Psbt::from_str(ORIGINAL_PSBT).unwrap();
+        let mut raw_payload = original_psbt.serialize();
+        raw_payload.extend_from_slice(b"\nv=2&minfeerate=1\0\0");
+        let base64_payload = format!("{}\nv=2&minfeerate=1", ORIGINAL_PSBT).into_bytes();
+
+        assert!(UncheckedProposal::from_v2_payload(
+            raw_payload.clone(),
+            context.clone(),
+            false,
+            crate::supported_versions(),
+        )
+        .is_err());
+        let proposal = UncheckedProposal::from_v2_payload(
+            raw_payload,
+            context.clone(),
+            true,
+            crate::supported_versions(),
+        )
+        .unwrap();
+        assert_eq!(proposal.extract_tx_to_schedule_broadcast(), original_psbt.clone().extract_tx());
+        assert_eq!(proposal.inner.params.min_feerate, FeeRate::from_sat_per_vb_unchecked(1));
+
+        for accept_raw_psbt in [false, true] {
+            let proposal = UncheckedProposal::from_v2_payload(
+                base64_payload.clone(),
+                context.clone(),
+                accept_raw_psbt,
+                crate::supported_versions(),
+            )
+            .unwrap();
+            assert_eq!(
+                proposal.extract_tx_to_schedule_broadcast(),
+                original_psbt.clone().extract_tx()
+            );
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "v2")]
+    fn resent_request_is_answered_with_its_context() {
+        let mut enrolled = test_enrolled();
+        let server = ohttp::Server::new(enrolled.ohttp_keys.0.clone()).unwrap();
+        let (req, ctx) = enrolled.extract_req().unwrap();
+
+        // The first attempt is lost in transit, the directory answers the retry
+        let _lost = server.decapsulate(&req.clone().body).unwrap();
+        let (_, server_response) = server.decapsulate(&req.body).unwrap();
+        let mut bhttp_response = Vec::new();
+        bhttp::Message::response(202)
+            .write_bhttp(bhttp::Mode::KnownLength, &mut bhttp_response)
+            .unwrap();
+        let body = server_response.encapsulate(&bhttp_response).unwrap();
+        assert!(matches!(enrolled.process_res(body.as_slice(), ctx), Polled::Pending(_)));
+    }
+
+    #[test]
+    #[cfg(feature = "v2")]
+    fn enrolled_rate_limits_proposals() {
+        let mut enrolled = test_enrolled();
+        let server = ohttp::Server::new(enrolled.ohttp_keys.0.clone()).unwrap();
+        enrolled.limit_proposal_rate(1, Duration::from_secs(3600));
+        let poll = |mut enrolled: Enrolled| {
+            let (req, ctx) = enrolled.extract_req().unwrap();
+            let (_, server_response) = server.decapsulate(&req.body).unwrap();
+            let mut response = bhttp::Message::response(200);
+            response.write_content(format!("{}\nv=1", ORIGINAL_PSBT));
+            let mut bhttp_response = Vec::new();
+            response.write_bhttp(bhttp::Mode::KnownLength, &mut bhttp_response).unwrap();
+            let body = server_response.encapsulate(&bhttp_response).unwrap();
+            enrolled.process_res(body.as_slice(), ctx)
+        };
+
+        // A receiver resuming from storage answers the first proposal
+        assert!(matches!(poll(enrolled.clone()), Polled::Proposal(_)));
+        // The throttled proposal hands the session back to keep polling
+        let enrolled = match poll(enrolled) {
+            Polled::Rejected(Error::BadRequest(ref e), enrolled)
+                if e.error_code() == "unavailable" =>
+                *enrolled,
+            _ => panic!("expected the proposal to be rate limited"),
+        };
+        assert!(matches!(poll(enrolled), Polled::Rejected(..)));
+    }
+
+    /// Run the cross-implementation payload vectors in `tests/vectors/receive_v2_payloads.json`.
+    ///
+    /// Each vector is an object with a `description`, the decrypted `payload` a sender posts to
+    /// the directory (base64 Original PSBT, `\n`, then the query string, optionally null padded),
+    /// whether it is `valid`, and for valid payloads the expected `input_count`.
+    #[test]
+    #[cfg(feature = "v2")]
+    fn v2_payload_vectors() {
+        const VECTORS: &str = include_str!("../../tests/vectors/receive_v2_payloads.json");
+
+        let context = test_context(None);
+        let vectors: Vec<serde_json::Value> = serde_json::from_str(VECTORS).unwrap();
+        for vector in vectors {
+            let description = vector["description"].as_str().unwrap();
+            let payload = vector["payload"].as_str().unwrap().as_bytes().to_vec();
+            let result = UncheckedProposal::from_v2_payload(
+                payload,
+                context.clone(),
+                false,
+                crate::supported_versions(),
+            );
+            if vector["valid"].as_bool().unwrap() {
+                let proposal = result.unwrap_or_else(|e| panic!("{}: {}", description, e));
+                assert!(!proposal.received_encrypted(), "{}", description);
+                let input_count = vector["input_count"].as_u64().unwrap() as usize;
+                assert_eq!(
+                    proposal.extract_tx_to_schedule_broadcast().input.len(),
+                    input_count,
+                    "{}",
+                    description
+                );
+            } else {
+                assert!(result.is_err(), "{}", description);
+            }
+        }
+    }
 }
@@ -5,7 +5,16 @@ pub const V1_REQ_CONTENT_TYPE: &str = "text/plain";
 #[cfg(feature = "v2")]
 pub const V2_REQ_CONTENT_TYPE: &str = "message/ohttp-req";
 
+/// The `Content-Type` of writes to a payjoin directory, set on the request inside the OHTTP
+/// encapsulation
+#[cfg(feature = "v2")]
+pub const V2_DIRECTORY_CONTENT_TYPE: &str = "text/plain";
+
 /// Represents data that needs to be transmitted to the receiver or payjoin directory.
+///
+/// A v2 request may be re-sent as is, for example after a transport error, and its response
+/// decapsulated with the `ohttp::ClientResponse` extracted alongside it. Extracting the request
+/// again encapsulates it anew, so only the new `ClientResponse` can read the new response.
 #[non_exhaustive]
 #[derive(Debug, Clone)]
 pub struct Request {
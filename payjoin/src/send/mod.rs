@@ -46,6 +46,8 @@ use crate::psbt::PsbtExt;
 use crate::request::Request;
 use crate::uri::UriExt;
 use crate::weight::{varint_size, ComputeWeight};
+#[cfg(feature = "v2")]
+use crate::{AttestationError, ParameterAttestation, RelayUrl};
 use crate::{PjUri, Uri};
 
 // See usize casts
@@ -324,7 +326,7 @@ impl RequestContext {
     #[cfg(feature = "v2")]
     pub fn extract_v2(
         &mut self,
-        ohttp_relay: Url,
+        ohttp_relay: RelayUrl,
     ) -> Result<(Request, ContextV2), CreateRequestError> {
         let rs = Self::rs_pubkey_from_dir_endpoint(&self.endpoint)?;
         let url = self.endpoint.clone();
@@ -341,11 +343,12 @@ impl RequestContext {
             "POST",
             url.as_str(),
             Some(&body),
+            Some(crate::request::V2_DIRECTORY_CONTENT_TYPE),
         )
         .map_err(InternalCreateRequestError::OhttpEncapsulation)?;
         log::debug!("ohttp_relay_url: {:?}", ohttp_relay);
         Ok((
-            Request { url: ohttp_relay, body },
+            Request { url: ohttp_relay.into(), body },
             // this method may be called more than once to re-construct the ohttp, therefore we must clone (or TODO memoize)
             ContextV2 {
                 context_v1: ContextV1 {
@@ -363,6 +366,30 @@ impl RequestContext {
         ))
     }
 
+    /// Check a receiver's [`ParameterAttestation`] against the receiver key the sender pinned.
+    ///
+    /// Besides a valid signature by `pubkey`, the payee must be the attested address, the
+    /// endpoint must be that key's subdirectory of the attested directory, and the OHTTP keys
+    /// in use must be the attested ones.
+    #[cfg(feature = "v2")]
+    pub fn verify_attestation(
+        &self,
+        attestation: &ParameterAttestation,
+        pubkey: &PublicKey,
+    ) -> Result<(), AttestationError> {
+        attestation.verify(pubkey)?;
+        let endpoint_pubkey = Self::rs_pubkey_from_dir_endpoint(&self.endpoint)
+            .map_err(|_| AttestationError::ParameterMismatch)?;
+        if &endpoint_pubkey != pubkey
+            || attestation.script_pubkey() != self.payee.as_script()
+            || !is_directory_resource(attestation.directory(), &self.endpoint)
+            || self.ohttp_keys.as_ref() != Some(attestation.ohttp_keys())
+        {
+            return Err(AttestationError::ParameterMismatch);
+        }
+        Ok(())
+    }
+
     #[cfg(feature = "v2")]
     fn rs_pubkey_from_dir_endpoint(endpoint: &Url) -> Result<PublicKey, CreateRequestError> {
         let path_and_query: String;
@@ -1018,6 +1045,26 @@ fn serialize_url(
     Ok(url)
 }
 
+/// Whether `endpoint` is a resource below `directory`: same scheme, host and port, and the
+/// directory's path segments followed by at least one more.
+#[cfg(feature = "v2")]
+fn is_directory_resource(directory: &Url, endpoint: &Url) -> bool {
+    fn segments(url: &Url) -> Vec<&str> {
+        url.path_segments().map(|segments| segments.collect()).unwrap_or_default()
+    }
+
+    let mut directory_path = segments(directory);
+    if directory_path.last() == Some(&"") {
+        directory_path.pop();
+    }
+    let endpoint_path = segments(endpoint);
+    directory.scheme() == endpoint.scheme()
+        && directory.host() == endpoint.host()
+        && directory.port_or_known_default() == endpoint.port_or_known_default()
+        && endpoint_path.len() > directory_path.len()
+        && endpoint_path.starts_with(&directory_path)
+}
+
 #[cfg(test)]
 mod test {
     use std::str::FromStr;
@@ -1091,6 +1138,106 @@ mod test {
         assert!(req_ctx == deserialized);
     }
 
+    #[test]
+    #[cfg(all(feature = "v2", feature = "receive"))]
+    fn verify_attestation_pins_receiver_parameters() {
+        use ohttp::hpke::{Aead, Kdf, Kem};
+        use ohttp::SymmetricSuite;
+
+        use super::*;
+        use crate::OhttpKeys;
+        const SYMMETRIC: &[SymmetricSuite] =
+            &[ohttp::SymmetricSuite::new(Kdf::HkdfSha256, Aead::ChaCha20Poly1305)];
+
+        let secp = bitcoin::secp256k1::Secp256k1::new();
+        let s = bitcoin::secp256k1::KeyPair::from_seckey_slice(&secp, &[1; 32]).unwrap();
+        let other = bitcoin::secp256k1::KeyPair::from_seckey_slice(&secp, &[2; 32]).unwrap();
+        let directory = Url::parse("https://directory.com/").unwrap();
+        let ohttp_keys =
+            OhttpKeys(ohttp::KeyConfig::new(1, Kem::X25519Sha256, Vec::from(SYMMETRIC)).unwrap());
+        let endpoint =
+            directory.join(&crate::receive::v2::session_id_for(&s.public_key())).unwrap();
+        let req_ctx = RequestContext {
+            psbt: Psbt::from_str(ORIGINAL_PSBT).unwrap(),
+            endpoint,
+            ohttp_keys: Some(ohttp_keys.clone()),
+            disable_output_substitution: false,
+            fee_contribution: None,
+            min_fee_rate: FeeRate::ZERO,
+            input_type: InputType::SegWitV0 {
+                ty: crate::input_type::SegWitV0Type::Pubkey,
+                nested: true,
+            },
+            sequence: Sequence::MAX,
+            payee: ScriptBuf::from(vec![0x00]),
+            e: bitcoin::secp256k1::SecretKey::from_slice(&[0x01; 32]).unwrap(),
+        };
+
+        let payee = req_ctx.payee.clone();
+        let attestation =
+            ParameterAttestation::sign(&s, payee.clone(), directory.clone(), ohttp_keys.clone());
+        assert!(req_ctx.verify_attestation(&attestation, &s.public_key()).is_ok());
+        assert!(req_ctx.verify_attestation(&attestation, &other.public_key()).is_err());
+
+        let elsewhere = Url::parse("https://evil.com/").unwrap();
+        let attestation =
+            ParameterAttestation::sign(&s, payee.clone(), elsewhere.clone(), ohttp_keys.clone());
+        assert!(req_ctx.verify_attestation(&attestation, &s.public_key()).is_err());
+
+        // A directory with a path attests its own subdirectories, not look-alike paths
+        let session_id = crate::receive::v2::session_id_for(&s.public_key());
+        let pj_directory = Url::parse("https://directory.com/pj").unwrap();
+        let attestation =
+            ParameterAttestation::sign(&s, payee.clone(), pj_directory, ohttp_keys.clone());
+        let at = |endpoint: &str| RequestContext {
+            endpoint: Url::parse(endpoint).unwrap().join(&session_id).unwrap(),
+            ..req_ctx.clone()
+        };
+        assert!(at("https://directory.com/pj/")
+            .verify_attestation(&attestation, &s.public_key())
+            .is_ok());
+        assert!(at("https://directory.com/pjevil/")
+            .verify_attestation(&attestation, &s.public_key())
+            .is_err());
+        assert!(at("https://directory.com:8443/pj/")
+            .verify_attestation(&attestation, &s.public_key())
+            .is_err());
+        assert!(at("https://directory.com.evil.com/pj/")
+            .verify_attestation(&attestation, &s.public_key())
+            .is_err());
+
+        // The attestation doesn't vouch for a URI whose address was swapped
+        let attested = ParameterAttestation::sign(
+            &s,
+            ScriptBuf::from(vec![0x51]),
+            directory.clone(),
+            ohttp_keys.clone(),
+        );
+        assert!(attested.verify(&s.public_key()).is_ok());
+        assert!(matches!(
+            req_ctx.verify_attestation(&attested, &s.public_key()),
+            Err(AttestationError::ParameterMismatch)
+        ));
+        let swapped = ParameterAttestation::new(
+            s.public_key(),
+            payee.clone(),
+            directory.clone(),
+            ohttp_keys.clone(),
+            *attested.signature(),
+        );
+        assert!(swapped.verify(&s.public_key()).is_err());
+
+        // A signature lifted onto other parameters doesn't verify
+        let forged = ParameterAttestation::new(
+            s.public_key(),
+            payee,
+            directory,
+            ohttp_keys,
+            *attestation.signature(),
+        );
+        assert!(forged.verify(&s.public_key()).is_err());
+    }
+
     #[test]
     fn handle_json_errors() {
         let ctx = create_v1_context();
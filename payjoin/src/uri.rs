@@ -69,6 +69,18 @@ impl<'a> UriExt<'a> for Uri<'a, NetworkChecked> {
     }
 }
 
+pub trait PjUriExt: sealed::UriExt {
+    /// The complete BIP 21 URI string to render as a QR code.
+    ///
+    /// Emits `bitcoin:<address>` followed by `amount`, `label` and `message` when set, then the
+    /// payjoin parameters `pj` and `pjos`, and `ohttp` when OHTTP keys are present.
+    fn to_qr_string(&self) -> String;
+}
+
+impl<'a> PjUriExt for PjUri<'a> {
+    fn to_qr_string(&self) -> String { self.to_string() }
+}
+
 /// Build a valid `PjUri`.
 ///
 /// Payjoin receiver can use this builder to create a payjoin
@@ -208,13 +220,16 @@ impl<'a> bip21::SerializeParams for &'a PayjoinExtras {
             ("pjos", if self.disable_output_substitution { "1" } else { "0" }.to_string()),
         ];
         #[cfg(feature = "v2")]
-        if let Some(ohttp_keys) = self.ohttp_keys.clone().and_then(|c| c.encode().ok()) {
-            let config =
-                bitcoin::base64::Config::new(bitcoin::base64::CharacterSet::UrlSafe, false);
-            let base64_ohttp_keys = bitcoin::base64::encode_config(ohttp_keys, config);
-            params.push(("ohttp", base64_ohttp_keys));
-        } else {
-            log::warn!("Failed to encode ohttp config, ignoring");
+        if let Some(ohttp_keys) = &self.ohttp_keys {
+            match ohttp_keys.encode() {
+                Ok(ohttp_keys) => {
+                    let config =
+                        bitcoin::base64::Config::new(bitcoin::base64::CharacterSet::UrlSafe, false);
+                    let base64_ohttp_keys = bitcoin::base64::encode_config(ohttp_keys, config);
+                    params.push(("ohttp", base64_ohttp_keys));
+                }
+                Err(_) => log::warn!("Failed to encode ohttp config, ignoring"),
+            }
         }
         params.into_iter()
     }
@@ -435,4 +450,47 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn test_qr_string_roundtrip() {
+        use std::str::FromStr;
+
+        let address = bitcoin::Address::from_str("12c6DSiU4Rq3P4ZxziKxzrL5LmMBrzjrJX")
+            .unwrap()
+            .assume_checked();
+        #[cfg(feature = "v2")]
+        let ohttp_keys = {
+            use ohttp::hpke::{Aead, Kdf, Kem};
+            const SYMMETRIC: &[ohttp::SymmetricSuite] =
+                &[ohttp::SymmetricSuite::new(Kdf::HkdfSha256, Aead::ChaCha20Poly1305)];
+            Some(OhttpKeys(
+                ohttp::KeyConfig::new(1, Kem::X25519Sha256, Vec::from(SYMMETRIC)).unwrap(),
+            ))
+        };
+        let uri = PjUriBuilder::new(
+            address.clone(),
+            Url::parse("https://example.com/pj").unwrap(),
+            #[cfg(feature = "v2")]
+            ohttp_keys.clone(),
+        )
+        .amount(Amount::ONE_BTC)
+        .label("label".to_string())
+        .pjos(true)
+        .build();
+
+        let qr_string = uri.to_qr_string();
+        assert!(qr_string.contains("pj="));
+        assert!(qr_string.contains("pjos=1"));
+        let parsed = Uri::try_from(qr_string.as_str())
+            .unwrap()
+            .assume_checked()
+            .check_pj_supported()
+            .unwrap_or_else(|_| panic!("{} should support payjoin", qr_string));
+        assert_eq!(parsed.address, address);
+        assert_eq!(parsed.amount, Some(Amount::ONE_BTC));
+        assert_eq!(parsed.extras.endpoint, uri.extras.endpoint);
+        assert!(parsed.extras.is_output_substitution_disabled());
+        #[cfg(feature = "v2")]
+        assert_eq!(parsed.extras.ohttp_keys, ohttp_keys);
+    }
 }
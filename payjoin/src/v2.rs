@@ -142,11 +142,15 @@ impl error::Error for HpkeError {
     }
 }
 
+/// Encapsulate a bhttp request to `target_resource` for the OHTTP gateway.
+///
+/// A `body` is sent with `content_type` as its `Content-Type` header, if one is given.
 pub fn ohttp_encapsulate(
     ohttp_keys: &mut ohttp::KeyConfig,
     method: &str,
     target_resource: &str,
     body: Option<&[u8]>,
+    content_type: Option<&str>,
 ) -> Result<(Vec<u8>, ohttp::ClientResponse), OhttpEncapsulationError> {
     use std::fmt::Write;
 
@@ -166,6 +170,9 @@ pub fn ohttp_encapsulate(
         url.path().as_bytes().to_vec(),
     );
     if let Some(body) = body {
+        if let Some(content_type) = content_type {
+            bhttp_message.put_header("content-type", content_type);
+        }
         bhttp_message.write_content(body);
     }
     let mut bhttp_req = Vec::new();
@@ -174,7 +181,7 @@ pub fn ohttp_encapsulate(
     Ok(encapsulated)
 }
 
-/// decapsulate ohttp, bhttp response and return http response body and status code
+/// decapsulate ohttp, bhttp response and return http response body
 pub fn ohttp_decapsulate(
     res_ctx: ohttp::ClientResponse,
     ohttp_body: &[u8],
@@ -185,6 +192,17 @@ pub fn ohttp_decapsulate(
     Ok(response.content().to_vec())
 }
 
+/// decapsulate ohttp, bhttp response and return the whole http response
+#[cfg(feature = "receive")]
+pub fn ohttp_decapsulate_response(
+    res_ctx: ohttp::ClientResponse,
+    ohttp_body: &[u8],
+) -> Result<bhttp::Message, OhttpEncapsulationError> {
+    let bhttp_body = res_ctx.decapsulate(ohttp_body)?;
+    let mut r = std::io::Cursor::new(bhttp_body);
+    Ok(bhttp::Message::read_bhttp(&mut r)?)
+}
+
 /// Error from de/encapsulating an Oblivious HTTP request or response.
 #[derive(Debug)]
 pub enum OhttpEncapsulationError {
@@ -229,6 +247,9 @@ impl error::Error for OhttpEncapsulationError {
     }
 }
 
+/// The path at which a payjoin directory advertises its OHTTP KeyConfig.
+pub const OHTTP_KEYS_PATH: &str = "/ohttp-keys";
+
 #[derive(Debug, Clone)]
 pub struct OhttpKeys(pub ohttp::KeyConfig);
 
@@ -237,6 +258,75 @@ impl OhttpKeys {
     pub fn decode(bytes: &[u8]) -> Result<Self, ohttp::Error> {
         ohttp::KeyConfig::decode(bytes).map(Self)
     }
+
+    /// The URL a directory serves its OHTTP KeyConfig at.
+    ///
+    /// GET it, ideally through an OHTTP relay so the directory never sees the client's IP
+    /// address, and parse the response body with [`OhttpKeys::from_directory_response`].
+    pub fn directory_endpoint(directory: &DirectoryUrl) -> url::Url {
+        directory.as_url().join(OHTTP_KEYS_PATH).expect("an http(s) URL accepts an absolute path")
+    }
+
+    /// Parse the KeyConfig a directory serves at [`OhttpKeys::directory_endpoint`], as
+    /// [`OhttpKeys::from_bytes`] does
+    pub fn from_directory_response(body: &[u8]) -> Result<Self, OhttpKeysError> {
+        Self::from_bytes(body)
+    }
+
+    /// Parse a KeyConfig in its RFC 9458 encoding, as served by a directory.
+    ///
+    /// Unlike [`OhttpKeys::decode`], an input cut short of the lengths it declares is reported
+    /// as [`OhttpKeysError::Truncated`].
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, OhttpKeysError> {
+        // KeyConfig { key_id: u8, kem_id: u16, public_key, cipher_suites_len: u16, cipher_suites }
+        let kem_id = match bytes.get(1..3) {
+            Some(kem) => u16::from_be_bytes([kem[0], kem[1]]),
+            None => return Err(OhttpKeysError::Truncated),
+        };
+        let kem = ohttp::hpke::Kem::try_from(kem_id)
+            .map_err(|_| OhttpKeysError::UnsupportedKem(kem_id))?;
+        let suites_start = 3 + kem.n_pk() + 2;
+        let suites_len = match bytes.get(suites_start - 2..suites_start) {
+            Some(len) => u16::from_be_bytes([len[0], len[1]]) as usize,
+            None => return Err(OhttpKeysError::Truncated),
+        };
+        if bytes.len() < suites_start + suites_len {
+            return Err(OhttpKeysError::Truncated);
+        }
+        Self::decode(bytes).map_err(OhttpKeysError::Malformed)
+    }
+
+    /// Read a KeyConfig from a file holding its raw RFC 9458 encoding, e.g. the body a
+    /// directory serves at [`OhttpKeys::directory_endpoint`] saved as is.
+    ///
+    /// The file must not be base64 or otherwise text encoded.
+    pub fn from_file(path: impl AsRef<std::path::Path>) -> Result<Self, OhttpKeysError> {
+        Self::from_bytes(&std::fs::read(path).map_err(OhttpKeysError::Io)?)
+    }
+
+    /// The identifier of the key in this KeyConfig
+    pub fn key_id(&self) -> u8 { self.encoded()[0] }
+
+    /// The (KDF, AEAD) pairs this KeyConfig supports
+    pub fn symmetric_suites(&self) -> Vec<(ohttp::hpke::Kdf, ohttp::hpke::Aead)> {
+        // KeyConfig { key_id: u8, kem_id: u16, public_key, cipher_suites_len: u16, cipher_suites }
+        let encoded = self.encoded();
+        let kem = ohttp::hpke::Kem::try_from(u16::from_be_bytes([encoded[1], encoded[2]]))
+            .expect("KeyConfig only holds supported KEMs");
+        let suites_start = 3 + kem.n_pk() + 2;
+        encoded[suites_start..]
+            .chunks_exact(4)
+            .filter_map(|suite| {
+                let kdf = ohttp::hpke::Kdf::try_from(u16::from_be_bytes([suite[0], suite[1]]));
+                let aead = ohttp::hpke::Aead::try_from(u16::from_be_bytes([suite[2], suite[3]]));
+                Some((kdf.ok()?, aead.ok()?))
+            })
+            .collect()
+    }
+
+    fn encoded(&self) -> Vec<u8> {
+        self.0.encode().expect("encoding a KeyConfig to a Vec is infallible")
+    }
 }
 
 impl PartialEq for OhttpKeys {
@@ -288,6 +378,266 @@ impl serde::Serialize for OhttpKeys {
     }
 }
 
+/// Error from parsing the OHTTP KeyConfig advertised by a directory.
+#[derive(Debug)]
+pub enum OhttpKeysError {
+    Malformed(ohttp::Error),
+    UnsupportedKem(u16),
+    /// The KeyConfig ends before the lengths it declares
+    Truncated,
+    Io(std::io::Error),
+}
+
+impl fmt::Display for OhttpKeysError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use OhttpKeysError::*;
+
+        match &self {
+            Malformed(e) => write!(f, "Malformed OHTTP KeyConfig: {}", e),
+            UnsupportedKem(kem_id) => write!(f, "Unsupported OHTTP KEM: {:#06x}", kem_id),
+            Truncated => write!(f, "Truncated OHTTP KeyConfig"),
+            Io(e) => write!(f, "Failed to read OHTTP KeyConfig: {}", e),
+        }
+    }
+}
+
+impl error::Error for OhttpKeysError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        use OhttpKeysError::*;
+
+        match &self {
+            Malformed(e) => Some(e),
+            UnsupportedKem(_) | Truncated => None,
+            Io(e) => Some(e),
+        }
+    }
+}
+
+const ATTESTATION_TAG: &[u8] = b"payjoin/v2/parameter-attestation";
+
+/// A receiver's signature over the parameters of its v2 session.
+///
+/// Senders that pinned the receiver's public key, e.g. on first use, can check out of band that
+/// a payjoin URI's address, directory and OHTTP keys really came from the receiver.
+///
+/// The signature is a BIP 340 Schnorr signature by the session's static key over the SHA256 of
+/// the canonical encoding:
+///
+/// ```text
+/// "payjoin/v2/parameter-attestation"
+/// || receiver public key (33 bytes, compressed)
+/// || address script length (u32 big endian) || address script_pubkey
+/// || directory URL length (u32 big endian) || directory URL (UTF-8)
+/// || OHTTP KeyConfig length (u32 big endian) || OHTTP KeyConfig (RFC 9458 encoding)
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParameterAttestation {
+    pubkey: PublicKey,
+    script_pubkey: bitcoin::ScriptBuf,
+    directory: url::Url,
+    ohttp_keys: OhttpKeys,
+    signature: bitcoin::secp256k1::schnorr::Signature,
+}
+
+impl ParameterAttestation {
+    /// Reassemble an attestation received out of band so it can be verified
+    pub fn new(
+        pubkey: PublicKey,
+        script_pubkey: bitcoin::ScriptBuf,
+        directory: url::Url,
+        ohttp_keys: OhttpKeys,
+        signature: bitcoin::secp256k1::schnorr::Signature,
+    ) -> Self {
+        Self { pubkey, script_pubkey, directory, ohttp_keys, signature }
+    }
+
+    #[cfg(feature = "receive")]
+    pub(crate) fn sign(
+        s: &bitcoin::secp256k1::KeyPair,
+        script_pubkey: bitcoin::ScriptBuf,
+        directory: url::Url,
+        ohttp_keys: OhttpKeys,
+    ) -> Self {
+        let pubkey = s.public_key();
+        let message = attestation_message(&pubkey, &script_pubkey, &directory, &ohttp_keys);
+        let signature = Secp256k1::new().sign_schnorr(&message, s);
+        Self { pubkey, script_pubkey, directory, ohttp_keys, signature }
+    }
+
+    /// Check the signature and that the attestation was made by `pubkey`
+    pub fn verify(&self, pubkey: &PublicKey) -> Result<(), AttestationError> {
+        if &self.pubkey != pubkey {
+            return Err(AttestationError::PubkeyMismatch);
+        }
+        let message = attestation_message(
+            &self.pubkey,
+            &self.script_pubkey,
+            &self.directory,
+            &self.ohttp_keys,
+        );
+        Secp256k1::verification_only()
+            .verify_schnorr(&self.signature, &message, &self.pubkey.x_only_public_key().0)
+            .map_err(AttestationError::InvalidSignature)
+    }
+
+    /// The canonical encoding of the attested parameters
+    pub fn canonical_encoding(&self) -> Vec<u8> {
+        canonical_encoding(&self.pubkey, &self.script_pubkey, &self.directory, &self.ohttp_keys)
+    }
+
+    pub fn pubkey(&self) -> &PublicKey { &self.pubkey }
+
+    /// The script_pubkey of the payjoin URI's address
+    pub fn script_pubkey(&self) -> &bitcoin::Script { &self.script_pubkey }
+
+    pub fn directory(&self) -> &url::Url { &self.directory }
+
+    pub fn ohttp_keys(&self) -> &OhttpKeys { &self.ohttp_keys }
+
+    pub fn signature(&self) -> &bitcoin::secp256k1::schnorr::Signature { &self.signature }
+}
+
+fn canonical_encoding(
+    pubkey: &PublicKey,
+    script_pubkey: &bitcoin::Script,
+    directory: &url::Url,
+    ohttp_keys: &OhttpKeys,
+) -> Vec<u8> {
+    let directory = directory.as_str().as_bytes();
+    let ohttp_keys = ohttp_keys.encoded();
+    let mut encoding = ATTESTATION_TAG.to_vec();
+    encoding.extend_from_slice(&pubkey.serialize());
+    encoding.extend_from_slice(&(script_pubkey.len() as u32).to_be_bytes());
+    encoding.extend_from_slice(script_pubkey.as_bytes());
+    encoding.extend_from_slice(&(directory.len() as u32).to_be_bytes());
+    encoding.extend_from_slice(directory);
+    encoding.extend_from_slice(&(ohttp_keys.len() as u32).to_be_bytes());
+    encoding.extend_from_slice(&ohttp_keys);
+    encoding
+}
+
+fn attestation_message(
+    pubkey: &PublicKey,
+    script_pubkey: &bitcoin::Script,
+    directory: &url::Url,
+    ohttp_keys: &OhttpKeys,
+) -> bitcoin::secp256k1::Message {
+    use bitcoin::hashes::{sha256, Hash};
+
+    let digest =
+        sha256::Hash::hash(&canonical_encoding(pubkey, script_pubkey, directory, ohttp_keys));
+    bitcoin::secp256k1::Message::from_slice(digest.as_ref()).expect("a sha256 digest is 32 bytes")
+}
+
+/// Error from verifying a [`ParameterAttestation`].
+#[derive(Debug)]
+pub enum AttestationError {
+    /// The attestation was made by another key than the pinned one
+    PubkeyMismatch,
+    /// The attested parameters differ from the ones in use
+    ParameterMismatch,
+    InvalidSignature(bitcoin::secp256k1::Error),
+}
+
+impl fmt::Display for AttestationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use AttestationError::*;
+
+        match &self {
+            PubkeyMismatch => write!(f, "Attestation was not made by the pinned key"),
+            ParameterMismatch => write!(f, "Attested parameters differ from the session's"),
+            InvalidSignature(e) => write!(f, "Invalid attestation signature: {}", e),
+        }
+    }
+}
+
+impl error::Error for AttestationError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        use AttestationError::*;
+
+        match &self {
+            InvalidSignature(e) => Some(e),
+            PubkeyMismatch | ParameterMismatch => None,
+        }
+    }
+}
+
+/// The URL of an OHTTP relay, which forwards encapsulated requests to a directory's gateway.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RelayUrl(url::Url);
+
+/// The URL of a payjoin directory, which stores payloads for receivers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirectoryUrl(url::Url);
+
+macro_rules! impl_http_url {
+    ($name:ident) => {
+        impl $name {
+            pub fn as_url(&self) -> &url::Url { &self.0 }
+        }
+
+        impl TryFrom<url::Url> for $name {
+            type Error = UrlError;
+
+            fn try_from(url: url::Url) -> Result<Self, Self::Error> {
+                match url.scheme() {
+                    "http" | "https" => Ok(Self(url)),
+                    scheme => Err(UrlError::UnsupportedScheme(scheme.to_string())),
+                }
+            }
+        }
+
+        impl TryFrom<&str> for $name {
+            type Error = UrlError;
+
+            fn try_from(url: &str) -> Result<Self, Self::Error> {
+                url::Url::parse(url).map_err(UrlError::Parse)?.try_into()
+            }
+        }
+
+        impl From<$name> for url::Url {
+            fn from(value: $name) -> Self { value.0 }
+        }
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result { self.0.fmt(f) }
+        }
+    };
+}
+
+impl_http_url!(RelayUrl);
+impl_http_url!(DirectoryUrl);
+
+/// Error from validating a [`RelayUrl`] or [`DirectoryUrl`].
+#[derive(Debug)]
+pub enum UrlError {
+    Parse(url::ParseError),
+    UnsupportedScheme(String),
+}
+
+impl fmt::Display for UrlError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use UrlError::*;
+
+        match &self {
+            Parse(e) => write!(f, "URL parsing failed: {}", e),
+            UnsupportedScheme(scheme) =>
+                write!(f, "Expected an http(s) URL, found scheme: {}", scheme),
+        }
+    }
+}
+
+impl error::Error for UrlError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        use UrlError::*;
+
+        match &self {
+            Parse(e) => Some(e),
+            UnsupportedScheme(_) => None,
+        }
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -305,4 +655,88 @@ mod test {
         let deserialized: OhttpKeys = serde_json::from_str(&serialized).unwrap();
         assert_eq!(keys.encode().unwrap(), deserialized.encode().unwrap());
     }
+
+    #[test]
+    fn test_ohttp_keys_suites() {
+        use ohttp::hpke::{Aead, Kdf, Kem};
+        use ohttp::SymmetricSuite;
+        const SYMMETRIC: &[SymmetricSuite] = &[
+            ohttp::SymmetricSuite::new(Kdf::HkdfSha256, Aead::ChaCha20Poly1305),
+            ohttp::SymmetricSuite::new(Kdf::HkdfSha256, Aead::Aes128Gcm),
+        ];
+        let keys =
+            OhttpKeys(ohttp::KeyConfig::new(7, Kem::X25519Sha256, Vec::from(SYMMETRIC)).unwrap());
+        assert_eq!(keys.key_id(), 7);
+        assert_eq!(
+            keys.symmetric_suites(),
+            vec![(Kdf::HkdfSha256, Aead::ChaCha20Poly1305), (Kdf::HkdfSha256, Aead::Aes128Gcm)]
+        );
+    }
+
+    #[test]
+    fn test_http_url_schemes() {
+        assert!(RelayUrl::try_from("https://relay.example").is_ok());
+        assert!(DirectoryUrl::try_from("http://localhost:8080").is_ok());
+        assert!(matches!(
+            DirectoryUrl::try_from("ftp://directory.example"),
+            Err(UrlError::UnsupportedScheme(_))
+        ));
+        assert!(matches!(RelayUrl::try_from("not a url"), Err(UrlError::Parse(_))));
+    }
+
+    #[test]
+    fn test_ohttp_keys_from_directory_response() {
+        use ohttp::hpke::{Aead, Kdf, Kem};
+        use ohttp::SymmetricSuite;
+        const SYMMETRIC: &[SymmetricSuite] =
+            &[ohttp::SymmetricSuite::new(Kdf::HkdfSha256, Aead::ChaCha20Poly1305)];
+
+        let directory = DirectoryUrl::try_from("https://directory.example/subdir").unwrap();
+        assert_eq!(
+            OhttpKeys::directory_endpoint(&directory).as_str(),
+            "https://directory.example/ohttp-keys"
+        );
+
+        let keys =
+            OhttpKeys(ohttp::KeyConfig::new(1, Kem::X25519Sha256, Vec::from(SYMMETRIC)).unwrap());
+        let mut body = keys.encode().unwrap();
+        assert_eq!(OhttpKeys::from_directory_response(&body).unwrap(), keys);
+        assert!(matches!(
+            OhttpKeys::from_directory_response(&body[..10]),
+            Err(OhttpKeysError::Truncated)
+        ));
+        body[1..3].copy_from_slice(&0xffffu16.to_be_bytes());
+        assert!(matches!(
+            OhttpKeys::from_directory_response(&body),
+            Err(OhttpKeysError::UnsupportedKem(0xffff))
+        ));
+    }
+
+    #[test]
+    fn test_ohttp_keys_from_file() {
+        use ohttp::hpke::{Aead, Kdf, Kem};
+        use ohttp::SymmetricSuite;
+        const SYMMETRIC: &[SymmetricSuite] =
+            &[ohttp::SymmetricSuite::new(Kdf::HkdfSha256, Aead::ChaCha20Poly1305)];
+
+        let keys =
+            OhttpKeys(ohttp::KeyConfig::new(1, Kem::X25519Sha256, Vec::from(SYMMETRIC)).unwrap());
+        let encoded = keys.encode().unwrap();
+        let path = std::env::temp_dir().join(format!("ohttp_keys_{}", std::process::id()));
+        std::fs::write(&path, &encoded).unwrap();
+        assert_eq!(OhttpKeys::from_file(&path).unwrap(), keys);
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(OhttpKeys::from_file(&path), Err(OhttpKeysError::Io(_))));
+
+        assert!(matches!(OhttpKeys::from_bytes(&encoded[..2]), Err(OhttpKeysError::Truncated)));
+        assert!(matches!(
+            OhttpKeys::from_bytes(&encoded[..encoded.len() - 1]),
+            Err(OhttpKeysError::Truncated)
+        ));
+        let mut padded = encoded.clone();
+        padded.push(0);
+        assert!(matches!(OhttpKeys::from_bytes(&padded), Err(OhttpKeysError::Malformed(_))));
+        let base64 = bitcoin::base64::encode_config(&encoded, bitcoin::base64::URL_SAFE);
+        assert!(OhttpKeys::from_bytes(base64.as_bytes()).is_err());
+    }
 }
@@ -0,0 +1,52 @@
+use std::fmt;
+
+/// A payjoin protocol version, as carried in the `v` parameter
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "v2", derive(serde::Serialize, serde::Deserialize))]
+pub enum Version {
+    /// BIP 78 payjoin
+    #[cfg_attr(feature = "v2", serde(rename = "1"))]
+    One,
+    /// BIP 77 serverless payjoin
+    #[cfg_attr(feature = "v2", serde(rename = "2"))]
+    Two,
+}
+
+const SUPPORTED_VERSIONS: &[Version] = &[
+    Version::One,
+    #[cfg(feature = "v2")]
+    Version::Two,
+];
+
+/// The protocol versions supported by this build
+pub fn supported_versions() -> &'static [Version] { SUPPORTED_VERSIONS }
+
+impl Version {
+    /// Whether this build of the crate supports the version
+    pub fn is_supported(self) -> bool { SUPPORTED_VERSIONS.contains(&self) }
+
+    /// The value of the `v` parameter for this version
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Version::One => "1",
+            Version::Two => "2",
+        }
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result { f.write_str(self.as_str()) }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn supported_versions_match_features() {
+        assert!(Version::One.is_supported());
+        assert_eq!(Version::Two.is_supported(), cfg!(feature = "v2"));
+        assert!(supported_versions().iter().all(|version| version.is_supported()));
+        assert_eq!(Version::Two.to_string(), "2");
+    }
+}
@@ -169,7 +169,7 @@ mod integration {
 
             let receiver_substitute_address =
                 receiver.get_new_address(None, None).unwrap().assume_checked();
-            payjoin.substitute_output_address(receiver_substitute_address);
+            payjoin.substitute_output_address(receiver_substitute_address).unwrap();
             let payjoin_proposal = payjoin
                 .finalize_proposal(
                     |psbt: &Psbt| {